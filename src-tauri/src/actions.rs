@@ -0,0 +1,573 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::engine::Engine;
+use crate::history::Direction;
+use crate::links;
+use crate::settings::{self, Permission};
+use crate::sync::{self, WsMessage};
+
+const AUDIT_FILE: &str = "remote_actions.log";
+/// The audit log before the current one.
+const ROTATED_AUDIT_FILE: &str = "remote_actions.log.1";
+/// Once the audit log reaches this size it replaces the rotated one and starts over.
+const MAX_AUDIT_BYTES: u64 = 1024 * 1024;
+/// Entries returned by `list_action_log`.
+const AUDIT_LIST_LIMIT: usize = 500;
+/// Requests waiting for the local user at once; a peer asking for more is declined.
+const MAX_PENDING_REQUESTS: usize = 5;
+const MAX_NOTIFICATION_CHARS: usize = 1000;
+/// Files a peer may never ask us to open: programs, installers, scripts, app bundles,
+/// shortcuts, documents with macros and disk images. Compared case-insensitively against
+/// the extension.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "apk", "app", "appimage", "applescript", "bash", "bat", "bundle", "chm", "cmd", "com",
+    "command", "cpl", "csh", "deb", "desktop", "dmg", "docm", "exe", "fileloc", "fish",
+    "gadget", "hta", "img", "inetloc", "inf", "iso", "jar", "jnlp", "js", "jse", "ksh",
+    "lnk", "msc", "msi", "msix", "msp", "mst", "pif", "pkg", "pl", "pptm", "prefpane",
+    "ps1", "psd1", "psm1", "py", "rb", "reg", "rpm", "scf", "scpt", "scr", "sh", "terminal",
+    "tool", "url", "vbe", "vbs", "vhd", "vhdx", "webloc", "workflow", "ws", "wsf", "xll",
+    "xlsm", "zsh",
+];
+
+/// Something a peer asks this machine to do.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RemoteAction {
+    Notify { title: String, body: String },
+    OpenUrl { url: String },
+    /// Opens a file the requester sent us earlier, by the name it was sent under.
+    OpenFile { name: String },
+    LockScreen,
+}
+
+/// Key for per-peer permissions.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    Notify,
+    OpenUrl,
+    OpenFile,
+    LockScreen,
+}
+
+impl RemoteAction {
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            RemoteAction::Notify { .. } => ActionKind::Notify,
+            RemoteAction::OpenUrl { .. } => ActionKind::OpenUrl,
+            RemoteAction::OpenFile { .. } => ActionKind::OpenFile,
+            RemoteAction::LockScreen => ActionKind::LockScreen,
+        }
+    }
+}
+
+/// A request waiting for the local user, kept in `SyncState`.
+pub struct PendingRequest {
+    action: RemoteAction,
+    /// Ids of the same request asked again while this one waited; they get its answer.
+    repeats: Vec<String>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Sent to the peer, no answer yet.
+    Requested,
+    /// Waiting for the local user to decide.
+    Pending,
+    Done,
+    Declined,
+    Failed,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix time in milliseconds.
+    pub timestamp: i64,
+    /// `Sent` for actions we asked the peer to run, `Received` for the peer's requests.
+    pub direction: Direction,
+    pub peer: Option<String>,
+    pub id: String,
+    pub action: RemoteAction,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+fn audit_path(engine: &Engine) -> PathBuf {
+    engine.paths().data_dir.join(AUDIT_FILE)
+}
+
+fn rotated_audit_path(engine: &Engine) -> PathBuf {
+    engine.paths().data_dir.join(ROTATED_AUDIT_FILE)
+}
+
+/// Appends to the audit log, one JSON object per line, and tells the UI to refresh. A
+/// full log is rotated first, so at most two logs' worth of entries are kept.
+fn audit(
+    engine: &Engine,
+    direction: Direction,
+    id: &str,
+    action: &RemoteAction,
+    outcome: Outcome,
+    detail: Option<&str>,
+) {
+    let entry = AuditEntry {
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        direction,
+        peer: sync::current_peer(engine),
+        id: id.to_string(),
+        action: action.clone(),
+        outcome,
+        detail: detail.map(str::to_string),
+    };
+    let path = audit_path(engine);
+    let appended = (|| {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        if std::fs::metadata(&path).is_ok_and(|m| m.len() >= MAX_AUDIT_BYTES) {
+            std::fs::rename(&path, rotated_audit_path(engine)).map_err(|e| e.to_string())?;
+        }
+        let mut line = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|e| e.to_string())
+    })();
+    if appended.is_ok() {
+        engine.emit("action_log", ());
+    }
+}
+
+/// Entries in the log at `path`, oldest first. Lines that fail to parse are skipped.
+fn read_audit(path: &Path) -> Result<Vec<AuditEntry>, String> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+/// Newest first, from the current and the rotated log.
+pub fn list_action_log(engine: Engine) -> Result<Vec<AuditEntry>, String> {
+    let mut entries = read_audit(&rotated_audit_path(&engine))?;
+    entries.extend(read_audit(&audit_path(&engine))?);
+    entries.reverse();
+    entries.truncate(AUDIT_LIST_LIMIT);
+    Ok(entries)
+}
+
+fn emit_action_requested(engine: &Engine, id: &str, action: &RemoteAction) {
+    engine.emit(
+        "action_requested",
+        serde_json::json!({ "id": id, "peer": sync::current_peer(engine), "action": action }),
+    );
+}
+
+fn emit_action_result(engine: &Engine, id: &str, action: Option<&RemoteAction>, error: Option<&str>) {
+    engine.emit(
+        "action_result",
+        serde_json::json!({ "id": id, "action": action, "error": error }),
+    );
+}
+
+/// Whether opening `path` could run code: a known program or script extension, an app
+/// bundle or other folder, any exec bit, or a `#!` line.
+fn is_executable(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if extension.is_some_and(|e| EXECUTABLE_EXTENSIONS.contains(&e.as_str())) {
+        return true;
+    }
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return true;
+    };
+    if !metadata.is_file() {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 != 0 {
+            return true;
+        }
+    }
+    let mut start = [0u8; 2];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut start))
+        .is_ok_and(|_| start == *b"#!")
+}
+
+/// Local path of the file a peer asks us to open, unless opening it could run code.
+fn openable_file(engine: &Engine, name: &str) -> Result<PathBuf, String> {
+    let path = sync::received_file(engine, name).ok_or("No such file was received")?;
+    if is_executable(&path) {
+        return Err("Programs, scripts and app bundles are never opened for a peer".to_string());
+    }
+    Ok(path)
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let status = command.status().map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Command exited with {status}"))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn lock_screen() -> Result<(), String> {
+    const CG_SESSION: &str =
+        "/System/Library/CoreServices/Menu Extras/User.menu/Contents/Resources/CGSession";
+    if std::path::Path::new(CG_SESSION).exists() {
+        return run(Command::new(CG_SESSION).arg("-suspend"));
+    }
+    // Newer macOS dropped CGSession; sleeping the display locks it when a password is
+    // required after sleep, which is the default.
+    run(Command::new("pmset").arg("displaysleepnow"))
+}
+
+#[cfg(target_os = "linux")]
+fn lock_screen() -> Result<(), String> {
+    run(Command::new("loginctl").arg("lock-session"))
+        .or_else(|_| run(Command::new("xdg-screensaver").arg("lock")))
+}
+
+#[cfg(target_os = "windows")]
+fn lock_screen() -> Result<(), String> {
+    run(Command::new("rundll32.exe").arg("user32.dll,LockWorkStation"))
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+fn lock_screen() -> Result<(), String> {
+    Err("Locking the screen is not supported on this platform".to_string())
+}
+
+async fn perform(engine: &Engine, action: &RemoteAction) -> Result<(), String> {
+    match action {
+        RemoteAction::Notify { title, body } => {
+            let body: String = body.chars().take(MAX_NOTIFICATION_CHARS).collect();
+            let title = match sync::current_peer(engine) {
+                Some(peer) if title.is_empty() => peer,
+                _ => title.chars().take(MAX_NOTIFICATION_CHARS).collect(),
+            };
+            engine.desktop().notify(&title, &body)
+        }
+        RemoteAction::OpenUrl { url } => {
+            links::check_url(url, &settings::current(engine).allowed_url_schemes)?;
+            engine.desktop().open_url(url)
+        }
+        RemoteAction::OpenFile { name } => {
+            let path = openable_file(engine, name)?;
+            engine.desktop().open_path(&path)
+        }
+        RemoteAction::LockScreen => engine.spawn_blocking(lock_screen)
+            .await
+            .map_err(|e| e.to_string())?,
+    }
+}
+
+/// Runs an allowed action once and reports back to the peer for each of `ids`.
+async fn fulfil(engine: &Engine, ids: Vec<String>, action: RemoteAction) {
+    let result = perform(engine, &action).await;
+    let error = result.err();
+    let outcome = if error.is_some() { Outcome::Failed } else { Outcome::Done };
+    for id in ids {
+        audit(engine, Direction::Received, &id, &action, outcome, error.as_deref());
+        let msg = WsMessage::ActionResult {
+            id,
+            error: error.clone(),
+        };
+        let _ = sync::send_message(engine, &msg).await;
+    }
+}
+
+async fn decline(engine: &Engine, id: String, action: &RemoteAction, reason: &str) {
+    audit(engine, Direction::Received, &id, action, Outcome::Declined, Some(reason));
+    let msg = WsMessage::ActionResult {
+        id,
+        error: Some(reason.to_string()),
+    };
+    let _ = sync::send_message(engine, &msg).await;
+}
+
+/// Where an action the user has to be asked about ends up.
+enum Queued {
+    /// Shown to the user; `first` if no other request was waiting.
+    New { first: bool },
+    /// The same request is already waiting and will answer this one too.
+    Repeat,
+    Full,
+}
+
+fn queue_request(engine: &Engine, id: &str, action: &RemoteAction) -> Queued {
+    let mut pending = engine.state().action_requests.lock().unwrap();
+    if let Some(waiting) = pending.values_mut().find(|p| p.action == *action) {
+        waiting.repeats.push(id.to_string());
+        return Queued::Repeat;
+    }
+    if pending.len() >= MAX_PENDING_REQUESTS {
+        return Queued::Full;
+    }
+    let first = pending.is_empty();
+    let request = PendingRequest {
+        action: action.clone(),
+        repeats: Vec::new(),
+    };
+    pending.insert(id.to_string(), request);
+    Queued::New { first }
+}
+
+/// Asks the peer to run `action`. The answer arrives as an `action_result` event.
+pub async fn send_remote_action(action: RemoteAction, engine: Engine) -> Result<String, String> {
+    if !sync::peer_supports(&engine, sync::FEATURE_ACTIONS) {
+        return Err("The other device does not support remote actions".to_string());
+    }
+    if let RemoteAction::OpenUrl { url } = &action {
+        links::check_url(url, &settings::current(&engine).allowed_url_schemes)?;
+    }
+    let id = crate::transfer::new_id();
+    let msg = WsMessage::Action {
+        id: id.clone(),
+        action: action.clone(),
+    };
+    sync::send_message(&engine, &msg).await?;
+    audit(&engine, Direction::Sent, &id, &action, Outcome::Requested, None);
+    let state = engine.state();
+    state.sent_actions.lock().unwrap().insert(id.clone(), action);
+    Ok(id)
+}
+
+pub async fn receive_action(engine: &Engine, id: String, action: RemoteAction) {
+    let peer = sync::current_peer(engine);
+    let mut permission =
+        settings::current(engine).action_permission_for(peer.as_deref(), action.kind());
+    if let RemoteAction::OpenFile { name } = &action {
+        if let Err(reason) = openable_file(engine, name) {
+            return decline(engine, id, &action, &reason).await;
+        }
+        // Opening a file hands it to whatever app claims it, so the user always decides.
+        if permission == Permission::Allow {
+            permission = Permission::Ask;
+        }
+    }
+    match permission {
+        // Some actions block for a while; keep reading messages meanwhile.
+        Permission::Allow => {
+            let engine = engine.clone();
+            engine.clone().spawn(async move {
+                fulfil(&engine, vec![id], action).await;
+            });
+        }
+        Permission::Deny => decline(engine, id, &action, "Not allowed").await,
+        Permission::Ask => match queue_request(engine, &id, &action) {
+            Queued::New { first } => {
+                audit(engine, Direction::Received, &id, &action, Outcome::Pending, None);
+                emit_action_requested(engine, &id, &action);
+                // Only raise the window once, not for every request a peer sends.
+                if first {
+                    engine.emit("bring_to_front", ());
+                }
+            }
+            Queued::Repeat => {
+                let detail = "Same as a request already waiting";
+                audit(engine, Direction::Received, &id, &action, Outcome::Pending, Some(detail));
+            }
+            Queued::Full => decline(engine, id, &action, "Too many requests waiting for an answer").await,
+        },
+    }
+}
+
+pub fn receive_result(engine: &Engine, id: String, error: Option<String>) {
+    let action = engine.state().sent_actions.lock().unwrap().remove(&id);
+    if let Some(action) = &action {
+        let outcome = if error.is_some() { Outcome::Failed } else { Outcome::Done };
+        audit(engine, Direction::Sent, &id, action, outcome, error.as_deref());
+    }
+    emit_action_result(engine, &id, action.as_ref(), error.as_deref());
+}
+
+/// The local user's answer to an action request. `remember` stores it for this peer and
+/// kind of action.
+pub async fn answer_remote_action(
+    id: String,
+    allow: bool,
+    remember: bool,
+    engine: Engine,
+) -> Result<(), String> {
+    let PendingRequest { action, repeats } = engine
+        .state()
+        .action_requests
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or("Request no longer pending")?;
+    // Opening files is asked for every time, so only a refusal is worth remembering.
+    let remember = remember && !(allow && action.kind() == ActionKind::OpenFile);
+    if remember {
+        if let Some(peer) = sync::current_peer(&engine) {
+            let mut settings = settings::current(&engine);
+            settings.peers.entry(peer).or_default().actions.insert(
+                action.kind(),
+                if allow { Permission::Allow } else { Permission::Deny },
+            );
+            settings::set_settings(settings, engine.clone())?;
+        }
+    }
+    let ids = std::iter::once(id).chain(repeats).collect();
+    if allow {
+        fulfil(&engine, ids, action).await;
+    } else {
+        for id in ids {
+            decline(&engine, id, &action, "Declined by the other user").await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestEngine};
+
+    /// Connects two engines where `host` saves received files straight away and lets
+    /// `client` open them without asking.
+    async fn trusting_host() -> (TestEngine, TestEngine) {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let mut settings = settings::current(&host.engine);
+        settings.download_dir = Some(host.dir.join("downloads"));
+        let peer = settings.peers.entry("client".to_string()).or_default();
+        peer.actions.insert(ActionKind::OpenFile, Permission::Allow);
+        settings::set_settings(settings, host.engine.clone()).unwrap();
+        testing::connect(&mut host, &mut client).await;
+        (host, client)
+    }
+
+    async fn send_file(host: &mut TestEngine, client: &TestEngine, path: PathBuf) {
+        sync::send_path(path, client.engine.clone()).await.unwrap();
+        host.wait_for("remote_file", |p| !p["path"].is_null()).await;
+    }
+
+    /// Asks `client`'s peer to open `name` and waits for the answer.
+    async fn open_file(client: &mut TestEngine, name: &str) -> serde_json::Value {
+        let action = RemoteAction::OpenFile { name: name.to_string() };
+        let id = send_remote_action(action, client.engine.clone()).await.unwrap();
+        client.wait_for("action_result", |p| p["id"] == id.as_str()).await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn executable_sent_by_the_peer_is_never_opened() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (mut host, mut client) = trusting_host().await;
+        let path = client.file("tool", b"\x7fELF not really");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        send_file(&mut host, &client, path).await;
+
+        let result = open_file(&mut client, "tool").await;
+        assert!(result["error"].as_str().unwrap().contains("never opened"), "{result}");
+        let log = list_action_log(host.engine.clone()).unwrap();
+        assert!(matches!(log[0].outcome, Outcome::Declined));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scripts_and_programs_are_refused_by_name_and_content() {
+        let (mut host, mut client) = trusting_host().await;
+        for (name, bytes) in [
+            ("setup.EXE", &b"MZ"[..]),
+            ("install.command", b"echo hi"),
+            ("budget.XLSM", b"PK"),
+            ("installer.iso", b"CD001"),
+            ("notes.txt", b"#!/bin/sh\necho hi"),
+        ] {
+            send_file(&mut host, &client, client.file(name, bytes)).await;
+            let result = open_file(&mut client, name).await;
+            assert!(result["error"].as_str().unwrap().contains("never opened"), "{name}: {result}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn opening_a_document_is_always_asked_for() {
+        let (mut host, client) = trusting_host().await;
+        send_file(&mut host, &client, client.file("report.pdf", b"%PDF-1.7")).await;
+
+        let action = RemoteAction::OpenFile { name: "report.pdf".to_string() };
+        let id = send_remote_action(action, client.engine.clone()).await.unwrap();
+        host.wait_for("action_requested", |p| p["id"] == id.as_str()).await;
+        assert!(host.engine.state().action_requests.lock().unwrap().contains_key(&id));
+    }
+
+    fn notify(body: &str) -> RemoteAction {
+        RemoteAction::Notify {
+            title: String::new(),
+            body: body.to_string(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repeated_request_shares_the_waiting_prompt_and_its_answer() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        let first = send_remote_action(notify("hi"), client.engine.clone()).await.unwrap();
+        host.wait_for("action_requested", |p| p["id"] == first.as_str()).await;
+        let repeat = send_remote_action(notify("hi"), client.engine.clone()).await.unwrap();
+        while !list_action_log(host.engine.clone()).unwrap().iter().any(|e| e.id == repeat) {
+            host.wait_for("action_log", |_| true).await;
+        }
+        assert_eq!(host.engine.state().action_requests.lock().unwrap().len(), 1);
+
+        answer_remote_action(first.clone(), false, false, host.engine.clone()).await.unwrap();
+        for id in [first, repeat] {
+            let result = client.wait_for("action_result", |p| p["id"] == id.as_str()).await;
+            assert_eq!(result["error"], "Declined by the other user");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_beyond_the_limit_are_declined_without_asking() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        for i in 0..MAX_PENDING_REQUESTS {
+            let id = send_remote_action(notify(&i.to_string()), client.engine.clone()).await.unwrap();
+            host.wait_for("action_requested", |p| p["id"] == id.as_str()).await;
+        }
+        let id = send_remote_action(notify("one too many"), client.engine.clone()).await.unwrap();
+        let result = client.wait_for("action_result", |p| p["id"] == id.as_str()).await;
+        assert!(result["error"].as_str().unwrap().contains("Too many"), "{result}");
+        assert_eq!(host.engine.state().action_requests.lock().unwrap().len(), MAX_PENDING_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn full_audit_log_is_rotated_and_still_listed() {
+        let test = TestEngine::new("audit");
+        let engine = &test.engine;
+        audit(engine, Direction::Sent, "old", &notify("old"), Outcome::Requested, None);
+        let mut file = std::fs::OpenOptions::new().append(true).open(audit_path(engine)).unwrap();
+        file.write_all(&vec![b'\n'; MAX_AUDIT_BYTES as usize]).unwrap();
+
+        audit(engine, Direction::Sent, "new", &notify("new"), Outcome::Requested, None);
+        assert!(std::fs::metadata(audit_path(engine)).unwrap().len() < 1024);
+        let ids: Vec<_> = list_action_log(engine.clone()).unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["new", "old"]);
+    }
+}
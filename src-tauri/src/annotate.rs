@@ -0,0 +1,238 @@
+use image::{imageops, Rgba, RgbaImage};
+use serde::Deserialize;
+
+/// Size of the mosaic blocks a blur starts from. A plain gaussian blur of text can be
+/// partly undone, so blurred regions are pixelated first.
+const BLUR_BLOCK: u32 = 12;
+const BLUR_SIGMA: f32 = 4.0;
+const MAX_STROKE: u32 = 64;
+
+/// A rectangle in image pixels.
+#[derive(Clone, Copy, Deserialize)]
+pub struct Rect {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+impl Rect {
+    /// Clips to the image, returning `(x, y, width, height)`, or `None` if nothing is left.
+    fn clip(&self, image: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        let (w, h) = (image.width() as i64, image.height() as i64);
+        let (mut x0, mut y0) = (self.x, self.y);
+        let (mut x1, mut y1) = (self.x.saturating_add(self.width), self.y.saturating_add(self.height));
+        // Rectangles dragged up or to the left arrive with negative sizes.
+        if x1 < x0 {
+            std::mem::swap(&mut x0, &mut x1);
+        }
+        if y1 < y0 {
+            std::mem::swap(&mut y0, &mut y1);
+        }
+        let (x0, y0) = (x0.clamp(0, w), y0.clamp(0, h));
+        let (x1, y1) = (x1.clamp(0, w), y1.clamp(0, h));
+        (x1 > x0 && y1 > y0).then(|| (x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32))
+    }
+}
+
+/// One edit from the UI, applied in order.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Edit {
+    /// Redaction: pixelate and blur the region.
+    Blur { rect: Rect },
+    /// Redaction: fill the region with solid black.
+    BlackBox { rect: Rect },
+    /// Annotation: rectangle outline.
+    Rect {
+        rect: Rect,
+        color: [u8; 3],
+        #[serde(default = "default_stroke")]
+        width: u32,
+    },
+    /// Annotation: straight line.
+    Line {
+        from: (i64, i64),
+        to: (i64, i64),
+        color: [u8; 3],
+        #[serde(default = "default_stroke")]
+        width: u32,
+    },
+}
+
+fn default_stroke() -> u32 {
+    4
+}
+
+fn fill(image: &mut RgbaImage, (x, y, w, h): (u32, u32, u32, u32), color: Rgba<u8>) {
+    for py in y..y + h {
+        for px in x..x + w {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// Draws a `width`-pixel square centered on (x, y), clipped to the image.
+fn dot(image: &mut RgbaImage, x: i64, y: i64, width: u32, color: Rgba<u8>) {
+    let half = width as i64 / 2;
+    let square = Rect {
+        x: x - half,
+        y: y - half,
+        width: width as i64,
+        height: width as i64,
+    };
+    if let Some(clipped) = square.clip(image) {
+        fill(image, clipped, color);
+    }
+}
+
+fn blur(image: &mut RgbaImage, (x, y, w, h): (u32, u32, u32, u32)) {
+    let region = imageops::crop_imm(image, x, y, w, h).to_image();
+    let small = imageops::resize(
+        &region,
+        w.div_ceil(BLUR_BLOCK),
+        h.div_ceil(BLUR_BLOCK),
+        imageops::FilterType::Triangle,
+    );
+    let mosaic = imageops::resize(&small, w, h, imageops::FilterType::Nearest);
+    let blurred = imageops::blur(&mosaic, BLUR_SIGMA);
+    imageops::replace(image, &blurred, x as i64, y as i64);
+}
+
+fn line(image: &mut RgbaImage, from: (i64, i64), to: (i64, i64), width: u32, color: Rgba<u8>) {
+    // Keep the step count bounded by the image size, whatever the UI sends.
+    let width = width.clamp(1, MAX_STROKE);
+    let margin = width as i64;
+    let (w, h) = (image.width() as i64 + margin, image.height() as i64 + margin);
+    let from = (from.0.clamp(-margin, w), from.1.clamp(-margin, h));
+    let to = (to.0.clamp(-margin, w), to.1.clamp(-margin, h));
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let steps = dx.abs().max(dy.abs()).max(1);
+    for i in 0..=steps {
+        let x = from.0 + dx * i / steps;
+        let y = from.1 + dy * i / steps;
+        dot(image, x, y, width, color);
+    }
+}
+
+pub fn apply(image: &mut RgbaImage, edits: &[Edit]) {
+    for edit in edits {
+        match edit {
+            Edit::Blur { rect } => {
+                if let Some(clipped) = rect.clip(image) {
+                    blur(image, clipped);
+                }
+            }
+            Edit::BlackBox { rect } => {
+                if let Some(clipped) = rect.clip(image) {
+                    fill(image, clipped, Rgba([0, 0, 0, 255]));
+                }
+            }
+            Edit::Rect { rect, color, width } => {
+                let color = Rgba([color[0], color[1], color[2], 255]);
+                let (x0, y0) = (rect.x, rect.y);
+                let (x1, y1) = (rect.x.saturating_add(rect.width), rect.y.saturating_add(rect.height));
+                for (from, to) in [
+                    ((x0, y0), (x1, y0)),
+                    ((x1, y0), (x1, y1)),
+                    ((x1, y1), (x0, y1)),
+                    ((x0, y1), (x0, y0)),
+                ] {
+                    line(image, from, to, *width, color);
+                }
+            }
+            Edit::Line { from, to, color, width } => {
+                line(image, *from, *to, *width, Rgba([color[0], color[1], color[2], 255]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    fn rect(x: i64, y: i64, width: i64, height: i64) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    fn white(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, WHITE)
+    }
+
+    fn count(image: &RgbaImage, color: Rgba<u8>) -> usize {
+        image.pixels().filter(|p| **p == color).count()
+    }
+
+    #[test]
+    fn clips_rectangles_to_the_image() {
+        let image = white(100, 50);
+        assert_eq!(rect(10, 10, 20, 5).clip(&image), Some((10, 10, 20, 5)));
+        assert_eq!(rect(-10, -10, 30, 30).clip(&image), Some((0, 0, 20, 20)));
+        assert_eq!(rect(90, 40, 50, 50).clip(&image), Some((90, 40, 10, 10)));
+        assert_eq!(rect(-5, -5, 200, 200).clip(&image), Some((0, 0, 100, 50)));
+        assert_eq!(rect(i64::MAX, i64::MIN, i64::MAX, i64::MAX).clip(&image), None);
+    }
+
+    #[test]
+    fn normalizes_rectangles_dragged_backwards() {
+        let image = white(100, 50);
+        assert_eq!(rect(30, 20, -20, -10).clip(&image), Some((10, 10, 20, 10)));
+        assert_eq!(rect(5, 5, -50, -50).clip(&image), Some((0, 0, 5, 5)));
+    }
+
+    #[test]
+    fn skips_rectangles_outside_or_empty() {
+        let image = white(100, 50);
+        assert_eq!(rect(100, 0, 10, 10).clip(&image), None);
+        assert_eq!(rect(-20, 0, 10, 10).clip(&image), None);
+        assert_eq!(rect(10, 10, 0, 10).clip(&image), None);
+    }
+
+    #[test]
+    fn black_box_past_the_edge_covers_only_the_image() {
+        let mut image = white(20, 10);
+        apply(&mut image, &[Edit::BlackBox { rect: rect(15, 5, 100, 100) }]);
+        assert_eq!(count(&image, BLACK), 5 * 5);
+        assert_eq!(*image.get_pixel(15, 5), BLACK);
+        assert_eq!(*image.get_pixel(14, 5), WHITE);
+    }
+
+    #[test]
+    fn blur_stays_inside_its_region() {
+        let mut image = white(40, 40);
+        fill(&mut image, (0, 0, 40, 20), BLACK);
+        apply(&mut image, &[Edit::Blur { rect: rect(30, 10, 100, 20) }]);
+        assert_eq!(*image.get_pixel(29, 0), BLACK);
+        assert_eq!(*image.get_pixel(29, 39), WHITE);
+        assert_ne!(*image.get_pixel(35, 19), BLACK);
+    }
+
+    #[test]
+    fn strokes_far_outside_the_image_are_clipped() {
+        let red = [255, 0, 0];
+        let mut image = white(10, 10);
+        apply(
+            &mut image,
+            &[
+                Edit::Line { from: (i64::MIN, 2), to: (i64::MAX, 2), color: red, width: 1 },
+                Edit::Line { from: (-100, -100), to: (-50, -50), color: red, width: 1 },
+            ],
+        );
+        assert_eq!(count(&image, Rgba([255, 0, 0, 255])), 10);
+        assert!((0..10).all(|x| *image.get_pixel(x, 2) == Rgba([255, 0, 0, 255])));
+
+        let mut image = white(10, 10);
+        apply(
+            &mut image,
+            &[
+                Edit::Rect { rect: rect(-5, -5, i64::MAX, i64::MAX), color: red, width: u32::MAX },
+                Edit::Line { from: (i64::MIN, i64::MIN), to: (i64::MAX, i64::MAX), color: red, width: 0 },
+            ],
+        );
+        assert_eq!(*image.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    }
+}
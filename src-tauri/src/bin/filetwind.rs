@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use tauri_app_lib::daemon::{self, Config};
+
+const USAGE: &str = "Usage: filetwind [--config <file>]
+
+Runs the sync engine without the app window. The config file is JSON; see
+daemon::Config for the fields. Control clients connect to the socket it prints.";
+
+fn main() {
+    let mut config_path: Option<PathBuf> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => exit_with_usage("--config needs a file"),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            other => exit_with_usage(&format!("Unknown argument {other}")),
+        }
+    }
+
+    let result = Config::load(config_path.as_deref()).and_then(daemon::run);
+    if let Err(e) = result {
+        eprintln!("filetwind: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_usage(error: &str) -> ! {
+    eprintln!("filetwind: {error}\n\n{USAGE}");
+    std::process::exit(2);
+}
@@ -0,0 +1,445 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::engine::Engine;
+use crate::files;
+use crate::history::Direction;
+use crate::sync::{self, WsMessage};
+
+/// One file per peer in here.
+const CHAT_DIR: &str = "chat";
+/// All threads in one file, as saved by earlier versions. Split up on load.
+const LEGACY_CHAT_FILE: &str = "chat.json";
+/// Messages kept per peer; the oldest are dropped first.
+const MAX_MESSAGES: usize = 1000;
+/// Changes made within this long of each other are saved together.
+const SAVE_DELAY: Duration = Duration::from_millis(250);
+const MAX_TEXT_CHARS: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatStatus {
+    /// Written while the peer was away; sent when it reconnects.
+    Queued,
+    /// Sent, not acknowledged yet. Resent on reconnect in case it was lost.
+    Sent,
+    /// The peer has it; for received messages, not read yet.
+    Delivered,
+    Read,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    pub direction: Direction,
+    pub text: String,
+    /// Unix time in milliseconds, as set by the author.
+    pub timestamp: i64,
+    pub status: ChatStatus,
+}
+
+/// Message threads keyed by peer name, each oldest first.
+#[derive(Default, Deserialize)]
+pub struct Chats {
+    threads: HashMap<String, Vec<ChatMessage>>,
+    /// Peers whose thread changed since it was last saved.
+    #[serde(skip)]
+    unsaved: HashSet<String>,
+    /// A save is queued and will write every thread in `unsaved`.
+    #[serde(skip)]
+    saving: bool,
+}
+
+/// What one peer's chat file holds.
+#[derive(Serialize, Deserialize)]
+struct ThreadFile {
+    peer: String,
+    messages: Vec<ChatMessage>,
+}
+
+#[derive(Serialize)]
+pub struct ChatThread {
+    pub peer: String,
+    pub unread: usize,
+    pub last: Option<ChatMessage>,
+}
+
+fn chat_dir(engine: &Engine) -> PathBuf {
+    engine.paths().data_dir.join(CHAT_DIR)
+}
+
+/// Peer names can hold anything, so files are named by their bytes in hex.
+fn thread_path(engine: &Engine, peer: &str) -> PathBuf {
+    let mut name = String::with_capacity(peer.len() * 2 + 5);
+    for byte in peer.bytes() {
+        let _ = write!(name, "{byte:02x}");
+    }
+    chat_dir(engine).join(name + ".json")
+}
+
+fn save_thread(engine: &Engine, peer: String, messages: Vec<ChatMessage>) -> Result<(), String> {
+    std::fs::create_dir_all(chat_dir(engine)).map_err(|e| e.to_string())?;
+    let path = thread_path(engine, &peer);
+    let json = serde_json::to_vec(&ThreadFile { peer, messages }).map_err(|e| e.to_string())?;
+    files::write_atomic(&path, &json)
+}
+
+/// Loads chats from the app data dir, starting empty if missing or unreadable.
+pub fn load(engine: &Engine) -> Chats {
+    let mut chats = Chats::default();
+    if let Ok(entries) = std::fs::read_dir(chat_dir(engine)) {
+        for entry in entries.filter_map(Result::ok) {
+            let thread = std::fs::read(entry.path())
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<ThreadFile>(&bytes).ok());
+            if let Some(thread) = thread {
+                chats.threads.insert(thread.peer, thread.messages);
+            }
+        }
+    }
+
+    let legacy_path = engine.paths().data_dir.join(LEGACY_CHAT_FILE);
+    let legacy = std::fs::read(&legacy_path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Chats>(&bytes).ok());
+    if let Some(legacy) = legacy {
+        let mut saved = true;
+        for (peer, messages) in legacy.threads {
+            saved &= save_thread(engine, peer.clone(), messages.clone()).is_ok();
+            chats.threads.entry(peer).or_insert(messages);
+        }
+        if saved {
+            let _ = std::fs::remove_file(legacy_path);
+        }
+    }
+    chats
+}
+
+/// Writes the threads changed since the last save. Runs on a blocking thread.
+fn save(engine: &Engine) {
+    let changed: Vec<(String, Vec<ChatMessage>)> = {
+        let mut chats = engine.state().chats.lock().unwrap();
+        chats.saving = false;
+        let unsaved = std::mem::take(&mut chats.unsaved);
+        unsaved
+            .into_iter()
+            .map(|peer| {
+                let messages = chats.threads.get(&peer).cloned().unwrap_or_default();
+                (peer, messages)
+            })
+            .collect()
+    };
+    for (peer, messages) in changed {
+        let _ = save_thread(engine, peer, messages);
+    }
+}
+
+/// Applies `change` to `peer`'s thread, queues a save and tells the UI to refresh.
+/// Only the changed threads are written, and a burst of changes is written once.
+fn update<T>(
+    engine: &Engine,
+    peer: &str,
+    change: impl FnOnce(&mut Vec<ChatMessage>) -> T,
+) -> Result<T, String> {
+    let state = engine.state();
+    let mut chats = state.chats.lock().map_err(|_| "lock")?;
+    let thread = chats.threads.entry(peer.to_string()).or_default();
+    let result = change(thread);
+    if thread.len() > MAX_MESSAGES {
+        let excess = thread.len() - MAX_MESSAGES;
+        thread.drain(..excess);
+    }
+    chats.unsaved.insert(peer.to_string());
+    if !chats.saving {
+        chats.saving = true;
+        let saving = engine.clone();
+        engine.spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+            let engine = saving.clone();
+            let _ = saving.spawn_blocking(move || save(&engine)).await;
+        });
+    }
+    drop(chats);
+    engine.emit("chat", serde_json::json!({ "peer": peer }));
+    Ok(result)
+}
+
+/// The peer we are connected to, if it can receive chat messages.
+fn chat_peer(engine: &Engine) -> Option<String> {
+    sync::current_peer(engine).filter(|_| sync::peer_supports(engine, sync::FEATURE_CHAT))
+}
+
+/// Sends a message to `peer`, or queues it until that peer reconnects.
+pub async fn send_chat(peer: String, text: String, engine: Engine) -> Result<ChatMessage, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Message is empty".to_string());
+    }
+    let mut message = ChatMessage {
+        id: crate::transfer::new_id(),
+        direction: Direction::Sent,
+        text: text.chars().take(MAX_TEXT_CHARS).collect(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        status: ChatStatus::Queued,
+    };
+    if chat_peer(&engine).as_deref() == Some(peer.as_str()) {
+        let msg = WsMessage::Chat {
+            id: message.id.clone(),
+            text: message.text.clone(),
+            timestamp: message.timestamp,
+        };
+        if sync::send_message(&engine, &msg).await.is_ok() {
+            message.status = ChatStatus::Sent;
+        }
+    }
+    let stored = message.clone();
+    update(&engine, &peer, |thread| thread.push(stored))?;
+    Ok(message)
+}
+
+/// Sends whatever is queued or unacknowledged for the peer that just said hello.
+pub async fn flush(engine: &Engine) {
+    let Some(peer) = chat_peer(engine) else {
+        return;
+    };
+    let pending: Vec<ChatMessage> = match engine.state().chats.lock() {
+        Ok(chats) => chats
+            .threads
+            .get(&peer)
+            .map(|thread| {
+                thread
+                    .iter()
+                    .filter(|m| {
+                        m.direction == Direction::Sent
+                            && matches!(m.status, ChatStatus::Queued | ChatStatus::Sent)
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let mut sent = Vec::new();
+    for message in pending {
+        let msg = WsMessage::Chat {
+            id: message.id.clone(),
+            text: message.text,
+            timestamp: message.timestamp,
+        };
+        if sync::send_message(engine, &msg).await.is_err() {
+            break;
+        }
+        sent.push(message.id);
+    }
+    if !sent.is_empty() {
+        let _ = update(engine, &peer, |thread| {
+            for m in thread.iter_mut().filter(|m| sent.contains(&m.id)) {
+                if m.status == ChatStatus::Queued {
+                    m.status = ChatStatus::Sent;
+                }
+            }
+        });
+    }
+}
+
+pub async fn receive_chat(engine: &Engine, id: String, text: String, timestamp: i64) {
+    let Some(peer) = sync::current_peer(engine) else {
+        return;
+    };
+    let message = ChatMessage {
+        id: id.clone(),
+        direction: Direction::Received,
+        text: text.chars().take(MAX_TEXT_CHARS).collect(),
+        timestamp,
+        status: ChatStatus::Delivered,
+    };
+    // A resent message we already have is only acknowledged again.
+    let _ = update(engine, &peer, |thread| {
+        if !thread.iter().any(|m| m.id == id) {
+            thread.push(message);
+        }
+    });
+    let ack = WsMessage::ChatAck {
+        ids: vec![id],
+        read: false,
+    };
+    let _ = sync::send_message(engine, &ack).await;
+}
+
+pub fn receive_ack(engine: &Engine, ids: Vec<String>, read: bool) {
+    let Some(peer) = sync::current_peer(engine) else {
+        return;
+    };
+    let status = if read { ChatStatus::Read } else { ChatStatus::Delivered };
+    let _ = update(engine, &peer, |thread| {
+        for m in thread.iter_mut() {
+            // Acks can arrive out of order; never go back from read to delivered.
+            if m.direction == Direction::Sent && ids.contains(&m.id) && m.status != ChatStatus::Read {
+                m.status = status;
+            }
+        }
+    });
+}
+
+/// Messages exchanged with `peer`, oldest first.
+pub fn list_chat(peer: String, engine: Engine) -> Result<Vec<ChatMessage>, String> {
+    let state = engine.state();
+    let chats = state.chats.lock().map_err(|_| "lock")?;
+    Ok(chats.threads.get(&peer).cloned().unwrap_or_default())
+}
+
+/// One entry per peer with messages, most recent conversation first.
+pub fn list_chat_threads(engine: Engine) -> Result<Vec<ChatThread>, String> {
+    let state = engine.state();
+    let chats = state.chats.lock().map_err(|_| "lock")?;
+    let mut threads: Vec<ChatThread> = chats
+        .threads
+        .iter()
+        .filter(|(_, thread)| !thread.is_empty())
+        .map(|(peer, thread)| ChatThread {
+            peer: peer.clone(),
+            unread: thread
+                .iter()
+                .filter(|m| m.direction == Direction::Received && m.status != ChatStatus::Read)
+                .count(),
+            last: thread.last().cloned(),
+        })
+        .collect();
+    threads.sort_by_key(|t| std::cmp::Reverse(t.last.as_ref().map(|m| m.timestamp)));
+    Ok(threads)
+}
+
+/// Marks everything received from `peer` as read and tells the peer, if connected.
+/// Read receipts for messages read while disconnected are not sent later.
+pub async fn mark_chat_read(peer: String, engine: Engine) -> Result<(), String> {
+    let unread = list_chat_threads(engine.clone())?
+        .iter()
+        .any(|t| t.peer == peer && t.unread > 0);
+    // Nothing to do; also avoids a `chat` event, which the UI answers by marking read.
+    if !unread {
+        return Ok(());
+    }
+    let ids = update(&engine, &peer, |thread| {
+        thread
+            .iter_mut()
+            .filter(|m| m.direction == Direction::Received && m.status != ChatStatus::Read)
+            .map(|m| {
+                m.status = ChatStatus::Read;
+                m.id.clone()
+            })
+            .collect::<Vec<_>>()
+    })?;
+    if !ids.is_empty() && chat_peer(&engine).as_deref() == Some(peer.as_str()) {
+        sync::send_message(&engine, &WsMessage::ChatAck { ids, read: true }).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestEngine};
+
+    fn statuses(test: &TestEngine, peer: &str) -> Vec<ChatStatus> {
+        let thread = list_chat(peer.to_string(), test.engine.clone()).unwrap();
+        thread.iter().map(|m| m.status).collect()
+    }
+
+    /// Waits until the thread with `peer` has messages in exactly these states.
+    async fn wait_for_statuses(test: &mut TestEngine, peer: &str, expected: &[ChatStatus]) {
+        while statuses(test, peer) != expected {
+            test.wait_for("chat", |p| p["peer"] == peer).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_message_is_sent_on_connect_then_delivered_and_read() {
+        let mut host = TestEngine::new("chat-host");
+        let mut client = TestEngine::new("chat-client");
+        let sent = send_chat("chat-host".into(), " hello ".into(), client.engine.clone())
+            .await
+            .unwrap();
+        assert_eq!(sent.text, "hello");
+        assert!(sent.status == ChatStatus::Queued);
+
+        testing::connect(&mut host, &mut client).await;
+        wait_for_statuses(&mut client, "chat-host", &[ChatStatus::Delivered]).await;
+        wait_for_statuses(&mut host, "chat-client", &[ChatStatus::Delivered]).await;
+        let threads = list_chat_threads(host.engine.clone()).unwrap();
+        assert_eq!(threads[0].unread, 1);
+
+        mark_chat_read("chat-client".into(), host.engine.clone()).await.unwrap();
+        assert!(statuses(&host, "chat-client") == [ChatStatus::Read]);
+        assert_eq!(list_chat_threads(host.engine.clone()).unwrap()[0].unread, 0);
+        wait_for_statuses(&mut client, "chat-host", &[ChatStatus::Read]).await;
+
+        // A late delivery receipt never takes a message back from read.
+        receive_ack(&client.engine, vec![sent.id], false);
+        assert!(statuses(&client, "chat-host") == [ChatStatus::Read]);
+    }
+
+    #[tokio::test]
+    async fn resent_message_is_stored_once() {
+        let mut host = TestEngine::new("chat-dup-host");
+        let mut client = TestEngine::new("chat-dup-client");
+        testing::connect(&mut host, &mut client).await;
+
+        for _ in 0..2 {
+            receive_chat(&host.engine, "same-id".into(), "twice".into(), 1).await;
+        }
+        let thread = list_chat("chat-dup-client".into(), host.engine.clone()).unwrap();
+        assert_eq!(thread.len(), 1);
+        assert!(thread[0].direction == Direction::Received);
+    }
+
+    /// Waits for the queued save, then loads the chats again.
+    async fn reload(test: &TestEngine, peers: usize) -> Chats {
+        let deadline = std::time::Instant::now() + testing::EVENT_TIMEOUT;
+        loop {
+            let chats = load(&test.engine);
+            if chats.threads.len() == peers || std::time::Instant::now() > deadline {
+                return chats;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn each_peer_is_saved_to_its_own_file() {
+        let test = TestEngine::new("chat-files");
+        for peer in ["Studio", "../../etc/passwd", "Kitchen iPad"] {
+            send_chat(peer.into(), format!("hi {peer}"), test.engine.clone()).await.unwrap();
+        }
+        let chats = reload(&test, 3).await;
+        assert_eq!(chats.threads["../../etc/passwd"][0].text, "hi ../../etc/passwd");
+        assert_eq!(chats.threads["Kitchen iPad"].len(), 1);
+
+        let files = std::fs::read_dir(chat_dir(&test.engine)).unwrap().count();
+        assert_eq!(files, 3);
+        assert!(thread_path(&test.engine, "../../etc/passwd").starts_with(chat_dir(&test.engine)));
+    }
+
+    #[tokio::test]
+    async fn single_chat_file_is_split_up() {
+        let test = TestEngine::new("chat-legacy");
+        let legacy = test.engine.paths().data_dir.join(LEGACY_CHAT_FILE);
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        let message = r#"{"id":"1","direction":"received","text":"old","timestamp":1,"status":"read"}"#;
+        std::fs::write(&legacy, format!(r#"{{"threads":{{"Studio":[{message}]}}}}"#)).unwrap();
+
+        let chats = load(&test.engine);
+        assert_eq!(chats.threads["Studio"][0].text, "old");
+        assert!(!legacy.exists());
+        assert_eq!(load(&test.engine).threads["Studio"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_messages_are_refused() {
+        let test = TestEngine::new("chat-empty");
+        assert!(send_chat("peer".into(), "  \n".into(), test.engine.clone()).await.is_err());
+        assert!(list_chat_threads(test.engine.clone()).unwrap().is_empty());
+    }
+}
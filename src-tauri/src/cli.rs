@@ -0,0 +1,516 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clipboard::ClipContent;
+use crate::control::{self, Client, ControlInfo};
+use crate::daemon::{self, Headless};
+use crate::engine::{self, Engine, EventSink, Paths};
+use crate::sync::{self, Peer};
+
+const USAGE: &str = "Usage: filetwin [options] <command>
+
+Commands:
+  send <file>...      Send files to the peer
+  clip push [text]    Send text, or stdin when piped, or the local clipboard
+  clip pull           Wait for the next clipboard item from the peer and print it
+  peers               List peers found on the network
+  connect <peer>      Connect the daemon to a peer, by name or host:port
+  status              Show the connection
+  watch <folder>      Send files added to or changed in the folder, until interrupted
+  events [name]...    Print events as they happen, all or only those named, until
+                      interrupted
+
+Options:
+  --socket <path>     Control endpoint of a running filetwind or app; found through
+                      their control.json if unset (also FILETWIN_SOCKET)
+  --token <token>     Token for --socket, read from control.json if unset (also
+                      FILETWIN_TOKEN)
+  --to <peer>         Connect to the peer directly, by name or host:port, instead of
+                      going through filetwind (also FILETWIN_PEER)
+  --timeout <secs>    How long to wait for a peer or its clipboard (default 30)
+  --json              Print results as JSON";
+
+/// How long mDNS browsing runs before peers are listed.
+const BROWSE_TIME: Duration = Duration::from_secs(3);
+/// How long a direct connection waits for the peer's `Hello`.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Command {
+    Send(Vec<PathBuf>),
+    ClipPush(Option<String>),
+    ClipPull,
+    Peers,
+    Connect(String),
+    Status,
+    Watch(PathBuf),
+    Events(Vec<String>),
+}
+
+struct Options {
+    socket: Option<String>,
+    token: Option<String>,
+    to: Option<String>,
+    timeout: Duration,
+    json: bool,
+}
+
+fn parse(args: Vec<String>) -> Result<(Options, Command), String> {
+    let mut options = Options {
+        socket: std::env::var("FILETWIN_SOCKET").ok(),
+        token: std::env::var("FILETWIN_TOKEN").ok(),
+        to: std::env::var("FILETWIN_PEER").ok(),
+        timeout: Duration::from_secs(30),
+        json: false,
+    };
+    let mut words = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => options.socket = Some(args.next().ok_or("--socket needs a path")?),
+            "--token" => options.token = Some(args.next().ok_or("--token needs a token")?),
+            "--to" => options.to = Some(args.next().ok_or("--to needs a peer")?),
+            "--timeout" => {
+                let secs = args.next().and_then(|s| s.parse().ok());
+                options.timeout = Duration::from_secs(secs.ok_or("--timeout needs seconds")?);
+            }
+            "--json" => options.json = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {flag}")),
+            _ => words.push(arg),
+        }
+    }
+
+    let mut words = words.into_iter();
+    let command = match (words.next().as_deref(), words.next()) {
+        (Some("send"), Some(first)) => {
+            let mut paths = vec![PathBuf::from(first)];
+            paths.extend(words.by_ref().map(PathBuf::from));
+            Command::Send(paths)
+        }
+        (Some("clip"), Some(sub)) if sub == "push" => Command::ClipPush(words.next()),
+        (Some("clip"), Some(sub)) if sub == "pull" => Command::ClipPull,
+        (Some("peers"), None) => Command::Peers,
+        (Some("connect"), Some(peer)) => Command::Connect(peer),
+        (Some("status"), None) => Command::Status,
+        (Some("watch"), Some(folder)) => Command::Watch(PathBuf::from(folder)),
+        (Some("events"), first) => Command::Events(first.into_iter().chain(words.by_ref()).collect()),
+        (None, _) => return Err("Missing command".to_string()),
+        _ => return Err("Unknown command or missing argument".to_string()),
+    };
+    if words.next().is_some() {
+        return Err("Too many arguments".to_string());
+    }
+    Ok((options, command))
+}
+
+/// `host:port`, if `peer` is written that way rather than as a name.
+fn host_port(peer: &str) -> Option<(String, u16)> {
+    let (host, port) = peer.rsplit_once(':')?;
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+fn find_peer<'a>(peers: &'a [Peer], name: &str) -> Option<&'a Peer> {
+    peers.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// Where commands are carried out: a running daemon, or a connection of our own.
+trait Backend {
+    fn send_file(&mut self, path: &Path) -> Result<(), String>;
+    fn push_clip(&mut self, text: Option<String>) -> Result<(), String>;
+    fn pull_clip(&mut self, timeout: Duration) -> Result<Option<ClipContent>, String>;
+    fn peers(&mut self) -> Result<Vec<Peer>, String>;
+    fn connect(&mut self, peer: &str, timeout: Duration) -> Result<(), String>;
+    fn status(&mut self) -> Result<Value, String>;
+    /// Passes events to `each`, all or only those named, until the connection ends.
+    fn follow(&mut self, events: &[String], each: &mut dyn FnMut(&str, &Value)) -> Result<(), String>;
+}
+
+/// Talks to filetwind or the app over their control endpoint.
+struct Daemon {
+    client: Client,
+}
+
+impl Daemon {
+    fn browse(&mut self) -> Result<Vec<Peer>, String> {
+        let peers: Vec<Peer> = serde_json::from_value(self.client.call("peers", Value::Null)?)
+            .map_err(|e| e.to_string())?;
+        if !peers.is_empty() {
+            return Ok(peers);
+        }
+        self.client.call("start_browse", Value::Null)?;
+        std::thread::sleep(BROWSE_TIME);
+        serde_json::from_value(self.client.call("peers", Value::Null)?).map_err(|e| e.to_string())
+    }
+}
+
+impl Backend for Daemon {
+    fn send_file(&mut self, path: &Path) -> Result<(), String> {
+        // The daemon runs in a different directory.
+        let path = path.canonicalize().map_err(|e| format!("{}: {e}", path.display()))?;
+        self.client.call("send_file", json!({ "path": path })).map(drop)
+    }
+
+    fn push_clip(&mut self, text: Option<String>) -> Result<(), String> {
+        self.client.call("send_clipboard", json!({ "text": text })).map(drop)
+    }
+
+    fn pull_clip(&mut self, timeout: Duration) -> Result<Option<ClipContent>, String> {
+        // The last item may be hours old; wait for the peer to copy something, as `--to` does.
+        self.client.call("subscribe", json!({ "events": ["remote_clipboard"] }))?;
+        eprintln!("Waiting for the next clipboard item from the peer");
+        if self.client.next_event_within(timeout)?.is_none() {
+            return Ok(None);
+        }
+        serde_json::from_value(self.client.call("last_remote_clipboard", Value::Null)?)
+            .map_err(|e| e.to_string())
+    }
+
+    fn peers(&mut self) -> Result<Vec<Peer>, String> {
+        self.browse()
+    }
+
+    fn connect(&mut self, peer: &str, _timeout: Duration) -> Result<(), String> {
+        let (host, port) = match host_port(peer) {
+            Some(addr) => addr,
+            None => {
+                let peers = self.browse()?;
+                let found = find_peer(&peers, peer).ok_or(format!("No peer named {peer} found"))?;
+                (found.host.clone(), found.port)
+            }
+        };
+        self.client.call("connect_to", json!({ "host": host, "port": port })).map(drop)
+    }
+
+    fn status(&mut self) -> Result<Value, String> {
+        self.client.call("status", Value::Null)
+    }
+
+    fn follow(&mut self, events: &[String], each: &mut dyn FnMut(&str, &Value)) -> Result<(), String> {
+        let only = (!events.is_empty()).then_some(events);
+        self.client.call("subscribe", json!({ "events": only }))?;
+        loop {
+            let (event, payload) = self.client.next_event()?;
+            each(&event, &payload);
+        }
+    }
+}
+
+/// Collects engine events for the command waiting on them.
+struct ChannelEvents(Mutex<mpsc::Sender<(String, Value)>>);
+
+impl EventSink for ChannelEvents {
+    fn emit(&self, event: &str, payload: Value) {
+        let _ = self.0.lock().unwrap().send((event.to_string(), payload));
+    }
+}
+
+/// Runs an engine of our own for the length of the command and connects to `to`.
+struct Direct {
+    runtime: tokio::runtime::Runtime,
+    engine: Engine,
+    events: mpsc::Receiver<(String, Value)>,
+    data_dir: PathBuf,
+    to: String,
+}
+
+impl Direct {
+    fn start(to: String) -> Result<Direct, String> {
+        let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+        // Nothing needs to outlive the command.
+        let data_dir = std::env::temp_dir().join(format!("filetwin-{}", std::process::id()));
+        let paths = Paths {
+            config_dir: data_dir.clone(),
+            data_dir: data_dir.clone(),
+            cache_dir: data_dir.join("cache"),
+        };
+        let (tx, events) = mpsc::channel();
+        let engine = Engine::new(
+            engine::host_name(),
+            paths,
+            ChannelEvents(Mutex::new(tx)),
+            Headless,
+            runtime.handle().clone(),
+        );
+        Ok(Direct {
+            runtime,
+            engine,
+            events,
+            data_dir,
+            to,
+        })
+    }
+
+    /// Waits up to `timeout` for an event `matches` accepts.
+    fn wait_for(&self, timeout: Duration, matches: impl Fn(&str, &Value) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.events.recv_timeout(left) {
+                Ok((event, payload)) if matches(&event, &payload) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    fn browse(&self, until: impl Fn(&[Peer]) -> bool, timeout: Duration) -> Result<Vec<Peer>, String> {
+        self.runtime.block_on(sync::start_browse(self.engine.clone()))?;
+        let deadline = Instant::now() + timeout;
+        let mut peers = sync::list_peers(&self.engine);
+        while !until(&peers) && Instant::now() < deadline {
+            self.wait_for(deadline - Instant::now(), |event, _| event == "peers");
+            peers = sync::list_peers(&self.engine);
+        }
+        self.runtime.block_on(sync::stop_browse(self.engine.clone()))?;
+        Ok(peers)
+    }
+
+    /// Connects to the peer unless already connected, and waits for its `Hello`.
+    fn ensure_connected(&self, timeout: Duration) -> Result<(), String> {
+        if sync::is_connected(&self.engine) {
+            return Ok(());
+        }
+        let (host, port) = match host_port(&self.to) {
+            Some(addr) => addr,
+            None => {
+                let peers = self.browse(|peers| find_peer(peers, &self.to).is_some(), timeout)?;
+                let found =
+                    find_peer(&peers, &self.to).ok_or(format!("No peer named {} found", self.to))?;
+                (found.host.clone(), found.port)
+            }
+        };
+        self.runtime
+            .block_on(sync::connect_to(host.clone(), port, self.engine.clone()))?;
+        // Connecting reports the address; the peer's hello then reports its name.
+        let greeted = self.wait_for(HELLO_TIMEOUT, |event, payload| {
+            event == "connected" && payload["name"] != host.as_str()
+        });
+        if !greeted {
+            return Err(format!("{} did not answer in time", self.to));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for Direct {
+    fn send_file(&mut self, path: &Path) -> Result<(), String> {
+        self.ensure_connected(HELLO_TIMEOUT)?;
+        self.runtime
+            .block_on(sync::send_path(path.to_path_buf(), self.engine.clone()))
+    }
+
+    fn push_clip(&mut self, text: Option<String>) -> Result<(), String> {
+        self.ensure_connected(HELLO_TIMEOUT)?;
+        self.runtime
+            .block_on(sync::send_clipboard(text, self.engine.clone()))
+    }
+
+    fn pull_clip(&mut self, timeout: Duration) -> Result<Option<ClipContent>, String> {
+        self.ensure_connected(timeout)?;
+        // A fresh connection has received nothing yet; wait for the peer to copy something.
+        eprintln!("Waiting for the next clipboard item from {}", self.to);
+        self.wait_for(timeout, |event, _| event == "remote_clipboard");
+        Ok(sync::last_remote_clip(&self.engine))
+    }
+
+    fn peers(&mut self) -> Result<Vec<Peer>, String> {
+        self.browse(|_| false, BROWSE_TIME)
+    }
+
+    fn connect(&mut self, _peer: &str, _timeout: Duration) -> Result<(), String> {
+        Err("connect needs a running filetwind; with --to, each command connects by itself".to_string())
+    }
+
+    fn status(&mut self) -> Result<Value, String> {
+        self.ensure_connected(HELLO_TIMEOUT)?;
+        Ok(json!({
+            "name": self.engine.name(),
+            "connected": sync::is_connected(&self.engine),
+            "peer": sync::current_peer(&self.engine),
+        }))
+    }
+
+    fn follow(&mut self, events: &[String], each: &mut dyn FnMut(&str, &Value)) -> Result<(), String> {
+        self.ensure_connected(HELLO_TIMEOUT)?;
+        while sync::is_connected(&self.engine) {
+            match self.events.recv_timeout(WATCH_INTERVAL) {
+                Ok((event, payload)) if events.is_empty() || events.contains(&event) => {
+                    each(&event, &payload)
+                }
+                _ => {}
+            }
+        }
+        Err(format!("Disconnected from {}", self.to))
+    }
+}
+
+impl Drop for Direct {
+    fn drop(&mut self) {
+        let _ = self
+            .runtime
+            .block_on(sync::disconnect_when_sent(self.engine.clone()));
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// Size and modification time, to notice changed files.
+type Stamp = (u64, Option<SystemTime>);
+
+/// Regular files directly in `folder`, skipping hidden ones such as partial downloads.
+fn scan(folder: &Path) -> Result<HashMap<PathBuf, Stamp>, String> {
+    let entries = std::fs::read_dir(folder).map_err(|e| format!("{}: {e}", folder.display()))?;
+    Ok(entries
+        .filter_map(Result::ok)
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok().filter(|m| m.is_file())?;
+            Some((entry.path(), (meta.len(), meta.modified().ok())))
+        })
+        .collect())
+}
+
+/// Sends files that appear in or change in `folder`. A file is sent once it is unchanged
+/// between two scans, so files still being written are not sent half-done. Files already
+/// there at the start are not sent.
+fn watch(backend: &mut dyn Backend, folder: &Path) -> Result<(), String> {
+    let mut sent = scan(folder)?;
+    let mut last = sent.clone();
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        let now = scan(folder)?;
+        for (path, stamp) in &now {
+            if last.get(path) != Some(stamp) || sent.get(path) == Some(stamp) {
+                continue;
+            }
+            // A failed file is tried again only when it changes.
+            match backend.send_file(path) {
+                Ok(()) => println!("sent {}", path.display()),
+                Err(e) => eprintln!("{}: {e}", path.display()),
+            }
+            sent.insert(path.clone(), *stamp);
+        }
+        sent.retain(|path, _| now.contains_key(path));
+        last = now;
+    }
+}
+
+fn print_status(status: &Value, json: bool) {
+    if json {
+        println!("{status}");
+        return;
+    }
+    println!("name: {}", status["name"].as_str().unwrap_or_default());
+    match status["peer"].as_str() {
+        Some(peer) if status["connected"] == true => println!("connected to {peer}"),
+        _ => println!("not connected"),
+    }
+}
+
+fn print_peers(peers: &[Peer], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(peers).unwrap_or_default());
+        return;
+    }
+    for peer in peers {
+        println!("{}\t{}:{}", peer.name, peer.host, peer.port);
+    }
+}
+
+fn execute(options: Options, command: Command) -> Result<(), String> {
+    let mut backend: Box<dyn Backend> = match options.to.clone() {
+        Some(to) => Box::new(Direct::start(to)?),
+        None => {
+            let info = match (options.socket.clone(), options.token.clone()) {
+                (Some(endpoint), Some(token)) => Ok(ControlInfo { endpoint, token }),
+                (None, Some(token)) => Ok(ControlInfo {
+                    endpoint: daemon::Config::default().socket(),
+                    token,
+                }),
+                (endpoint, None) => control::discover(endpoint.as_deref()),
+            };
+            let client = info.and_then(|info| Client::connect(&info)).map_err(|e| {
+                format!("{e}\nUse --to <peer> to connect directly.")
+            })?;
+            Box::new(Daemon { client })
+        }
+    };
+
+    match command {
+        Command::Send(paths) => paths.iter().try_for_each(|path| backend.send_file(path)),
+        Command::ClipPush(text) => {
+            let text = match text {
+                Some(text) => Some(text),
+                None if !std::io::stdin().is_terminal() => {
+                    let mut text = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut text)
+                        .map_err(|e| e.to_string())?;
+                    Some(text)
+                }
+                // Everything on the local clipboard.
+                None => None,
+            };
+            backend.push_clip(text)
+        }
+        Command::ClipPull => {
+            let content = backend
+                .pull_clip(options.timeout)?
+                .ok_or("Nothing received from the peer yet")?;
+            if options.json {
+                println!("{}", serde_json::to_string(&content).map_err(|e| e.to_string())?);
+            } else if content.text.is_empty() {
+                return Err(format!(
+                    "The clipboard item has no text ({})",
+                    content.format_names().join(", ")
+                ));
+            } else {
+                print!("{}", content.text);
+            }
+            Ok(())
+        }
+        Command::Peers => {
+            print_peers(&backend.peers()?, options.json);
+            Ok(())
+        }
+        Command::Connect(peer) => backend.connect(&peer, options.timeout),
+        Command::Status => {
+            print_status(&backend.status()?, options.json);
+            Ok(())
+        }
+        Command::Watch(folder) => watch(backend.as_mut(), &folder),
+        Command::Events(events) => backend.follow(&events, &mut |event, payload| {
+            if options.json {
+                println!("{}", json!({ "event": event, "payload": payload }));
+            } else {
+                println!("{event}\t{payload}");
+            }
+        }),
+    }
+}
+
+/// Runs the `filetwin` command line and returns the exit code.
+pub fn main(args: Vec<String>) -> i32 {
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{USAGE}");
+        return 0;
+    }
+    let (options, command) = match parse(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("filetwin: {e}\n\n{USAGE}");
+            return 2;
+        }
+    };
+    match execute(options, command) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("filetwin: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use base64::Engine as _;
+use clipboard_rs::common::RustImage;
+use clipboard_rs::{
+    Clipboard, ClipboardContent, ClipboardContext, ClipboardHandler, ClipboardWatcher,
+    ClipboardWatcherContext, ContentFormat, RustImageData,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::engine::Engine;
+use crate::settings;
+use crate::sync;
+
+/// One clipboard item as sent over the wire. `text` is always filled in where possible so
+/// peers that only understand plain text still get something useful.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ClipContent {
+    pub text: String,
+    #[serde(default)]
+    pub formats: Vec<ClipFormat>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum ClipFormat {
+    Html { html: String },
+    Rtf { rtf: String },
+    /// Base64-encoded PNG.
+    Image { png: String, width: u32, height: u32 },
+    /// Names of files sent ahead of this item through the regular file transfer.
+    Files { names: Vec<String> },
+}
+
+impl ClipFormat {
+    fn size(&self) -> usize {
+        match self {
+            ClipFormat::Html { html } => html.len(),
+            ClipFormat::Rtf { rtf } => rtf.len(),
+            ClipFormat::Image { png, .. } => png.len(),
+            ClipFormat::Files { names } => names.iter().map(String::len).sum(),
+        }
+    }
+}
+
+impl ClipContent {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.formats.is_empty()
+    }
+
+    /// Approximate size on the wire, in bytes.
+    pub fn size(&self) -> usize {
+        self.text.len() + self.formats.iter().map(ClipFormat::size).sum::<usize>()
+    }
+
+    /// Cuts the item down to about `max` bytes: rich formats that do not fit are dropped,
+    /// then the text is shortened. Returns whether anything was removed.
+    pub fn truncate(&mut self, max: usize) -> bool {
+        if self.size() <= max {
+            return false;
+        }
+        let mut budget = max.saturating_sub(self.text.len().min(max));
+        self.formats.retain(|f| {
+            let fits = f.size() <= budget;
+            if fits {
+                budget -= f.size();
+            }
+            fits
+        });
+        if self.text.len() > max {
+            let mut end = max;
+            while !self.text.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.text.truncate(end);
+        }
+        true
+    }
+
+    /// Identifies the content across a round trip through the OS clipboard. HTML, RTF and
+    /// PNG bytes get rewritten by the OS, so only text, image size and file names count.
+    fn signature(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.text.hash(&mut hasher);
+        for format in &self.formats {
+            match format {
+                ClipFormat::Image { width, height, .. } => (width, height).hash(&mut hasher),
+                ClipFormat::Files { names } => names.hash(&mut hasher),
+                ClipFormat::Html { .. } | ClipFormat::Rtf { .. } => {}
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Format names for the UI, e.g. `["image", "text"]`.
+    pub fn format_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self
+            .formats
+            .iter()
+            .map(|f| match f {
+                ClipFormat::Html { .. } => "html",
+                ClipFormat::Rtf { .. } => "rtf",
+                ClipFormat::Image { .. } => "image",
+                ClipFormat::Files { .. } => "files",
+            })
+            .collect();
+        if !self.text.is_empty() {
+            names.push("text");
+        }
+        names
+    }
+}
+
+/// Clipboard content read on this machine, ready to send.
+#[derive(Default)]
+pub struct LocalClip {
+    pub content: ClipContent,
+    /// Paths of the copied files named in a `Files` format.
+    pub files: Vec<PathBuf>,
+    /// The source app flagged the content as a password or otherwise not to be shared.
+    pub concealed: bool,
+}
+
+impl From<ClipContent> for LocalClip {
+    fn from(content: ClipContent) -> Self {
+        LocalClip {
+            content,
+            ..Default::default()
+        }
+    }
+}
+
+/// What the watcher has seen, by content signature, so it only sends real local changes.
+#[derive(Default)]
+pub struct ClipboardWatch {
+    /// Last clipboard content observed locally.
+    last_seen: Option<u64>,
+    /// Last content received from the peer. Seeing it locally means we just applied it,
+    /// so sending it back would start a ping-pong loop.
+    last_remote: Option<u64>,
+}
+
+/// `file://` URIs (as used on Linux) to plain paths.
+fn uri_to_path(uri: &str) -> PathBuf {
+    let Some(encoded) = uri.strip_prefix("file://") else {
+        return PathBuf::from(uri);
+    };
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Markers password managers put on the clipboard: the nspasteboard.org types on macOS,
+/// the clipboard history/monitor opt-outs on Windows and KDE's hint on Linux.
+fn is_concealed(ctx: &ClipboardContext) -> bool {
+    let formats = ctx.available_formats().unwrap_or_default();
+    formats.iter().any(|format| match format.as_str() {
+        "org.nspasteboard.ConcealedType"
+        | "org.nspasteboard.TransientType"
+        | "ExcludeClipboardContentFromMonitorProcessing" => true,
+        // A DWORD set to 0 means "keep out of clipboard history".
+        "CanIncludeInClipboardHistory" | "CanUploadToCloudClipboard" => ctx
+            .get_buffer(format)
+            .is_ok_and(|value| value.iter().all(|b| *b == 0)),
+        "x-kde-passwordManagerHint" => ctx
+            .get_buffer(format)
+            .is_ok_and(|value| value.starts_with(b"secret")),
+        _ => false,
+    })
+}
+
+fn read_image(ctx: &ClipboardContext) -> Option<ClipFormat> {
+    let image = ctx.get_image().ok()?;
+    let (width, height) = image.get_size();
+    let png = image.to_png().ok()?;
+    Some(ClipFormat::Image {
+        png: base64::engine::general_purpose::STANDARD.encode(png.get_bytes()),
+        width,
+        height,
+    })
+}
+
+/// Reads every supported format from the system clipboard, plus the local paths of any
+/// copied files.
+fn read_local(ctx: &ClipboardContext) -> Option<LocalClip> {
+    let mut content = ClipContent {
+        text: ctx.get_text().unwrap_or_default(),
+        formats: Vec::new(),
+    };
+    let mut files = Vec::new();
+
+    if ctx.has(ContentFormat::Files) {
+        files = ctx
+            .get_files()
+            .unwrap_or_default()
+            .iter()
+            .map(|f| uri_to_path(f))
+            .filter(|p| p.is_file())
+            .collect();
+        let names: Vec<String> = files
+            .iter()
+            .filter_map(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .collect();
+        if !names.is_empty() {
+            if content.text.is_empty() {
+                content.text = names.join("\n");
+            }
+            content.formats.push(ClipFormat::Files { names });
+        }
+    }
+    if ctx.has(ContentFormat::Image) {
+        content.formats.extend(read_image(ctx));
+    }
+    if ctx.has(ContentFormat::Html) {
+        if let Ok(html) = ctx.get_html() {
+            content.formats.push(ClipFormat::Html { html });
+        }
+    }
+    if ctx.has(ContentFormat::Rtf) {
+        if let Ok(rtf) = ctx.get_rich_text() {
+            content.formats.push(ClipFormat::Rtf { rtf });
+        }
+    }
+
+    (!content.is_empty()).then(|| LocalClip {
+        content,
+        files,
+        concealed: is_concealed(ctx),
+    })
+}
+
+/// Reads the system clipboard for an explicit "send my clipboard".
+pub fn read_current() -> Result<LocalClip, String> {
+    let ctx = ClipboardContext::new().map_err(|e| e.to_string())?;
+    read_local(&ctx).ok_or_else(|| "Clipboard is empty".to_string())
+}
+
+/// Converts to OS clipboard entries, richest first. `files` are the local copies of the
+/// files named in a `Files` format.
+fn to_os_contents(content: &ClipContent, files: &[PathBuf]) -> Vec<ClipboardContent> {
+    let mut contents = Vec::new();
+    if !files.is_empty() {
+        let paths = files.iter().map(|p| p.to_string_lossy().into_owned()).collect();
+        contents.push(ClipboardContent::Files(paths));
+    }
+    for format in &content.formats {
+        match format {
+            ClipFormat::Image { png, .. } => {
+                let image = base64::engine::general_purpose::STANDARD
+                    .decode(png)
+                    .ok()
+                    .and_then(|bytes| RustImageData::from_bytes(&bytes).ok());
+                if let Some(image) = image {
+                    contents.push(ClipboardContent::Image(image));
+                }
+            }
+            ClipFormat::Html { html } => contents.push(ClipboardContent::Html(html.clone())),
+            ClipFormat::Rtf { rtf } => contents.push(ClipboardContent::Rtf(rtf.clone())),
+            ClipFormat::Files { .. } => {}
+        }
+    }
+    if !content.text.is_empty() {
+        contents.push(ClipboardContent::Text(content.text.clone()));
+    }
+    contents
+}
+
+/// Writes all formats to the system clipboard at once, falling back to the best single
+/// format this platform accepts.
+pub fn write_local(content: &ClipContent, files: &[PathBuf]) -> Result<(), String> {
+    let ctx = ClipboardContext::new().map_err(|e| e.to_string())?;
+    let contents = to_os_contents(content, files);
+    if contents.is_empty() || ctx.set(contents).is_ok() {
+        return Ok(());
+    }
+    for single in to_os_contents(content, files) {
+        if ctx.set(vec![single]).is_ok() {
+            return Ok(());
+        }
+    }
+    Err("Could not write to the clipboard".to_string())
+}
+
+/// Records content that arrived from the peer so the watcher does not echo it back.
+pub fn note_remote(engine: &Engine, content: &ClipContent) {
+    let state = engine.state();
+    state.clipboard.lock().unwrap().last_remote = Some(content.signature());
+}
+
+/// True if `content` is a local change we have not seen yet and did not come from the peer.
+fn take_local_change(engine: &Engine, content: &ClipContent) -> bool {
+    let signature = content.signature();
+    let Ok(mut watch) = engine.state().clipboard.lock() else {
+        return false;
+    };
+    if watch.last_seen == Some(signature) {
+        return false;
+    }
+    watch.last_seen = Some(signature);
+    watch.last_remote != Some(signature)
+}
+
+struct Watcher {
+    engine: Engine,
+    ctx: ClipboardContext,
+}
+
+impl ClipboardHandler for Watcher {
+    fn on_clipboard_change(&mut self) {
+        let Some(clip) = read_local(&self.ctx) else {
+            return;
+        };
+        if !take_local_change(&self.engine, &clip.content) {
+            return;
+        }
+        if settings::current(&self.engine).sync_clipboard && sync::is_connected(&self.engine) {
+            let _ = self.engine.block_on(sync::send_clip(&self.engine, clip));
+        }
+    }
+}
+
+/// Starts the background thread that pushes local clipboard changes to the peer while
+/// real-time sync is enabled. Runs independently of the window, so it keeps working
+/// when the app is hidden.
+pub fn start_watcher(engine: Engine) {
+    if engine.state().watching.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let (Ok(ctx), Ok(mut watcher)) = (ClipboardContext::new(), ClipboardWatcherContext::new())
+        else {
+            engine.state().watching.store(false, Ordering::SeqCst);
+            return;
+        };
+        // Whatever is on the clipboard at startup was not copied for the peer.
+        if let Some(clip) = read_local(&ctx) {
+            take_local_change(&engine, &clip.content);
+        }
+        watcher.add_handler(Watcher { engine: engine.clone(), ctx });
+        watcher.start_watch();
+        engine.state().watching.store(false, Ordering::SeqCst);
+    });
+}
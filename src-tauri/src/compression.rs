@@ -0,0 +1,77 @@
+use std::path::Path;
+
+/// Codec name advertised in `Hello` and announced in `FileStart`.
+pub const ZSTD: &str = "zstd";
+
+/// Fast level: transfers are network-bound, not worth burning CPU on a better ratio.
+const LEVEL: i32 = 3;
+/// Bytes compressed up front to decide whether a file is worth compressing at all.
+const SAMPLE_BYTES: usize = 64 * 1024;
+/// A sample must shrink below this fraction of its size (in percent) to enable compression.
+const MIN_SAVING_PERCENT: usize = 90;
+
+/// Formats that are already compressed; compressing them again only costs CPU.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar",
+    "jpeg", "jpg", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "tgz",
+    "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Decides from the name and the first chunk whether compressing this file pays off.
+pub fn worth_compressing(name: &str, sample: &[u8]) -> bool {
+    let ext = Path::new(name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if ext.is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str())) {
+        return false;
+    }
+
+    let sample = &sample[..sample.len().min(SAMPLE_BYTES)];
+    if sample.is_empty() {
+        return false;
+    }
+    match compress(sample) {
+        Ok(packed) => packed.len() * 100 < sample.len() * MIN_SAVING_PERCENT,
+        Err(_) => false,
+    }
+}
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, String> {
+    zstd::bulk::compress(data, LEVEL).map_err(|e| e.to_string())
+}
+
+/// Decompresses one chunk, refusing to produce more than `max_len` bytes so a hostile
+/// peer cannot send a decompression bomb.
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, String> {
+    zstd::bulk::decompress(data, max_len).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::noise;
+
+    #[test]
+    fn skips_formats_that_are_already_compressed() {
+        let text = "the same line again\n".repeat(1000);
+        assert!(worth_compressing("notes.txt", text.as_bytes()));
+        assert!(!worth_compressing("photo.JPG", text.as_bytes()));
+        assert!(!worth_compressing("archive.tar.gz", text.as_bytes()));
+        assert!(!worth_compressing("movie.mkv", text.as_bytes()));
+    }
+
+    #[test]
+    fn skips_incompressible_and_empty_samples() {
+        assert!(!worth_compressing("data.bin", &noise(64 * 1024)));
+        assert!(!worth_compressing("empty.txt", b""));
+    }
+
+    #[test]
+    fn round_trips_and_bounds_output() {
+        let data = "compress me ".repeat(500);
+        let packed = compress(data.as_bytes()).unwrap();
+        assert_eq!(decompress(&packed, data.len()).unwrap(), data.as_bytes());
+        assert!(decompress(&packed, data.len() - 1).is_err());
+        assert!(decompress(b"not zstd", 1024).is_err());
+    }
+}
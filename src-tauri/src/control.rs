@@ -0,0 +1,797 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::engine::Engine;
+use crate::{actions, chat, history, links, mirror, outbox, settings, sync};
+
+/// Written to the data dir while serving: where the endpoint is and its token.
+const CONTROL_FILE: &str = "control.json";
+/// The app's data folder, named after the identifier in `tauri.conf.json`.
+const APP_IDENTIFIER: &str = "com.remotesync.app";
+/// Longest request line accepted; longer ones close the connection.
+const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+/// Streamed only when subscribed to by name, as they are large and frequent.
+const NOISY_EVENTS: &[&str] = &["mirror_frame"];
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The operation itself failed, e.g. "Not connected".
+const OPERATION_FAILED: i64 = -32000;
+const NOT_AUTHENTICATED: i64 = -32001;
+
+/// How clients find and authenticate to a running daemon or app.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ControlInfo {
+    pub endpoint: String,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<String> for RpcError {
+    fn from(message: String) -> Self {
+        RpcError {
+            code: OPERATION_FAILED,
+            message,
+        }
+    }
+}
+
+/// A Unix socket named `socket` in `dir`, or a loopback address with `port` where there
+/// are no Unix sockets.
+pub fn endpoint_in(dir: &Path, socket: &str, port: u16) -> String {
+    if cfg!(unix) {
+        dir.join(socket).to_string_lossy().into_owned()
+    } else {
+        format!("127.0.0.1:{port}")
+    }
+}
+
+/// Where the app serves its control endpoint.
+#[cfg(feature = "gui")]
+pub fn app_endpoint(engine: &Engine) -> String {
+    endpoint_in(&engine.paths().data_dir, "control.sock", 47325)
+}
+
+fn new_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Compares in constant time, so the token cannot be guessed byte by byte.
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Replaces `path` with `bytes`, readable only by the current user. The file is written
+/// fresh and renamed into place, as opening an existing file keeps its old permissions.
+fn write_private(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let dir = path.parent().ok_or("Invalid path")?;
+    // Created with mode 0600 on Unix.
+    let mut file = tempfile::NamedTempFile::new_in(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    file.write_all(bytes).map_err(|e| e.to_string())?;
+    file.persist(path).map_err(|e| format!("{}: {}", path.display(), e.error))?;
+    Ok(())
+}
+
+/// Removes a socket left behind by a previous run, which would make bind fail. Anything
+/// else at `endpoint` is left alone: a file that is not a socket, one owned by another
+/// user, or a socket something is still listening on.
+#[cfg(unix)]
+fn remove_stale_socket(endpoint: &str) -> Result<(), String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let meta = match std::fs::symlink_metadata(endpoint) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{endpoint}: {e}")),
+    };
+    // SAFETY: getuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    if !meta.file_type().is_socket() || meta.uid() != uid {
+        return Err(format!("{endpoint}: already exists and is not our socket"));
+    }
+    if std::os::unix::net::UnixStream::connect(endpoint).is_ok() {
+        return Err(format!("{endpoint}: already being served"));
+    }
+    std::fs::remove_file(endpoint).map_err(|e| format!("{endpoint}: {e}"))
+}
+
+/// Parameter `name`. A missing one reads as `null`, so optional parameters may be left out.
+fn arg<T: DeserializeOwned>(params: &Value, name: &str) -> Result<T, RpcError> {
+    let value = params.get(name).cloned().unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|e| RpcError {
+        code: INVALID_PARAMS,
+        message: format!("{name}: {e}"),
+    })
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::from(e.to_string()))
+}
+
+/// The app's commands by the same names and parameters. Where the app asks with a file
+/// dialog, the path is a parameter instead: `send_file`, `save_received_file` (a file,
+/// or a folder to save into) and `set_download_dir`.
+async fn dispatch(engine: &Engine, method: &str, p: &Value) -> Result<Value, RpcError> {
+    let engine = engine.clone();
+    match method {
+        "status" => Ok(json!({
+            "name": engine.name(),
+            "connected": sync::is_connected(&engine),
+            "peer": sync::current_peer(&engine),
+            "hosting": sync::is_hosting(&engine),
+            "port": sync::host_port(&engine),
+        })),
+        "get_host_name" => to_value(engine.name()),
+        "peers" => to_value(sync::list_peers(&engine)),
+        "start_host" => to_value(sync::start_host(engine).await?),
+        "stop_host" => to_value(sync::stop_host(engine).await?),
+        "start_browse" => to_value(sync::start_browse(engine).await?),
+        "stop_browse" => to_value(sync::stop_browse(engine).await?),
+        "connect_to" => {
+            to_value(sync::connect_to(arg(p, "host")?, arg(p, "port")?, engine).await?)
+        }
+        "disconnect" => to_value(sync::disconnect(engine).await?),
+        "send_clipboard" => to_value(sync::send_clipboard(arg(p, "text")?, engine).await?),
+        "paste_from_remote" => to_value(sync::paste_from_remote(engine).await?),
+        "last_remote_clipboard" => to_value(sync::last_remote_clip(&engine)),
+        "send_bring_to_front" => to_value(sync::send_bring_to_front(engine).await?),
+        "send_remote_action" => {
+            to_value(actions::send_remote_action(arg(p, "action")?, engine).await?)
+        }
+        "answer_remote_action" => to_value(
+            actions::answer_remote_action(
+                arg(p, "id")?,
+                arg(p, "allow")?,
+                arg(p, "remember")?,
+                engine,
+            )
+            .await?,
+        ),
+        "list_action_log" => to_value(actions::list_action_log(engine)?),
+        "send_url" => to_value(links::send_url(arg(p, "url")?, arg(p, "title")?, engine).await?),
+        "list_links" => to_value(links::list_links(engine)),
+        "open_link" => to_value(links::open_link(arg(p, "id")?, engine)?),
+        "dismiss_link" => {
+            links::dismiss_link(arg(p, "id")?, engine);
+            Ok(Value::Null)
+        }
+        "send_chat" => {
+            to_value(chat::send_chat(arg(p, "peer")?, arg(p, "text")?, engine).await?)
+        }
+        "list_chat" => to_value(chat::list_chat(arg(p, "peer")?, engine)?),
+        "list_chat_threads" => to_value(chat::list_chat_threads(engine)?),
+        "mark_chat_read" => to_value(chat::mark_chat_read(arg(p, "peer")?, engine).await?),
+        "list_outbox" => to_value(outbox::list_outbox(engine)?),
+        "cancel_outbox" => to_value(outbox::cancel_outbox(arg(p, "id")?, engine)?),
+        "send_file" => to_value(sync::send_path(arg(p, "path")?, engine).await?),
+        "save_received_file" => {
+            let id: String = arg(p, "id")?;
+            let mut path: PathBuf = arg(p, "path")?;
+            // A folder to save into under the name the peer sent.
+            if path.is_dir() {
+                path.push(sync::staged_name(&engine, &id)?);
+            }
+            to_value(sync::save_staged(id, path, engine).await?)
+        }
+        "capture_screenshot_and_send" => {
+            to_value(sync::capture_screenshot_and_send(arg(p, "options")?, engine).await?)
+        }
+        "capture_screenshot" => {
+            to_value(sync::capture_screenshot(arg(p, "mode")?, engine).await?)
+        }
+        "send_screenshot" => to_value(
+            sync::send_screenshot(arg(p, "id")?, arg(p, "edits")?, arg(p, "options")?, engine)
+                .await?,
+        ),
+        "discard_screenshot" => to_value(sync::discard_screenshot(arg(p, "id")?, engine)?),
+        "request_remote_screenshot" => {
+            to_value(sync::request_remote_screenshot(arg(p, "mode")?, engine).await?)
+        }
+        "answer_screenshot_request" => to_value(
+            sync::answer_screenshot_request(
+                arg(p, "id")?,
+                arg(p, "allow")?,
+                arg(p, "remember")?,
+                engine,
+            )
+            .await?,
+        ),
+        "start_screen_share" => to_value(mirror::start_sharing(engine).await?),
+        "stop_screen_share" => {
+            mirror::stop_sharing(engine);
+            Ok(Value::Null)
+        }
+        "get_settings" => to_value(settings::get_settings(engine)?),
+        "set_settings" => to_value(settings::set_settings(arg(p, "settings")?, engine)?),
+        "set_download_dir" => to_value(settings::set_download_dir(
+            arg(p, "peer")?,
+            arg(p, "dir")?,
+            engine,
+        )?),
+        "list_history" => to_value(history::list_history(arg(p, "query")?, engine)?),
+        "pin_history" => {
+            to_value(history::pin_history(arg(p, "id")?, arg(p, "pinned")?, engine)?)
+        }
+        "delete_history" => to_value(history::delete_history(arg(p, "id")?, engine)?),
+        "resend_history" => to_value(sync::resend_history(arg(p, "id")?, engine).await?),
+        "apply_history" => to_value(sync::apply_history(arg(p, "id")?, engine).await?),
+        _ => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method {method}"),
+        }),
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// Events a connection asked for with `subscribe`.
+enum Subscription {
+    Nothing,
+    All,
+    Only(Vec<String>),
+}
+
+impl Subscription {
+    fn wants(&self, event: &str) -> bool {
+        match self {
+            Subscription::Nothing => false,
+            Subscription::All => !NOISY_EVENTS.contains(&event),
+            Subscription::Only(events) => events.iter().any(|e| e == event),
+        }
+    }
+}
+
+/// Per-connection state: whether the token was given and which events to stream.
+struct Session {
+    engine: Engine,
+    token: String,
+    authenticated: bool,
+    /// The first request was not `authenticate` with the right token; the connection
+    /// closes after the reply.
+    refused: bool,
+    subscription: Subscription,
+}
+
+impl Session {
+    /// Answers one request line.
+    async fn respond(&mut self, line: &str) -> Value {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                };
+                self.refused = !self.authenticated;
+                return response(Value::Null, Err(error));
+            }
+        };
+        let p = &request.params;
+        let result = match request.method.as_str() {
+            "authenticate" => match arg::<String>(p, "token") {
+                Ok(given) if token_matches(&given, &self.token) => {
+                    self.authenticated = true;
+                    Ok(Value::Null)
+                }
+                Ok(_) => Err(RpcError {
+                    code: NOT_AUTHENTICATED,
+                    message: "Wrong token".to_string(),
+                }),
+                Err(e) => Err(e),
+            },
+            _ if !self.authenticated => Err(RpcError {
+                code: NOT_AUTHENTICATED,
+                message: "Not authenticated".to_string(),
+            }),
+            "subscribe" => arg::<Option<Vec<String>>>(p, "events").map(|events| {
+                self.subscription = match events {
+                    Some(events) => Subscription::Only(events),
+                    None => Subscription::All,
+                };
+                Value::Null
+            }),
+            "unsubscribe" => {
+                self.subscription = Subscription::Nothing;
+                Ok(Value::Null)
+            }
+            method => dispatch(&self.engine, method, p).await,
+        };
+        self.refused = !self.authenticated;
+        response(request.id, result)
+    }
+}
+
+/// Forwards request lines, so the connection can wait on them and on events at once.
+async fn read_lines<R: AsyncRead + Unpin>(read: R, lines: mpsc::Sender<String>) {
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match (&mut read).take(MAX_REQUEST_BYTES as u64 + 1).read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if line.len() > MAX_REQUEST_BYTES => return,
+            Ok(_) => {}
+        }
+        if !line.trim().is_empty() && lines.send(line.trim().to_string()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The next event, or never when not subscribed.
+async fn next_event(
+    events: &mut Option<broadcast::Receiver<(String, Value)>>,
+) -> Result<(String, Value), broadcast::error::RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Answers requests in order and, once subscribed, streams events between responses as
+/// `event` notifications. A client too slow to keep up is told how many it missed.
+async fn serve_connection<S>(engine: Engine, stream: S, token: String)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let (tx, mut lines) = mpsc::channel(8);
+    let reader = engine.spawn(read_lines(read, tx));
+    let mut events = None;
+    let mut session = Session {
+        engine,
+        token,
+        authenticated: false,
+        refused: false,
+        subscription: Subscription::Nothing,
+    };
+
+    loop {
+        let message = tokio::select! {
+            line = lines.recv() => match line {
+                Some(line) => {
+                    let message = session.respond(&line).await;
+                    // Only subscribed connections take a copy of each event.
+                    match session.subscription {
+                        Subscription::Nothing => events = None,
+                        _ => {
+                            events.get_or_insert_with(|| session.engine.subscribe());
+                        }
+                    }
+                    message
+                }
+                None => break,
+            },
+            event = next_event(&mut events) => match event {
+                Ok((event, payload)) if session.subscription.wants(&event) => {
+                    notification("event", json!({ "event": event, "payload": payload }))
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    notification("events_missed", json!({ "count": missed }))
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        let mut line = message.to_string();
+        line.push('\n');
+        if write.write_all(line.as_bytes()).await.is_err() || session.refused {
+            break;
+        }
+    }
+    reader.abort();
+}
+
+/// Serves line-delimited JSON-RPC 2.0 on `endpoint`: a Unix socket readable only by the
+/// current user, or a loopback TCP address where there are none. Clients authenticate
+/// with a token generated per run and written, with the endpoint, to `control.json` in
+/// the data dir. Runs until the listener fails.
+pub async fn serve(engine: Engine, endpoint: &str) -> Result<(), String> {
+    let data_dir = &engine.paths().data_dir;
+    std::fs::create_dir_all(data_dir).map_err(|e| format!("{}: {e}", data_dir.display()))?;
+    #[cfg(unix)]
+    let listener = {
+        use std::os::unix::fs::PermissionsExt;
+
+        remove_stale_socket(endpoint)?;
+        let listener =
+            tokio::net::UnixListener::bind(endpoint).map_err(|e| format!("{endpoint}: {e}"))?;
+        std::fs::set_permissions(endpoint, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+        listener
+    };
+    #[cfg(not(unix))]
+    let listener = {
+        let addr: std::net::SocketAddr =
+            endpoint.parse().map_err(|_| format!("{endpoint}: not an address"))?;
+        if !addr.ip().is_loopback() {
+            return Err("The control endpoint must be a loopback address".to_string());
+        }
+        tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("{endpoint}: {e}"))?
+    };
+
+    let info = ControlInfo {
+        endpoint: endpoint.to_string(),
+        token: new_token()?,
+    };
+    let bytes = serde_json::to_vec_pretty(&info).map_err(|e| e.to_string())?;
+    write_private(&data_dir.join(CONTROL_FILE), &bytes)?;
+    loop {
+        let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+        engine.spawn(serve_connection(engine.clone(), stream, info.token.clone()));
+    }
+}
+
+/// Removes the endpoint and its token once no longer served.
+pub fn stop_serving(engine: &Engine, endpoint: &str) {
+    if cfg!(unix) {
+        let _ = std::fs::remove_file(endpoint);
+    }
+    let _ = std::fs::remove_file(engine.paths().data_dir.join(CONTROL_FILE));
+}
+
+/// Where the daemon and the app write `control.json`, the daemon first.
+fn control_files() -> Vec<PathBuf> {
+    let mut files = vec![crate::daemon::Config::default().data_dir().join(CONTROL_FILE)];
+    if let Some(dir) = dirs::data_dir() {
+        files.push(dir.join(APP_IDENTIFIER).join(CONTROL_FILE));
+    }
+    files
+}
+
+/// Finds a running daemon or app: the one serving `endpoint` if given, otherwise the first
+/// that accepts connections.
+pub fn discover(endpoint: Option<&str>) -> Result<ControlInfo, String> {
+    control_files()
+        .into_iter()
+        .filter_map(|path| std::fs::read(path).ok())
+        .filter_map(|bytes| serde_json::from_slice::<ControlInfo>(&bytes).ok())
+        .find(|info| match endpoint {
+            Some(endpoint) => info.endpoint == endpoint,
+            None => Client::open(&info.endpoint).is_ok(),
+        })
+        .ok_or_else(|| match endpoint {
+            Some(endpoint) => format!("No token for {endpoint}; set FILETWIN_TOKEN"),
+            None => "Neither filetwind nor the app is running".to_string(),
+        })
+}
+
+#[cfg(unix)]
+type ClientStream = std::os::unix::net::UnixStream;
+#[cfg(not(unix))]
+type ClientStream = std::net::TcpStream;
+
+/// A blocking control endpoint client, for command-line tools.
+pub struct Client {
+    stream: std::io::BufReader<ClientStream>,
+    /// Start of a line whose read timed out, finished by the next read.
+    partial: Vec<u8>,
+    next_id: u64,
+    /// Events that arrived while waiting for a response.
+    events: VecDeque<(String, Value)>,
+}
+
+impl Client {
+    fn open(endpoint: &str) -> Result<ClientStream, String> {
+        ClientStream::connect(endpoint).map_err(|e| format!("{endpoint}: {e}"))
+    }
+
+    /// Connects to `info.endpoint` and authenticates with its token.
+    pub fn connect(info: &ControlInfo) -> Result<Client, String> {
+        let mut client = Client {
+            stream: std::io::BufReader::new(Client::open(&info.endpoint)?),
+            partial: Vec::new(),
+            next_id: 1,
+            events: VecDeque::new(),
+        };
+        client
+            .call("authenticate", json!({ "token": info.token }))
+            .map_err(|e| format!("{}: {e}", info.endpoint))?;
+        Ok(client)
+    }
+
+    /// Reads one line. What was read before an error, such as a read timeout, is kept
+    /// for the next call.
+    fn read_line(&mut self) -> std::io::Result<Vec<u8>> {
+        use std::io::BufRead;
+
+        if self.stream.read_until(b'\n', &mut self.partial)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The control endpoint closed the connection",
+            ));
+        }
+        Ok(std::mem::take(&mut self.partial))
+    }
+
+    /// Reads one message. Events are queued for `next_event`; anything else is returned.
+    fn read_message(&mut self) -> Result<Option<Value>, String> {
+        let line = self.read_line().map_err(|e| e.to_string())?;
+        self.parse_message(&line)
+    }
+
+    fn parse_message(&mut self, line: &[u8]) -> Result<Option<Value>, String> {
+        let mut message: Value = serde_json::from_slice(line).map_err(|e| e.to_string())?;
+        match message["method"].as_str() {
+            Some("event") => {
+                let mut params = message["params"].take();
+                let event = params["event"].as_str().unwrap_or_default().to_string();
+                self.events.push_back((event, params["payload"].take()));
+                Ok(None)
+            }
+            Some(_) => Ok(None),
+            None => Ok(Some(message)),
+        }
+    }
+
+    /// Calls `method` and waits for its result. Operation errors come back as `Err`.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        use std::io::Write;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request =
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string();
+        request.push('\n');
+        self.stream
+            .get_mut()
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut response = loop {
+            if let Some(response) = self.read_message()? {
+                break response;
+            }
+        };
+        match response.get("error") {
+            Some(error) => Err(error["message"].as_str().unwrap_or("Unknown error").to_string()),
+            None => Ok(response["result"].take()),
+        }
+    }
+
+    /// Waits for the next event streamed after `subscribe`.
+    pub fn next_event(&mut self) -> Result<(String, Value), String> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            self.read_message()?;
+        }
+    }
+
+    /// Like `next_event`, but gives up after `timeout` and returns `None`.
+    pub fn next_event_within(&mut self, timeout: Duration) -> Result<Option<(String, Value)>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            let stream = self.stream.get_ref();
+            stream.set_read_timeout(Some(left)).map_err(|e| e.to_string())?;
+            let read = self.read_line();
+            let _ = self.stream.get_ref().set_read_timeout(None);
+            match read {
+                Ok(line) => {
+                    self.parse_message(&line)?;
+                }
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestEngine;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    type Connection = (BufReader<ReadHalf<DuplexStream>>, WriteHalf<DuplexStream>);
+
+    /// A connection served with `TOKEN`, read line by line.
+    fn connect(engine: &Engine) -> Connection {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        engine.spawn(serve_connection(engine.clone(), theirs, TOKEN.to_string()));
+        let (read, write) = tokio::io::split(ours);
+        (BufReader::new(read), write)
+    }
+
+    /// Sends one request and reads the reply, or `None` once the connection is closed.
+    async fn request((read, write): &mut Connection, method: &str, params: Value) -> Option<Value> {
+        let line = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        write.write_all(format!("{line}\n").as_bytes()).await.ok()?;
+        let mut reply = String::new();
+        match read.read_line(&mut reply).await {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(serde_json::from_str(&reply).unwrap()),
+        }
+    }
+
+    fn chat_threads(engine: &Engine) -> usize {
+        chat::list_chat_threads(engine.clone()).unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn requests_before_authenticating_are_refused_and_close_the_connection() {
+        let test = TestEngine::new("control-missing");
+        let mut conn = connect(&test.engine);
+        let chat = json!({ "peer": "peer", "text": "hi" });
+
+        let reply = request(&mut conn, "send_chat", chat.clone()).await.unwrap();
+        assert_eq!(reply["error"]["code"], NOT_AUTHENTICATED);
+        assert!(request(&mut conn, "authenticate", json!({ "token": TOKEN })).await.is_none());
+        assert_eq!(chat_threads(&test.engine), 0);
+    }
+
+    #[tokio::test]
+    async fn wrong_token_closes_the_connection() {
+        let test = TestEngine::new("control-wrong");
+        let mut conn = connect(&test.engine);
+
+        let reply = request(&mut conn, "authenticate", json!({ "token": "guess" })).await.unwrap();
+        assert_eq!(reply["error"]["message"], "Wrong token");
+        let chat = json!({ "peer": "peer", "text": "hi" });
+        assert!(request(&mut conn, "send_chat", chat).await.is_none());
+        assert_eq!(chat_threads(&test.engine), 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_first_request_closes_the_connection() {
+        let test = TestEngine::new("control-garbage");
+        let (read, write) = &mut connect(&test.engine);
+        write.write_all(b"not json\n").await.unwrap();
+        let mut reply = String::new();
+        read.read_line(&mut reply).await.unwrap();
+        assert!(reply.contains("\"error\""));
+        reply.clear();
+        assert_eq!(read.read_line(&mut reply).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn right_token_allows_commands() {
+        let test = TestEngine::new("control-right");
+        let mut conn = connect(&test.engine);
+
+        let reply = request(&mut conn, "authenticate", json!({ "token": TOKEN })).await.unwrap();
+        assert_eq!(reply["result"], Value::Null);
+        let chat = json!({ "peer": "peer", "text": "hi" });
+        let reply = request(&mut conn, "send_chat", chat).await.unwrap();
+        assert_eq!(reply["result"]["text"], "hi");
+        assert_eq!(chat_threads(&test.engine), 1);
+        let reply = request(&mut conn, "get_host_name", Value::Null).await.unwrap();
+        assert_eq!(reply["result"], "control-right");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_file_is_private_even_when_it_existed_before() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::testing::TempDir::new("control-private");
+        let path = dir.0.join(CONTROL_FILE);
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(dir.names(), [CONTROL_FILE]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serving_leaves_a_file_that_is_not_a_socket_alone() {
+        let test = TestEngine::new("control-file");
+        std::fs::create_dir_all(&test.dir).unwrap();
+        let endpoint = test.dir.join("control.sock");
+        std::fs::write(&endpoint, b"not a socket").unwrap();
+
+        let endpoint = endpoint.to_str().unwrap();
+        assert!(serve(test.engine.clone(), endpoint).await.is_err());
+        assert_eq!(std::fs::read(endpoint).unwrap(), b"not a socket");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serving_replaces_a_stale_socket_but_not_a_live_one() {
+        let test = TestEngine::new("control-socket");
+        std::fs::create_dir_all(&test.dir).unwrap();
+        let endpoint = test.dir.join("control.sock");
+        let endpoint = endpoint.to_str().unwrap();
+
+        let live = std::os::unix::net::UnixListener::bind(endpoint).unwrap();
+        let err = serve(test.engine.clone(), endpoint).await.unwrap_err();
+        assert!(err.contains("already being served"), "{err}");
+
+        // Dropping the listener leaves its socket file behind, as a crashed run would.
+        drop(live);
+        let (engine, path) = (test.engine.clone(), endpoint.to_string());
+        test.engine.spawn(async move {
+            let _ = serve(engine, &path).await;
+        });
+        let control = test.engine.paths().data_dir.join(CONTROL_FILE);
+        tokio::time::timeout(crate::testing::EVENT_TIMEOUT, async {
+            while !control.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(ClientStream::connect(endpoint).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn waiting_for_an_event_times_out_without_losing_a_half_read_one() {
+        use std::io::Write;
+
+        let (ours, mut theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut client = Client {
+            stream: std::io::BufReader::new(ours),
+            partial: Vec::new(),
+            next_id: 1,
+            events: VecDeque::new(),
+        };
+        let event = notification("event", json!({ "event": "remote_clipboard", "payload": 1 }));
+        let line = format!("{event}\n");
+        let (start, rest) = line.split_at(10);
+
+        theirs.write_all(start.as_bytes()).unwrap();
+        let waited = client.next_event_within(Duration::from_millis(50)).unwrap();
+        assert!(waited.is_none());
+        theirs.write_all(rest.as_bytes()).unwrap();
+        let (name, payload) = client.next_event_within(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(name, "remote_clipboard");
+        assert_eq!(payload, 1);
+    }
+}
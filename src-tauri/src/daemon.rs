@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::engine::{self, Desktop, Engine, EventSink, Paths};
+use crate::{clipboard, control, sync};
+
+/// How often a daemon configured with `connect` retries while disconnected.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+/// How soon a hosting daemon accepts a new connection after the last one ends.
+const REHOST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events too frequent or too large to log.
+const QUIET_EVENTS: &[&str] = &["mirror_frame", "screen_sharing_stats"];
+
+/// `filetwind.json`. Every field is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name announced to peers. The host name if unset.
+    pub name: Option<String>,
+    /// Settings, history and received files. `filetwind` in the user's data dir if unset.
+    pub data_dir: Option<PathBuf>,
+    /// Control endpoint: a Unix socket path, or a loopback `host:port` on Windows.
+    /// `filetwind.sock` in the data dir (`127.0.0.1:47324` on Windows) if unset.
+    pub socket: Option<String>,
+    /// Advertise over mDNS and accept a connection from a peer.
+    pub host: bool,
+    /// Port to host on. 0 picks a free port each time, which is logged.
+    pub port: u16,
+    /// Look for peers over mDNS, reported through the control socket.
+    pub browse: bool,
+    /// `host:port` of a peer to connect to and stay connected to.
+    pub connect: Option<String>,
+    /// Push local clipboard changes to the peer. Off by default, as servers rarely have
+    /// a clipboard.
+    pub watch_clipboard: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: None,
+            data_dir: None,
+            socket: None,
+            host: true,
+            port: sync::DEFAULT_PORT,
+            browse: false,
+            connect: None,
+            watch_clipboard: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or the defaults if there is no config file.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub(crate) fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("filetwind")
+        })
+    }
+
+    /// The control endpoint clients connect to.
+    pub fn socket(&self) -> String {
+        if let Some(socket) = &self.socket {
+            return socket.clone();
+        }
+        control::endpoint_in(&self.data_dir(), "filetwind.sock", 47324)
+    }
+}
+
+/// Logs events to stderr; clients subscribe to them over the control socket.
+struct LogEvents;
+
+impl EventSink for LogEvents {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        if !QUIET_EVENTS.contains(&event) {
+            eprintln!("{event} {payload}");
+        }
+    }
+}
+
+/// No desktop session: nothing is opened on the peer's behalf.
+pub(crate) struct Headless;
+
+impl Desktop for Headless {
+    fn open_url(&self, _url: &str) -> Result<(), String> {
+        Err("Not available on a headless device".to_string())
+    }
+
+    fn open_path(&self, _path: &Path) -> Result<(), String> {
+        Err("Not available on a headless device".to_string())
+    }
+
+    fn notify(&self, title: &str, body: &str) -> Result<(), String> {
+        eprintln!("notification {title}: {body}");
+        Ok(())
+    }
+
+    fn write_text(&self, _text: &str) -> Result<(), String> {
+        Err("Could not write to the clipboard".to_string())
+    }
+}
+
+/// Keeps a connection to `addr` open, reconnecting after it drops.
+async fn stay_connected(engine: Engine, addr: String) {
+    let Some((host, port)) = addr.rsplit_once(':').and_then(|(h, p)| Some((h, p.parse().ok()?)))
+    else {
+        eprintln!("connect: expected host:port, got {addr}");
+        return;
+    };
+    loop {
+        if !sync::is_connected(&engine) {
+            if let Err(e) = sync::connect_to(host.to_string(), port, engine.clone()).await {
+                eprintln!("connect {addr}: {e}");
+            }
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+/// Hosts on `port` now and again whenever a connection ends, as hosting accepts a
+/// single peer.
+async fn keep_hosting(engine: Engine, port: u16) {
+    loop {
+        if !sync::is_hosting(&engine) {
+            match sync::start_host_on(port, true, engine.clone()).await {
+                Ok(port) => eprintln!("hosting on port {port}"),
+                Err(e) => eprintln!("host: {e}"),
+            }
+        }
+        tokio::time::sleep(REHOST_INTERVAL).await;
+    }
+}
+
+/// Runs the sync engine without a window until interrupted, controlled over the local
+/// socket.
+pub fn run(config: Config) -> Result<(), String> {
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    let data_dir = config.data_dir();
+    std::fs::create_dir_all(&data_dir).map_err(|e| format!("{}: {e}", data_dir.display()))?;
+    let paths = Paths {
+        config_dir: data_dir.clone(),
+        data_dir: data_dir.clone(),
+        cache_dir: data_dir.join("cache"),
+    };
+    let name = config.name.clone().unwrap_or_else(engine::host_name);
+    let engine = Engine::new(name, paths, LogEvents, Headless, runtime.handle().clone());
+
+    runtime.block_on(async {
+        if config.host {
+            let port = sync::start_host_on(config.port, true, engine.clone()).await?;
+            eprintln!("hosting on port {port}");
+            engine.spawn(keep_hosting(engine.clone(), config.port));
+        }
+        if config.browse {
+            sync::start_browse(engine.clone()).await?;
+        }
+        if let Some(addr) = config.connect.clone() {
+            engine.spawn(stay_connected(engine.clone(), addr));
+        }
+        if config.watch_clipboard {
+            clipboard::start_watcher(engine.clone());
+        }
+
+        let socket = config.socket();
+        eprintln!("{} listening for control clients on {socket}", engine.name());
+        let served = tokio::select! {
+            served = control::serve(engine.clone(), &socket) => served,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        };
+        control::stop_serving(&engine, &socket);
+        let _ = sync::disconnect(engine.clone()).await;
+        served
+    })
+}
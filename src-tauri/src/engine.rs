@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::sync::SyncState;
+use crate::{chat, history, outbox, settings};
+
+/// Events kept for a slow subscriber before it misses some.
+const EVENT_BACKLOG: usize = 256;
+
+/// Receives what the engine reports, such as `connected` or `remote_file`. The app
+/// forwards events to the webview, the daemon to its control socket clients.
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+/// The parts of the desktop the engine uses on the peer's behalf.
+pub trait Desktop: Send + Sync {
+    fn open_url(&self, url: &str) -> Result<(), String>;
+    fn open_path(&self, path: &Path) -> Result<(), String>;
+    fn notify(&self, title: &str, body: &str) -> Result<(), String>;
+    /// Plain-text fallback for when writing the full clipboard item fails.
+    fn write_text(&self, text: &str) -> Result<(), String>;
+}
+
+/// Where the engine keeps its files.
+pub struct Paths {
+    /// `settings.json`.
+    pub config_dir: PathBuf,
+    /// History, chats, the outbox and the remote action log.
+    pub data_dir: PathBuf,
+    /// Staged and partially received files.
+    pub cache_dir: PathBuf,
+}
+
+struct Inner {
+    name: String,
+    paths: Paths,
+    state: SyncState,
+    events: Box<dyn EventSink>,
+    /// Every event again, for control socket clients.
+    subscribers: broadcast::Sender<(String, serde_json::Value)>,
+    desktop: Box<dyn Desktop>,
+    runtime: Handle,
+}
+
+/// Handle to the sync engine: its state, where events go and the runtime its tasks run
+/// on. Cheap to clone.
+#[derive(Clone)]
+pub struct Engine {
+    inner: Arc<Inner>,
+}
+
+/// This machine's host name, the default name announced to peers.
+pub fn host_name() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "Mac".to_string())
+}
+
+impl Engine {
+    /// Creates an engine announcing itself as `name` and loads what earlier runs saved
+    /// under `paths`.
+    pub fn new(
+        name: String,
+        paths: Paths,
+        events: impl EventSink + 'static,
+        desktop: impl Desktop + 'static,
+        runtime: Handle,
+    ) -> Engine {
+        let engine = Engine {
+            inner: Arc::new(Inner {
+                name,
+                paths,
+                state: SyncState::default(),
+                events: Box::new(events),
+                subscribers: broadcast::channel(EVENT_BACKLOG).0,
+                desktop: Box::new(desktop),
+                runtime,
+            }),
+        };
+        let state = engine.state();
+        *state.settings.lock().unwrap() = settings::load(&engine);
+        *state.history.lock().unwrap() = history::load(&engine);
+        *state.chats.lock().unwrap() = chat::load(&engine);
+        *state.outbox.lock().unwrap() = outbox::load(&engine);
+        // Files staged by a previous run can no longer be saved.
+        let _ = std::fs::remove_dir_all(crate::sync::staging_dir(&engine));
+        engine
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn paths(&self) -> &Paths {
+        &self.inner.paths
+    }
+
+    pub fn state(&self) -> &SyncState {
+        &self.inner.state
+    }
+
+    pub fn desktop(&self) -> &dyn Desktop {
+        self.inner.desktop.as_ref()
+    }
+
+    pub fn emit(&self, event: &str, payload: impl Serialize) {
+        if let Ok(payload) = serde_json::to_value(payload) {
+            if self.inner.subscribers.receiver_count() > 0 {
+                let _ = self.inner.subscribers.send((event.to_string(), payload.clone()));
+            }
+            self.inner.events.emit(event, payload);
+        }
+    }
+
+    /// Receives every event emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, serde_json::Value)> {
+        self.inner.subscribers.subscribe()
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.inner.runtime.spawn(future)
+    }
+
+    pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.inner.runtime.spawn_blocking(f)
+    }
+
+    /// Runs `future` to completion from a thread outside the runtime, such as the
+    /// clipboard watcher.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.inner.runtime.block_on(future)
+    }
+}
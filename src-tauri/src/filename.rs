@@ -0,0 +1,260 @@
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Most filesystems (APFS, ext4, NTFS) cap a single path component at 255 bytes.
+pub(crate) const MAX_NAME_BYTES: usize = 255;
+/// Extensions longer than this are not worth preserving when truncating.
+const MAX_EXT_BYTES: usize = 16;
+
+pub(crate) const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Bidi overrides can make `evil\u{202E}txt.exe` render as `evilexe.txt`.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+fn is_absolute(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    name.starts_with('/')
+        || name.starts_with('\\')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+fn truncate_to_bytes(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Turns a peer-supplied file name into a single safe path component.
+///
+/// Names that try to escape the target folder (`..`, absolute paths, nested paths) are
+/// rejected outright; everything else is cleaned up so it can be created on any platform.
+pub fn sanitize_file_name(name: &str) -> Result<String, String> {
+    // macOS peers send NFD, Linux and Windows expect NFC. Stripped characters go first so
+    // the marks they separated still compose.
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !is_bidi_control(*c))
+        .nfc()
+        .collect();
+
+    if is_absolute(&name) {
+        return Err("Absolute paths are not allowed".to_string());
+    }
+    let mut components = name.split(['/', '\\']);
+    if components.clone().any(|c| c.trim() == "..") {
+        return Err("Path traversal is not allowed".to_string());
+    }
+    if components.clone().count() > 1 {
+        return Err("Path separators are not allowed".to_string());
+    }
+    let name = components.next().unwrap_or_default();
+
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    // Windows silently drops trailing dots and spaces, which would change the name on disk.
+    let name = name.trim().trim_end_matches(['.', ' ']);
+    if name.is_empty() || name == "." {
+        return Err("Empty file name".to_string());
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    let mut name = if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem.trim_end())) {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    };
+
+    if name.len() > MAX_NAME_BYTES {
+        name = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && ext.len() <= MAX_EXT_BYTES => {
+                let stem = truncate_to_bytes(stem, MAX_NAME_BYTES - ext.len() - 1);
+                format!("{}.{}", stem, ext)
+            }
+            // Cutting can expose dots or spaces that were inside the name.
+            _ => truncate_to_bytes(&name, MAX_NAME_BYTES)
+                .trim_end_matches(['.', ' '])
+                .to_string(),
+        };
+        if name.is_empty() {
+            return Err("Empty file name".to_string());
+        }
+    }
+
+    Ok(name)
+}
+
+/// Inserts `suffix` before the extension (`a.txt` + ` (1)` = `a (1).txt`), shortening the
+/// stem if needed so the suffix survives the length limit.
+pub fn with_suffix(name: &str, suffix: &str) -> String {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= MAX_EXT_BYTES => (stem, Some(ext)),
+        _ => (name, None),
+    };
+    let ext_len = ext.map(|e| e.len() + 1).unwrap_or(0);
+    let stem = truncate_to_bytes(stem, MAX_NAME_BYTES.saturating_sub(suffix.len() + ext_len));
+    match ext {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext),
+        None => format!("{}{}", stem, suffix),
+    }
+}
+
+/// Sanitizes `name` and joins it onto `dir`, guaranteeing the result stays directly inside `dir`.
+pub fn safe_join(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let name = sanitize_file_name(name)?;
+    let path = dir.join(&name);
+    if path.parent() != Some(dir) {
+        return Err("File name escapes the target folder".to_string());
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(name: &str) -> String {
+        sanitize_file_name(name).unwrap_or_else(|e| panic!("{:?} rejected: {}", name, e))
+    }
+
+    fn rejected(name: &str) {
+        assert!(sanitize_file_name(name).is_err(), "{:?} should be rejected", name);
+    }
+
+    #[test]
+    fn keeps_ordinary_names() {
+        assert_eq!(ok("report.pdf"), "report.pdf");
+        assert_eq!(ok(".bashrc"), ".bashrc");
+        assert_eq!(ok("archive.tar.gz"), "archive.tar.gz");
+        assert_eq!(ok("Résumé final.docx"), "Résumé final.docx");
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        rejected("..");
+        rejected("../secret");
+        rejected("../../etc/passwd");
+        rejected("..\\..\\Windows\\System32\\drivers\\etc\\hosts");
+        rejected("foo/../../bar");
+        rejected(" .. ");
+        rejected("..\u{0}/x");
+    }
+
+    #[test]
+    fn rejects_absolute_and_nested_paths() {
+        rejected("/etc/passwd");
+        rejected("\\\\server\\share\\file");
+        rejected("C:\\Windows\\win.ini");
+        rejected("c:evil.txt");
+        rejected("dir/file.txt");
+        rejected("dir\\file.txt");
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        rejected("");
+        rejected(".");
+        rejected("   ");
+        rejected("...");
+        rejected("\u{0}\u{1}\u{7f}");
+    }
+
+    #[test]
+    fn strips_control_and_bidi_characters() {
+        assert_eq!(ok("a\u{0}b\nc\td.txt"), "abcd.txt");
+        assert_eq!(ok("evil\u{202E}gpj.exe"), "evilgpj.exe");
+        assert_eq!(ok("bell\u{7}.txt"), "bell.txt");
+    }
+
+    #[test]
+    fn replaces_characters_invalid_on_windows() {
+        assert_eq!(ok("what?.txt"), "what_.txt");
+        assert_eq!(ok("a<b>c|d*e\"f.txt"), "a_b_c_d_e_f.txt");
+        assert_eq!(ok("stream.txt:hidden"), "stream.txt_hidden");
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(ok("notes.txt. . "), "notes.txt");
+        assert_eq!(ok("  padded.txt  "), "padded.txt");
+    }
+
+    #[test]
+    fn escapes_reserved_device_names() {
+        assert_eq!(ok("CON"), "_CON");
+        assert_eq!(ok("nul.txt"), "_nul.txt");
+        assert_eq!(ok("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(ok("lpt9"), "_lpt9");
+        assert_eq!(ok("CONSOLE.txt"), "CONSOLE.txt");
+        assert_eq!(ok("COM10"), "COM10");
+    }
+
+    #[test]
+    fn truncates_overlong_names_keeping_extension() {
+        let long = format!("{}.txt", "a".repeat(400));
+        let name = ok(&long);
+        assert_eq!(name.len(), MAX_NAME_BYTES);
+        assert!(name.ends_with(".txt"));
+
+        let no_ext = "b".repeat(1000);
+        assert_eq!(ok(&no_ext).len(), MAX_NAME_BYTES);
+    }
+
+    #[test]
+    fn truncation_does_not_leave_trailing_dots_or_spaces() {
+        let long = format!("{}. .{}", "a".repeat(253), "b".repeat(100));
+        assert_eq!(ok(&long), "a".repeat(253));
+        rejected(&format!("{}{}", ".".repeat(300), "b".repeat(100)));
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        let long = format!("{}.png", "é".repeat(300));
+        let name = ok(&long);
+        assert!(name.len() <= MAX_NAME_BYTES);
+        assert!(name.ends_with(".png"));
+        assert!(name.trim_end_matches(".png").chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        let nfd = "Cafe\u{301}.txt";
+        let nfc = "Caf\u{e9}.txt";
+        assert_eq!(ok(nfd), nfc);
+        assert_eq!(ok(nfc), nfc);
+        assert_eq!(ok("Cafe\u{7}\u{301}.txt"), nfc);
+        assert_eq!(ok("Cafe\u{202E}\u{301}.txt"), nfc);
+    }
+
+    #[test]
+    fn suffix_survives_truncation() {
+        assert_eq!(with_suffix("a.txt", " (1)"), "a (1).txt");
+        assert_eq!(with_suffix(".bashrc", " (2)"), ".bashrc (2)");
+        let long = ok(&format!("{}.txt", "a".repeat(400)));
+        let suffixed = with_suffix(&long, " (12)");
+        assert_eq!(ok(&suffixed), suffixed);
+        assert!(suffixed.ends_with(" (12).txt"));
+    }
+
+    #[test]
+    fn safe_join_stays_inside_dir() {
+        let dir = Path::new("/tmp/inbox");
+        assert_eq!(safe_join(dir, "a.txt").unwrap(), dir.join("a.txt"));
+        assert!(safe_join(dir, "../a.txt").is_err());
+        assert!(safe_join(dir, "/a.txt").is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use base64::Engine;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::Write as _;
+use tokio::io::AsyncWriteExt;
+
+use crate::filename;
+
+/// Headroom kept free on the target volume so a transfer never fills the disk completely.
+const DISK_SPACE_MARGIN: u64 = 16 * 1024 * 1024;
+/// Upper bound on the extended attributes carried with one file.
+const MAX_XATTR_BYTES: usize = 64 * 1024;
+
+/// File attributes carried alongside the bytes so scripts stay executable and build
+/// outputs keep their timestamps.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// Modification time in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    /// Unix permission bits. Only the `0o777` range is ever applied.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Extended attributes by name, values base64-encoded.
+    #[serde(default)]
+    pub xattrs: Option<BTreeMap<String, String>>,
+}
+
+/// Attributes that describe the sending machine rather than the file, or that would grant
+/// privileges, are never copied.
+#[cfg(unix)]
+fn is_portable_xattr(name: &str) -> bool {
+    !(name == "com.apple.quarantine"
+        || name.starts_with("security.")
+        || name.starts_with("system.")
+        || name.starts_with("trusted."))
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Option<BTreeMap<String, String>> {
+    let mut attrs = BTreeMap::new();
+    let mut total = 0;
+    for name in xattr::list(path).ok()? {
+        let name = name.to_string_lossy().into_owned();
+        if !is_portable_xattr(&name) {
+            continue;
+        }
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            total += name.len() + value.len();
+            if total > MAX_XATTR_BYTES {
+                break;
+            }
+            attrs.insert(name, base64::engine::general_purpose::STANDARD.encode(value));
+        }
+    }
+    (!attrs.is_empty()).then_some(attrs)
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> Option<BTreeMap<String, String>> {
+    None
+}
+
+/// Collects the metadata to send with the file at `path`.
+pub fn read_meta(path: &Path, include_xattrs: bool) -> FileMeta {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return FileMeta::default();
+    };
+    let mtime = FileTime::from_last_modification_time(&metadata);
+
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o777)
+    };
+    #[cfg(not(unix))]
+    let mode = Some(if metadata.permissions().readonly() { 0o444 } else { 0o644 });
+
+    FileMeta {
+        mtime: Some(mtime.unix_seconds() * 1000 + i64::from(mtime.nanoseconds() / 1_000_000)),
+        mode,
+        xattrs: if include_xattrs { read_xattrs(path) } else { None },
+    }
+}
+
+/// Restores whatever of `meta` the platform supports. Failures are ignored: the file
+/// contents are what matter, attributes are best effort.
+pub fn apply_meta(path: &Path, meta: &FileMeta) {
+    #[cfg(unix)]
+    if let Some(attrs) = &meta.xattrs {
+        let mut total = 0;
+        for (name, value) in attrs {
+            let Ok(value) = base64::engine::general_purpose::STANDARD.decode(value) else {
+                continue;
+            };
+            total += name.len() + value.len();
+            if total > MAX_XATTR_BYTES {
+                break;
+            }
+            if is_portable_xattr(name) {
+                let _ = xattr::set(path, name, &value);
+            }
+        }
+    }
+
+    if let Some(mode) = meta.mode {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Never restore setuid/setgid/sticky bits from a remote peer.
+            let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777));
+        }
+        #[cfg(not(unix))]
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let mut permissions = metadata.permissions();
+            permissions.set_readonly(mode & 0o222 == 0);
+            let _ = std::fs::set_permissions(path, permissions);
+        }
+    }
+
+    // Last, since writing xattrs can bump the modification time on some filesystems.
+    if let Some(ms) = meta.mtime {
+        let mtime = FileTime::from_unix_time(ms.div_euclid(1000), (ms.rem_euclid(1000) * 1_000_000) as u32);
+        let _ = filetime::set_file_mtime(path, mtime);
+    }
+}
+
+/// Fails early if the volume holding `dir` cannot fit `size` more bytes.
+pub fn check_free_space(dir: &Path, size: u64) -> Result<(), String> {
+    // `dir` may not exist yet; measure the volume it will be created on.
+    let existing = dir.ancestors().find(|p| p.exists()).unwrap_or(dir);
+    let available = fs4::available_space(existing).map_err(|e| e.to_string())?;
+    if available < size.saturating_add(DISK_SPACE_MARGIN) {
+        return Err(format!(
+            "Not enough disk space: {} bytes needed, {} available",
+            size, available
+        ));
+    }
+    Ok(())
+}
+
+/// Hidden `.part` file next to `path`, so the final rename never crosses filesystems.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let temp_name = format!(".{}.{}-{}.part", name, std::process::id(), nanos);
+    path.with_file_name(filename::with_suffix(&temp_name, ""))
+}
+
+async fn write_and_sync(temp: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut file = tokio::fs::File::create(temp)
+        .await
+        .map_err(|e| e.to_string())?;
+    file.write_all(bytes).await.map_err(|e| e.to_string())?;
+    file.sync_all().await.map_err(|e| e.to_string())?;
+
+    let written = file.metadata().await.map_err(|e| e.to_string())?.len();
+    if written != bytes.len() as u64 {
+        return Err(format!(
+            "Short write: {} of {} bytes",
+            written,
+            bytes.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Persists renames and new links in `dir`; not possible (or needed) on Windows.
+fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Ok(dir) = std::fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
+
+/// Moves a fully written and synced temp file into place and persists the rename.
+pub fn commit_temp(temp: &Path, path: &Path) -> Result<(), String> {
+    if let Err(e) = std::fs::rename(temp, path) {
+        let _ = std::fs::remove_file(temp);
+        return Err(e.to_string());
+    }
+    if let Some(dir) = path.parent() {
+        sync_dir(dir);
+    }
+    Ok(())
+}
+
+/// Moves `temp` to `path` only if nothing is there yet, failing with `AlreadyExists`
+/// otherwise. Leaves `temp` in place on failure.
+fn claim(temp: &Path, path: &Path) -> std::io::Result<()> {
+    match std::fs::hard_link(temp, path) {
+        Ok(()) => {
+            let _ = std::fs::remove_file(temp);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(e),
+        // Filesystems without hard links (FAT, some network shares): reserve the name with
+        // an empty file, then replace it.
+        Err(_) => {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)?;
+            std::fs::rename(temp, path).inspect_err(|_| {
+                let _ = std::fs::remove_file(path);
+            })
+        }
+    }
+}
+
+/// Moves a fully written and synced temp file to `dir/name`, or `dir/name (1).ext`,
+/// `dir/name (2).ext`, ... if taken, and returns the final path. The name is claimed
+/// atomically, so two files received under the same name never overwrite each other.
+/// Fails if `name` is not a safe single path component.
+pub fn commit_temp_unique(temp: &Path, dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let committed = filename::sanitize_file_name(name).and_then(|name| {
+        for n in 0u32.. {
+            let file_name = match n {
+                0 => name.clone(),
+                n => filename::with_suffix(&name, &format!(" ({})", n)),
+            };
+            let path = filename::safe_join(dir, &file_name)?;
+            match claim(temp, &path) {
+                Ok(()) => return Ok(path),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Err("No free file name".to_string())
+    });
+    match committed {
+        Ok(path) => {
+            sync_dir(dir);
+            Ok(path)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(temp);
+            Err(e)
+        }
+    }
+}
+
+/// Writes `bytes` to `path` via a synced temp file in the same directory, so `path` either
+/// keeps its old contents or holds the complete new file, never a partial one. Blocks, so
+/// meant for small state files.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let temp = temp_path_for(path);
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.to_string());
+    }
+    commit_temp(&temp, path)
+}
+
+/// Writes `bytes` to a synced temp file next to `path`, removing it again on failure.
+async fn write_temp(path: &Path, bytes: &[u8], meta: Option<&FileMeta>) -> Result<PathBuf, String> {
+    let dir = path.parent().ok_or("Invalid path")?;
+    check_free_space(dir, bytes.len() as u64)?;
+
+    let temp = temp_path_for(path);
+    if let Err(e) = write_and_sync(&temp, bytes).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    if let Some(meta) = meta {
+        apply_meta(&temp, meta);
+    }
+    Ok(temp)
+}
+
+/// Copies `src` to `path` via a temp file in the same directory, so `path` either keeps
+/// its old contents or holds the complete new file, never a partial one.
+pub async fn copy_atomic(src: &Path, path: &Path, meta: Option<&FileMeta>) -> Result<(), String> {
+    let dir = path.parent().ok_or("Invalid path")?;
+    let size = tokio::fs::metadata(src)
+        .await
+        .map_err(|e| e.to_string())?
+        .len();
+    check_free_space(dir, size)?;
+
+    let temp = temp_path_for(path);
+    let copied = async {
+        tokio::fs::copy(src, &temp)
+            .await
+            .map_err(|e| e.to_string())?;
+        // Opened for writing: Windows refuses to flush a read-only handle.
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&temp)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.sync_all().await.map_err(|e| e.to_string())?;
+        let written = file.metadata().await.map_err(|e| e.to_string())?.len();
+        if written != size {
+            return Err(format!("Short write: {} of {} bytes", written, size));
+        }
+        Ok(())
+    };
+    if let Err(e) = copied.await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    if let Some(meta) = meta {
+        apply_meta(&temp, meta);
+    }
+    commit_temp(&temp, path)
+}
+
+/// Writes a received file into `dir` without prompting, returning the final path.
+pub async fn save_to_dir(
+    dir: &Path,
+    name: &str,
+    bytes: &[u8],
+    meta: Option<&FileMeta>,
+) -> Result<PathBuf, String> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| e.to_string())?;
+    let temp = write_temp(&filename::safe_join(dir, name)?, bytes, meta).await?;
+    commit_temp_unique(&temp, dir, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn temp_in(dir: &Path, contents: &str) -> PathBuf {
+        let temp = temp_path_for(&dir.join(contents));
+        std::fs::write(&temp, contents).unwrap();
+        temp
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_whole_file() {
+        let dir = TempDir::new("write");
+        let path = dir.0.join("state.json");
+        write_atomic(&path, b"old contents").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(dir.names(), ["state.json"]);
+    }
+
+    #[test]
+    fn failed_write_leaves_the_target_untouched_and_no_temp_file() {
+        let dir = TempDir::new("write-fails");
+        // Renaming a file over a non-empty folder fails after the temp file is written.
+        let path = dir.0.join("taken");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("inside"), b"kept").unwrap();
+
+        assert!(write_atomic(&path, b"new").is_err());
+        assert_eq!(dir.names(), ["taken"]);
+        assert_eq!(std::fs::read(path.join("inside")).unwrap(), b"kept");
+    }
+
+    #[tokio::test]
+    async fn copy_atomic_copies_and_applies_meta() {
+        let dir = TempDir::new("copy");
+        let src = dir.0.join("src.txt");
+        std::fs::write(&src, b"contents").unwrap();
+        let meta = FileMeta {
+            mtime: Some(1_600_000_000_000),
+            ..FileMeta::default()
+        };
+        let path = dir.0.join("copy.txt");
+        copy_atomic(&src, &path, Some(&meta)).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"contents");
+        let mtime = FileTime::from_last_modification_time(&std::fs::metadata(&path).unwrap());
+        assert_eq!(mtime.unix_seconds(), 1_600_000_000);
+        assert_eq!(dir.names(), ["copy.txt", "src.txt"]);
+    }
+
+    #[tokio::test]
+    async fn failed_copy_leaves_the_target_untouched_and_no_temp_file() {
+        let dir = TempDir::new("copy-fails");
+        let path = dir.0.join("target.txt");
+        std::fs::write(&path, b"kept").unwrap();
+
+        assert!(copy_atomic(&dir.0.join("missing"), &path, None).await.is_err());
+        let taken = dir.0.join("taken");
+        std::fs::create_dir(&taken).unwrap();
+        std::fs::write(taken.join("inside"), b"kept").unwrap();
+        assert!(copy_atomic(&path, &taken, None).await.is_err());
+
+        assert_eq!(dir.names(), ["taken", "target.txt"]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn commit_temp_removes_the_temp_file_on_failure() {
+        let dir = TempDir::new("commit-fails");
+        let temp = temp_in(&dir.0, "data");
+        assert!(commit_temp(&temp, &dir.0.join("missing").join("file")).is_err());
+        assert!(dir.names().is_empty());
+    }
+
+    #[test]
+    fn colliding_names_are_numbered_before_the_extension() {
+        let dir = TempDir::new("collisions");
+        for contents in ["first", "second", "third"] {
+            commit_temp_unique(&temp_in(&dir.0, contents), &dir.0, "report.pdf").unwrap();
+        }
+        assert_eq!(dir.names(), ["report (1).pdf", "report (2).pdf", "report.pdf"]);
+        assert_eq!(std::fs::read_to_string(dir.0.join("report.pdf")).unwrap(), "first");
+        assert_eq!(std::fs::read_to_string(dir.0.join("report (2).pdf")).unwrap(), "third");
+    }
+
+    #[test]
+    fn unsafe_names_are_refused_and_the_temp_file_removed() {
+        let dir = TempDir::new("unsafe");
+        let temp = temp_in(&dir.0, "data");
+        assert!(commit_temp_unique(&temp, &dir.0, "../escape").is_err());
+        assert!(dir.names().is_empty());
+    }
+
+    #[test]
+    fn concurrent_commits_of_one_name_keep_every_file() {
+        let dir = TempDir::new("race");
+        let temps: Vec<PathBuf> = (0..16).map(|i| temp_in(&dir.0, &format!("copy {i}"))).collect();
+        let barrier = std::sync::Barrier::new(temps.len());
+        std::thread::scope(|scope| {
+            for temp in &temps {
+                let (dir, barrier) = (&dir.0, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+                    commit_temp_unique(temp, dir, "same.txt").unwrap();
+                });
+            }
+        });
+
+        let mut contents: Vec<String> = dir
+            .names()
+            .iter()
+            .map(|name| std::fs::read_to_string(dir.0.join(name)).unwrap())
+            .collect();
+        contents.sort();
+        let mut expected: Vec<String> = (0..16).map(|i| format!("copy {i}")).collect();
+        expected.sort();
+        assert_eq!(contents, expected);
+    }
+}
@@ -0,0 +1,287 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64::Engine as _;
+
+use crate::compression;
+use crate::filename::{self, MAX_NAME_BYTES, RESERVED_NAMES};
+use crate::sync;
+use crate::transfer::Incoming;
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A text frame from a peer. Whatever `decode` accepts must serialize again, and decoding
+/// that must give the same message.
+pub fn ws_message(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Some(message) = sync::decode(text) else {
+        return;
+    };
+    let encoded = serde_json::to_string(&message).expect("decoded message serializes");
+    let again = sync::decode(&encoded).expect("serialized message decodes");
+    assert_eq!(serde_json::to_string(&again).unwrap(), encoded);
+}
+
+/// Reads `data` front to back, treating missing bytes as zero.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn byte(&mut self) -> u8 {
+        let (first, rest) = self.0.split_first().unwrap_or((&0, &[]));
+        self.0 = rest;
+        *first
+    }
+
+    fn bytes(&mut self, len: usize) -> &[u8] {
+        let (taken, rest) = self.0.split_at(len.min(self.0.len()));
+        self.0 = rest;
+        taken
+    }
+}
+
+/// A folder of its own for one run, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Scratch {
+        Scratch(std::env::temp_dir().join(format!(
+            "filetwin-fuzz-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+
+    fn entries(&self) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(&self.0)
+            .map(|dir| dir.flatten().map(|e| e.path()).collect())
+            .unwrap_or_default();
+        entries.sort();
+        entries
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A transfer as a peer might send it: the first byte says whether compression was
+/// negotiated, the next two give the announced size, and the rest are chunks of up to 255
+/// bytes, each sent raw, compressed, as garbage flagged compressed, or as broken base64.
+/// Reassembly must end in exactly the bytes sent, or fail and leave nothing behind.
+pub fn chunks(data: &[u8]) {
+    let mut input = Input(data);
+    let negotiated = input.byte() & 1 == 1;
+    let size = u16::from_le_bytes([input.byte(), input.byte()]) as u64;
+    let scratch = Scratch::new();
+    let compression = negotiated.then(|| compression::ZSTD.to_string());
+    let mut transfer = Incoming::start(&scratch.0, "fuzz.bin".to_string(), size, None, compression)
+        .expect("transfer starts");
+    let mut expected = Vec::new();
+
+    while !input.0.is_empty() {
+        let op = input.byte();
+        let len = input.byte() as usize;
+        let chunk = input.bytes(len).to_vec();
+        let remaining = (size - expected.len() as u64) as usize;
+        let (written, sent) = match op % 4 {
+            0 => (transfer.write_chunk(&chunk, false), Some(chunk)),
+            1 => {
+                let packed = compression::compress(&chunk).unwrap();
+                let sent = negotiated.then_some(chunk);
+                (transfer.write_chunk(&packed, true), sent)
+            }
+            2 => {
+                // Rarely valid zstd; whatever it unpacks to within bounds is what must land.
+                let sent = if negotiated {
+                    compression::decompress(&chunk, remaining).ok()
+                } else {
+                    None
+                };
+                (transfer.write_chunk(&chunk, true), sent)
+            }
+            _ => {
+                let text = String::from_utf8_lossy(&chunk);
+                let sent = base64::engine::general_purpose::STANDARD
+                    .decode(text.as_bytes())
+                    .ok();
+                (transfer.write_encoded(&text, false), sent)
+            }
+        };
+        match sent.filter(|sent| sent.len() <= remaining) {
+            Some(sent) => {
+                assert!(written.is_ok(), "chunk within bounds rejected: {written:?}");
+                expected.extend_from_slice(&sent);
+            }
+            None => {
+                assert!(written.is_err(), "bad chunk accepted");
+                // The receiver aborts the transfer, which drops it.
+                drop(transfer);
+                assert!(scratch.entries().is_empty(), "aborted transfer left files");
+                return;
+            }
+        }
+    }
+
+    let complete = expected.len() as u64 == size;
+    match transfer.finish() {
+        Ok(path) => {
+            assert!(complete, "finished short of the announced size");
+            assert_eq!(std::fs::read(&path).unwrap(), expected);
+            assert_eq!(scratch.entries(), vec![path]);
+        }
+        Err(_) => {
+            assert!(!complete, "complete transfer failed to finish");
+            assert!(scratch.entries().is_empty(), "failed transfer left files");
+        }
+    }
+}
+
+/// A name as a peer might send it. Whatever survives sanitizing must be one harmless path
+/// component on every platform, stay that way when sanitized again or given a suffix, and
+/// join onto a folder without leaving it.
+pub fn file_name(data: &[u8]) {
+    let name = String::from_utf8_lossy(data);
+    let Ok(clean) = filename::sanitize_file_name(&name) else {
+        return;
+    };
+
+    assert!(
+        !clean.is_empty() && clean.len() <= MAX_NAME_BYTES,
+        "{clean:?}"
+    );
+    assert!(clean != "." && clean != "..", "{clean:?}");
+    assert!(
+        !clean.ends_with(['.', ' ']),
+        "{clean:?} has a trailing dot or space"
+    );
+    assert!(
+        !clean.chars().any(|c| c.is_control()
+            || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*')
+            || matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')),
+        "{clean:?} has a forbidden character"
+    );
+    let stem = clean.split('.').next().unwrap_or_default().trim_end();
+    assert!(
+        !RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)),
+        "{clean:?} is a reserved device name"
+    );
+    assert_eq!(
+        filename::sanitize_file_name(&clean).as_deref(),
+        Ok(clean.as_str())
+    );
+
+    let dir = Path::new("received");
+    let joined = filename::safe_join(dir, &name).expect("sanitized name joins");
+    assert_eq!(joined.parent(), Some(dir));
+
+    let suffixed = filename::with_suffix(&clean, " (1)");
+    assert_eq!(
+        filename::sanitize_file_name(&suffixed).as_deref(),
+        Ok(suffixed.as_str())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Runs `target` on every input checked in under `fuzz/corpus/<name>`.
+    fn replay(name: &str, target: fn(&[u8])) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(name);
+        let mut count = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            let outcome = std::panic::catch_unwind(|| target(&data));
+            assert!(outcome.is_ok(), "{} fails", path.display());
+            count += 1;
+        }
+        assert!(count > 0, "no corpus in {}", dir.display());
+    }
+
+    /// Corpus inputs with bytes flipped, inserted and removed, which is where most bugs are
+    /// found: near valid input.
+    fn mutated(name: &str) -> impl Strategy<Value = Vec<u8>> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(name);
+        let seeds: Vec<Vec<u8>> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        let edits =
+            prop::collection::vec((any::<prop::sample::Index>(), any::<u8>(), 0..3u8), 0..8);
+        (prop::sample::select(seeds), edits).prop_map(|(mut data, edits)| {
+            for (at, byte, kind) in edits {
+                let at = at.index(data.len() + 1);
+                match kind {
+                    0 if at < data.len() => data[at] = byte,
+                    1 if at < data.len() => {
+                        data.remove(at);
+                    }
+                    _ => data.insert(at, byte),
+                }
+            }
+            data
+        })
+    }
+
+    #[test]
+    fn ws_message_corpus() {
+        replay("ws_message", ws_message);
+    }
+
+    #[test]
+    fn chunks_corpus() {
+        replay("chunks", chunks);
+    }
+
+    #[test]
+    fn file_name_corpus() {
+        replay("file_name", file_name);
+    }
+
+    proptest! {
+        #[test]
+        fn ws_message_mutated(data in mutated("ws_message")) {
+            ws_message(&data);
+        }
+
+        #[test]
+        fn ws_message_any_json(data in "\\{\"type\":\"[A-Za-z]{0,12}\"(,\"[a-z_]{1,10}\":(null|true|-?[0-9]{1,20}|\"[^\"\\\\]{0,8}\"|\\[\\]|\\{\\}))*\\}") {
+            ws_message(data.as_bytes());
+        }
+
+        #[test]
+        fn chunks_mutated(data in mutated("chunks")) {
+            chunks(&data);
+        }
+
+        #[test]
+        fn chunks_any(data in prop::collection::vec(any::<u8>(), 0..600)) {
+            chunks(&data);
+        }
+
+        #[test]
+        fn file_name_mutated(data in mutated("file_name")) {
+            file_name(&data);
+        }
+
+        #[test]
+        fn file_name_any(name in "\\PC{0,300}") {
+            file_name(name.as_bytes());
+        }
+
+        #[test]
+        fn file_name_long_with_extension(stem in "[^/\\\\]{200,300}", ext in "[a-z.]{0,20}") {
+            file_name(format!("{stem}.{ext}").as_bytes());
+        }
+    }
+}
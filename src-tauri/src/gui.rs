@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, Manager, WebviewUrl, WebviewWindowBuilder, WindowEvent};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_dialog::{DialogExt, FilePath};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_opener::OpenerExt;
+
+use crate::engine::{Desktop, EventSink};
+
+/// Label of the window that shows the peer's screen.
+const VIEWER_WINDOW: &str = "mirror";
+
+/// Forwards engine events to the webview. Screen frames go to the viewer window, which
+/// is opened when a share starts.
+pub struct TauriEvents {
+    app: AppHandle,
+    /// The user closed the viewer window; keep it closed until the peer starts a new share.
+    viewer_closed: Arc<AtomicBool>,
+}
+
+impl TauriEvents {
+    pub fn new(app: AppHandle) -> Self {
+        TauriEvents {
+            app,
+            viewer_closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn open_viewer(&self, peer: &str) {
+        if self.viewer_closed.load(Ordering::SeqCst)
+            || self.app.get_webview_window(VIEWER_WINDOW).is_some()
+        {
+            return;
+        }
+        let url = WebviewUrl::App("index.html#mirror".into());
+        match WebviewWindowBuilder::new(&self.app, VIEWER_WINDOW, url)
+            .title(format!("Screen of {peer}"))
+            .inner_size(1024.0, 640.0)
+            .build()
+        {
+            Ok(window) => {
+                let closed = self.viewer_closed.clone();
+                window.on_window_event(move |event| {
+                    if matches!(event, WindowEvent::Destroyed) {
+                        closed.store(true, Ordering::SeqCst);
+                    }
+                })
+            }
+            Err(_) => self.viewer_closed.store(true, Ordering::SeqCst),
+        }
+    }
+}
+
+impl EventSink for TauriEvents {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        match event {
+            "mirror_frame" => {
+                if payload["seq"] == 1 {
+                    self.viewer_closed.store(false, Ordering::SeqCst);
+                }
+                self.open_viewer(payload["peer"].as_str().unwrap_or_default());
+                let _ = self.app.emit_to(VIEWER_WINDOW, event, payload);
+            }
+            "mirror_stopped" => {
+                self.viewer_closed.store(false, Ordering::SeqCst);
+                let _ = self.app.emit_to(VIEWER_WINDOW, event, payload);
+            }
+            _ => {
+                let _ = self.app.emit(event, payload);
+            }
+        }
+    }
+}
+
+pub struct TauriDesktop {
+    app: AppHandle,
+}
+
+impl TauriDesktop {
+    pub fn new(app: AppHandle) -> Self {
+        TauriDesktop { app }
+    }
+}
+
+impl Desktop for TauriDesktop {
+    fn open_url(&self, url: &str) -> Result<(), String> {
+        self.app
+            .opener()
+            .open_url(url, None::<&str>)
+            .map_err(|e| e.to_string())
+    }
+
+    fn open_path(&self, path: &Path) -> Result<(), String> {
+        self.app
+            .opener()
+            .open_path(path.to_string_lossy(), None::<&str>)
+            .map_err(|e| e.to_string())
+    }
+
+    fn notify(&self, title: &str, body: &str) -> Result<(), String> {
+        self.app
+            .notification()
+            .builder()
+            .title(title)
+            .body(body)
+            .show()
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_text(&self, text: &str) -> Result<(), String> {
+        self.app
+            .clipboard()
+            .write_text(text)
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn local_path(path: Option<FilePath>) -> Result<Option<PathBuf>, String> {
+    match path {
+        Some(FilePath::Path(p)) => Ok(Some(p)),
+        Some(_) => Err("Invalid path".to_string()),
+        None => Ok(None),
+    }
+}
+
+pub fn pick_file(app: &AppHandle) -> Result<Option<PathBuf>, String> {
+    local_path(app.dialog().file().blocking_pick_file())
+}
+
+pub fn pick_folder(app: &AppHandle) -> Result<Option<PathBuf>, String> {
+    local_path(app.dialog().file().blocking_pick_folder())
+}
+
+/// Asks where to save a file, suggesting `name`.
+pub fn pick_save_path(app: &AppHandle, name: &str) -> Result<Option<PathBuf>, String> {
+    local_path(app.dialog().file().set_file_name(name).blocking_save_file())
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::clipboard::ClipContent;
+use crate::engine::Engine;
+use crate::files;
+
+const HISTORY_FILE: &str = "clipboard_history.json";
+/// Unpinned entries kept; pinned entries never age out.
+const MAX_ENTRIES: usize = 200;
+/// Total size of the unpinned entries kept, so a run of large images cannot grow the
+/// history without bound.
+const MAX_BYTES: usize = 32 * 1024 * 1024;
+/// Larger items are kept without the formats that do not fit, like a truncated clipboard
+/// item.
+const MAX_ENTRY_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub direction: Direction,
+    /// Peer the item was sent to or received from.
+    pub peer: Option<String>,
+    /// Unix time in milliseconds.
+    pub timestamp: i64,
+    #[serde(default)]
+    pub pinned: bool,
+    pub content: ClipContent,
+    /// Local copies of the files in the item, so it can be re-sent or re-applied later.
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+}
+
+impl HistoryEntry {
+    fn matches(&self, query: &str) -> bool {
+        self.content.text.to_lowercase().contains(query)
+            || self.peer.as_deref().is_some_and(|p| p.to_lowercase().contains(query))
+            || self.content.format_names().contains(&query)
+    }
+}
+
+/// Clipboard items, oldest first.
+#[derive(Default, Serialize, Deserialize)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    /// A save is queued and will write the latest entries.
+    #[serde(skip)]
+    saving: bool,
+}
+
+impl History {
+    fn push(&mut self, mut entry: HistoryEntry) {
+        entry.content.truncate(MAX_ENTRY_BYTES);
+        self.entries.push(entry);
+        let unpinned = self.entries.iter().filter(|e| !e.pinned);
+        let mut count = unpinned.clone().count();
+        let mut bytes: usize = unpinned.map(|e| e.content.size()).sum();
+        self.entries.retain(|e| {
+            if e.pinned || (count <= MAX_ENTRIES && bytes <= MAX_BYTES) {
+                return true;
+            }
+            count -= 1;
+            bytes -= e.content.size();
+            false
+        });
+    }
+}
+
+fn history_path(engine: &Engine) -> PathBuf {
+    engine.paths().data_dir.join(HISTORY_FILE)
+}
+
+/// Loads history from the app data dir, starting empty if missing or unreadable.
+pub fn load(engine: &Engine) -> History {
+    std::fs::read(history_path(engine))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the current history. Runs on a blocking thread.
+fn save(engine: &Engine) -> Result<(), String> {
+    let path = history_path(engine);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = {
+        let mut history = engine.state().history.lock().map_err(|_| "lock")?;
+        history.saving = false;
+        serde_json::to_vec(&*history).map_err(|e| e.to_string())?
+    };
+    files::write_atomic(&path, &json)
+}
+
+/// Applies `change` to the history, queues a save and tells the UI to refresh. Changes
+/// made while a save is queued are written by that save.
+fn update<T>(engine: &Engine, change: impl FnOnce(&mut History) -> T) -> Result<T, String> {
+    let state = engine.state();
+    let mut history = state.history.lock().map_err(|_| "lock")?;
+    let result = change(&mut history);
+    if !history.saving {
+        history.saving = true;
+        let saving = engine.clone();
+        engine.spawn_blocking(move || save(&saving));
+    }
+    drop(history);
+    engine.emit("clipboard_history", ());
+    Ok(result)
+}
+
+pub fn record(
+    engine: &Engine,
+    direction: Direction,
+    peer: Option<String>,
+    content: &ClipContent,
+    files: &[PathBuf],
+) {
+    let entry = HistoryEntry {
+        id: crate::transfer::new_id(),
+        direction,
+        peer,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        pinned: false,
+        content: content.clone(),
+        files: files.to_vec(),
+    };
+    let _ = update(engine, |history| history.push(entry));
+}
+
+pub fn entry(engine: &Engine, id: &str) -> Result<HistoryEntry, String> {
+    let state = engine.state();
+    let history = state.history.lock().map_err(|_| "lock")?;
+    history
+        .entries
+        .iter()
+        .find(|e| e.id == id)
+        .cloned()
+        .ok_or_else(|| "No such history entry".to_string())
+}
+
+/// Newest first, optionally filtered by a case-insensitive search on text, peer or format.
+pub fn list_history(query: Option<String>, engine: Engine) -> Result<Vec<HistoryEntry>, String> {
+    let state = engine.state();
+    let history = state.history.lock().map_err(|_| "lock")?;
+    let query = query.map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+    Ok(history
+        .entries
+        .iter()
+        .rev()
+        .filter(|e| query.as_deref().is_none_or(|q| e.matches(q)))
+        .cloned()
+        .collect())
+}
+
+pub fn pin_history(id: String, pinned: bool, engine: Engine) -> Result<(), String> {
+    update(&engine, |history| {
+        let entry = history.entries.iter_mut().find(|e| e.id == id);
+        entry.map(|e| e.pinned = pinned).ok_or("No such history entry")
+    })?
+    .map_err(|e| e.to_string())
+}
+
+pub fn delete_history(id: String, engine: Engine) -> Result<(), String> {
+    update(&engine, |history| history.entries.retain(|e| e.id != id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestEngine};
+    use std::time::Instant;
+
+    fn text(text: &str) -> ClipContent {
+        ClipContent {
+            text: text.to_string(),
+            formats: Vec::new(),
+        }
+    }
+
+    fn texts(engine: &Engine, query: Option<&str>) -> Vec<String> {
+        list_history(query.map(str::to_string), engine.clone())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.content.text)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_unpinned_entries_past_the_limit() {
+        let test = TestEngine::new("history-evict");
+        let engine = &test.engine;
+        record(engine, Direction::Sent, None, &text("pinned"), &[]);
+        let pinned = list_history(None, engine.clone()).unwrap()[0].id.clone();
+        pin_history(pinned, true, engine.clone()).unwrap();
+        for i in 0..MAX_ENTRIES + 5 {
+            record(engine, Direction::Received, Some("Peer".into()), &text(&format!("item {i}")), &[]);
+        }
+
+        let all = texts(engine, None);
+        assert_eq!(all.len(), MAX_ENTRIES + 1);
+        assert_eq!(all.first().unwrap(), &format!("item {}", MAX_ENTRIES + 4));
+        assert_eq!(all[MAX_ENTRIES - 1], "item 5");
+        assert_eq!(all.last().unwrap(), "pinned");
+    }
+
+    #[tokio::test]
+    async fn unpinning_lets_an_entry_age_out() {
+        let test = TestEngine::new("history-unpin");
+        let engine = &test.engine;
+        record(engine, Direction::Sent, None, &text("kept a while"), &[]);
+        let id = list_history(None, engine.clone()).unwrap()[0].id.clone();
+        pin_history(id.clone(), true, engine.clone()).unwrap();
+        for i in 0..MAX_ENTRIES {
+            record(engine, Direction::Sent, None, &text(&format!("item {i}")), &[]);
+        }
+        pin_history(id, false, engine.clone()).unwrap();
+        record(engine, Direction::Sent, None, &text("one more"), &[]);
+
+        let all = texts(engine, None);
+        assert_eq!(all.len(), MAX_ENTRIES);
+        assert!(!all.contains(&"kept a while".to_string()));
+    }
+
+    #[tokio::test]
+    async fn searches_text_and_peer_and_persists() {
+        let test = TestEngine::new("history-search");
+        let engine = &test.engine;
+        record(engine, Direction::Received, Some("Studio".into()), &text("Hello World"), &[]);
+        record(engine, Direction::Sent, Some("Laptop".into()), &text("other"), &[]);
+
+        assert_eq!(texts(engine, Some(" world ")), ["Hello World"]);
+        assert_eq!(texts(engine, Some("laptop")), ["other"]);
+        assert_eq!(texts(engine, Some("")), ["other", "Hello World"]);
+        assert!(pin_history("missing".into(), true, engine.clone()).is_err());
+
+        let reloaded = saved(engine, |h| h.entries.len() == 2).await;
+        assert_eq!(reloaded.entries.len(), 2);
+    }
+
+    /// The saved history, once `done` accepts it. Saves run in the background.
+    async fn saved(engine: &Engine, done: impl Fn(&History) -> bool) -> History {
+        let deadline = Instant::now() + testing::EVENT_TIMEOUT;
+        loop {
+            let history = load(engine);
+            if done(&history) || Instant::now() > deadline {
+                return history;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    fn unsaved(text: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: crate::transfer::new_id(),
+            direction: Direction::Sent,
+            peer: None,
+            timestamp: 0,
+            pinned: false,
+            content: ClipContent {
+                text: text.to_string(),
+                formats: Vec::new(),
+            },
+            files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_large_items_within_the_byte_budget() {
+        let mut history = History::default();
+        let megabyte = "x".repeat(1024 * 1024);
+        for i in 0..40 {
+            history.push(unsaved(&format!("{i} {megabyte}")));
+        }
+        let sizes: Vec<usize> = history.entries.iter().map(|e| e.content.size()).collect();
+        assert!(sizes.len() < 40);
+        assert!(sizes.iter().sum::<usize>() <= MAX_BYTES);
+        assert!(history.entries.last().unwrap().content.text.starts_with("39 "));
+
+        history.push(unsaved(&"y".repeat(2 * MAX_ENTRY_BYTES)));
+        assert_eq!(history.entries.last().unwrap().content.size(), MAX_ENTRY_BYTES);
+    }
+}
@@ -1,94 +1,374 @@
+mod actions;
+mod annotate;
+mod chat;
+pub mod cli;
+mod clipboard;
+mod compression;
+mod control;
+pub mod daemon;
+mod engine;
+mod filename;
+mod files;
+/// Checks run by the fuzz targets in `fuzz/` and by the property tests.
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+#[cfg(feature = "gui")]
+mod gui;
+mod history;
+mod links;
+mod mirror;
+mod outbox;
+mod screenshot;
+mod sensitive;
+mod settings;
 mod sync;
+/// Engines for tests, each in its own temporary folder, connected over loopback.
+#[cfg(test)]
+mod testing;
+mod transfer;
 
-use tauri::Manager;
+#[cfg(feature = "gui")]
+mod commands {
+    use tauri::{AppHandle, State};
 
-#[tauri::command]
-fn get_host_name() -> String {
-    hostname::get()
-        .map(|h| h.to_string_lossy().into_owned())
-        .unwrap_or_else(|_| "Unknown".to_string())
-}
+    use crate::engine::Engine;
+    use crate::{actions, annotate, chat, gui, history, links, mirror, outbox, screenshot, settings, sync};
 
-#[tauri::command]
-async fn start_host(app: tauri::AppHandle) -> Result<(), String> {
-    sync::start_host(app).await
-}
+    #[tauri::command]
+    pub fn get_host_name(engine: State<'_, Engine>) -> String {
+        engine.name().to_string()
+    }
 
-#[tauri::command]
-async fn stop_host(app: tauri::AppHandle) -> Result<(), String> {
-    sync::stop_host(app).await
-}
+    #[tauri::command]
+    pub async fn start_host(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::start_host(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn start_browse(app: tauri::AppHandle) -> Result<(), String> {
-    sync::start_browse(app).await
-}
+    #[tauri::command]
+    pub async fn stop_host(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::stop_host(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn stop_browse(app: tauri::AppHandle) -> Result<(), String> {
-    sync::stop_browse(app).await
-}
+    #[tauri::command]
+    pub async fn start_browse(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::start_browse(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn connect_to(
-    host: String,
-    port: u16,
-    app: tauri::AppHandle,
-) -> Result<(), String> {
-    sync::connect_to(host, port, app).await
-}
+    #[tauri::command]
+    pub async fn stop_browse(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::stop_browse(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn disconnect(app: tauri::AppHandle) -> Result<(), String> {
-    sync::disconnect(app).await
-}
+    #[tauri::command]
+    pub async fn connect_to(
+        host: String,
+        port: u16,
+        engine: State<'_, Engine>,
+    ) -> Result<(), String> {
+        sync::connect_to(host, port, engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn send_clipboard(text: String, app: tauri::AppHandle) -> Result<(), String> {
-    sync::send_clipboard(text, app).await
-}
+    #[tauri::command]
+    pub async fn disconnect(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::disconnect(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn send_bring_to_front(app: tauri::AppHandle) -> Result<(), String> {
-    sync::send_bring_to_front(app).await
-}
+    #[tauri::command]
+    pub async fn send_clipboard(text: Option<String>, engine: State<'_, Engine>) -> Result<(), String> {
+        sync::send_clipboard(text, engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn pick_and_send_file(app: tauri::AppHandle) -> Result<(), String> {
-    sync::pick_and_send_file(app).await
-}
+    #[tauri::command]
+    pub async fn paste_from_remote(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::paste_from_remote(engine.inner().clone()).await
+    }
 
-#[tauri::command]
-async fn capture_screenshot_and_send(app: tauri::AppHandle) -> Result<(), String> {
-    sync::capture_screenshot_and_send(app).await
-}
+    #[tauri::command]
+    pub async fn send_bring_to_front(engine: State<'_, Engine>) -> Result<(), String> {
+        sync::send_bring_to_front(engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn send_remote_action(action: actions::RemoteAction, engine: State<'_, Engine>) -> Result<String, String> {
+        actions::send_remote_action(action, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn answer_remote_action(
+        id: String,
+        allow: bool,
+        remember: bool,
+        engine: State<'_, Engine>,
+    ) -> Result<(), String> {
+        actions::answer_remote_action(id, allow, remember, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn list_action_log(engine: State<'_, Engine>) -> Result<Vec<actions::AuditEntry>, String> {
+        actions::list_action_log(engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub async fn send_url(url: String, title: Option<String>, engine: State<'_, Engine>) -> Result<(), String> {
+        links::send_url(url, title, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn list_links(engine: State<'_, Engine>) -> Vec<links::ReceivedLink> {
+        links::list_links(engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn open_link(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        links::open_link(id, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn dismiss_link(id: String, engine: State<'_, Engine>) {
+        links::dismiss_link(id, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub async fn send_chat(peer: String, text: String, engine: State<'_, Engine>) -> Result<chat::ChatMessage, String> {
+        chat::send_chat(peer, text, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn list_chat(peer: String, engine: State<'_, Engine>) -> Result<Vec<chat::ChatMessage>, String> {
+        chat::list_chat(peer, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn list_chat_threads(engine: State<'_, Engine>) -> Result<Vec<chat::ChatThread>, String> {
+        chat::list_chat_threads(engine.inner().clone())
+    }
 
-#[tauri::command]
-async fn save_received_file(name: String, data: String, app: tauri::AppHandle) -> Result<String, String> {
-    sync::save_received_file(name, data, app).await
+    #[tauri::command]
+    pub async fn mark_chat_read(peer: String, engine: State<'_, Engine>) -> Result<(), String> {
+        chat::mark_chat_read(peer, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn list_outbox(engine: State<'_, Engine>) -> Result<Vec<outbox::OutboxItem>, String> {
+        outbox::list_outbox(engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn cancel_outbox(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        outbox::cancel_outbox(id, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub async fn pick_and_send_file(app: AppHandle, engine: State<'_, Engine>) -> Result<(), String> {
+        match gui::pick_file(&app)? {
+            Some(path) => sync::send_path(path, engine.inner().clone()).await,
+            None => Ok(()),
+        }
+    }
+
+    #[tauri::command]
+    pub async fn capture_screenshot_and_send(
+        options: Option<screenshot::CaptureOptions>,
+        engine: State<'_, Engine>,
+    ) -> Result<(), String> {
+        sync::capture_screenshot_and_send(options, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn capture_screenshot(
+        mode: Option<screenshot::CaptureMode>,
+        engine: State<'_, Engine>,
+    ) -> Result<sync::ShotPreview, String> {
+        sync::capture_screenshot(mode, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn send_screenshot(
+        id: String,
+        edits: Vec<annotate::Edit>,
+        options: Option<screenshot::CaptureOptions>,
+        engine: State<'_, Engine>,
+    ) -> Result<(), String> {
+        sync::send_screenshot(id, edits, options, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn discard_screenshot(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        sync::discard_screenshot(id, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub async fn request_remote_screenshot(
+        mode: Option<screenshot::CaptureMode>,
+        engine: State<'_, Engine>,
+    ) -> Result<String, String> {
+        sync::request_remote_screenshot(mode, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn answer_screenshot_request(
+        id: String,
+        allow: bool,
+        remember: bool,
+        engine: State<'_, Engine>,
+    ) -> Result<(), String> {
+        sync::answer_screenshot_request(id, allow, remember, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn start_screen_share(engine: State<'_, Engine>) -> Result<(), String> {
+        mirror::start_sharing(engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn stop_screen_share(engine: State<'_, Engine>) {
+        mirror::stop_sharing(engine.inner().clone())
+    }
+
+    /// Copies a staged file to a location chosen in the save dialog.
+    #[tauri::command]
+    pub async fn save_received_file(id: String, app: AppHandle, engine: State<'_, Engine>) -> Result<String, String> {
+        let name = sync::staged_name(&engine, &id)?;
+        let path = gui::pick_save_path(&app, &name)?.ok_or("Cancelled")?;
+        sync::save_staged(id, path, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn list_history(query: Option<String>, engine: State<'_, Engine>) -> Result<Vec<history::HistoryEntry>, String> {
+        history::list_history(query, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn pin_history(id: String, pinned: bool, engine: State<'_, Engine>) -> Result<(), String> {
+        history::pin_history(id, pinned, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn delete_history(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        history::delete_history(id, engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub async fn resend_history(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        sync::resend_history(id, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub async fn apply_history(id: String, engine: State<'_, Engine>) -> Result<(), String> {
+        sync::apply_history(id, engine.inner().clone()).await
+    }
+
+    #[tauri::command]
+    pub fn get_settings(engine: State<'_, Engine>) -> Result<settings::Settings, String> {
+        settings::get_settings(engine.inner().clone())
+    }
+
+    #[tauri::command]
+    pub fn set_settings(settings: settings::Settings, engine: State<'_, Engine>) -> Result<(), String> {
+        settings::set_settings(settings, engine.inner().clone())
+    }
+
+    /// Lets the user choose the auto-save folder, globally or for a single peer.
+    #[tauri::command]
+    pub async fn pick_download_dir(
+        peer: Option<String>,
+        app: AppHandle,
+        engine: State<'_, Engine>,
+    ) -> Result<Option<String>, String> {
+        let Some(dir) = gui::pick_folder(&app)? else {
+            return Ok(None);
+        };
+        settings::set_download_dir(peer, dir.clone(), engine.inner().clone())?;
+        Ok(Some(dir.to_string_lossy().into_owned()))
+    }
 }
 
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    use tauri::Manager;
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_clipboard_manager::init())
         .plugin(tauri_plugin_dialog::init())
-        .manage(sync::SyncState::default())
+        .plugin(tauri_plugin_notification::init())
+        .setup(|app| {
+            let handle = app.handle();
+            let paths = engine::Paths {
+                config_dir: handle.path().app_config_dir()?,
+                data_dir: handle.path().app_data_dir()?,
+                cache_dir: handle.path().app_cache_dir()?,
+            };
+            let runtime = tauri::async_runtime::block_on(async { tokio::runtime::Handle::current() });
+            let engine = engine::Engine::new(
+                engine::host_name(),
+                paths,
+                gui::TauriEvents::new(handle.clone()),
+                gui::TauriDesktop::new(handle.clone()),
+                runtime,
+            );
+            clipboard::start_watcher(engine.clone());
+            let endpoint = control::app_endpoint(&engine);
+            let serving = engine.clone();
+            engine.spawn(async move {
+                if let Err(e) = control::serve(serving, &endpoint).await {
+                    eprintln!("control endpoint {endpoint}: {e}");
+                }
+            });
+            app.manage(engine);
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
-            get_host_name,
-            start_host,
-            stop_host,
-            start_browse,
-            stop_browse,
-            connect_to,
-            disconnect,
-            send_clipboard,
-            send_bring_to_front,
-            pick_and_send_file,
-            capture_screenshot_and_send,
-            save_received_file,
+            commands::get_host_name,
+            commands::start_host,
+            commands::stop_host,
+            commands::start_browse,
+            commands::stop_browse,
+            commands::connect_to,
+            commands::disconnect,
+            commands::send_clipboard,
+            commands::paste_from_remote,
+            commands::send_bring_to_front,
+            commands::send_remote_action,
+            commands::answer_remote_action,
+            commands::list_action_log,
+            commands::send_url,
+            commands::list_links,
+            commands::open_link,
+            commands::dismiss_link,
+            commands::send_chat,
+            commands::list_chat,
+            commands::list_chat_threads,
+            commands::mark_chat_read,
+            commands::list_outbox,
+            commands::cancel_outbox,
+            commands::pick_and_send_file,
+            commands::capture_screenshot_and_send,
+            commands::capture_screenshot,
+            commands::send_screenshot,
+            commands::discard_screenshot,
+            commands::request_remote_screenshot,
+            commands::answer_screenshot_request,
+            commands::start_screen_share,
+            commands::stop_screen_share,
+            commands::save_received_file,
+            commands::get_settings,
+            commands::set_settings,
+            commands::pick_download_dir,
+            commands::list_history,
+            commands::pin_history,
+            commands::delete_history,
+            commands::resend_history,
+            commands::apply_history,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                let engine = app.state::<engine::Engine>();
+                control::stop_serving(&engine, &control::app_endpoint(&engine));
+            }
+        });
 }
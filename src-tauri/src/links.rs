@@ -0,0 +1,187 @@
+use serde::Serialize;
+
+use crate::engine::Engine;
+use crate::settings::{self, LinkPolicy};
+use crate::sync::{self, WsMessage};
+
+/// Received links kept in the list; the oldest are dropped first.
+const MAX_LINKS: usize = 100;
+const MAX_URL_LEN: usize = 8 * 1024;
+
+#[derive(Clone, Serialize)]
+pub struct ReceivedLink {
+    pub id: String,
+    pub url: String,
+    pub title: Option<String>,
+    pub peer: Option<String>,
+    /// Unix time in milliseconds.
+    pub timestamp: i64,
+}
+
+/// Accepts `url` only if its scheme is one of `schemes` (compared case-insensitively) and
+/// it contains no whitespace or control characters.
+pub fn check_url(url: &str, schemes: &[String]) -> Result<(), String> {
+    if url.is_empty() || url.len() > MAX_URL_LEN {
+        return Err("Invalid link".to_string());
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("Invalid link".to_string());
+    }
+    let scheme = match url.split_once(':') {
+        Some((scheme, _))
+            if scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+        {
+            scheme
+        }
+        _ => return Err("Invalid link".to_string()),
+    };
+    if schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) {
+        Ok(())
+    } else {
+        Err(format!("{scheme}: links are not allowed"))
+    }
+}
+
+fn emit_links(engine: &Engine) {
+    engine.emit("links", list_links(engine.clone()));
+}
+
+/// A link from the peer that was rejected by the scheme check.
+fn emit_link_rejected(engine: &Engine, url: &str, reason: &str) {
+    engine.emit(
+        "link_rejected",
+        serde_json::json!({ "url": url, "reason": reason }),
+    );
+}
+
+fn queue(engine: &Engine, link: ReceivedLink) {
+    {
+        let mut links = engine.state().links.lock().unwrap();
+        if links.len() == MAX_LINKS {
+            links.remove(0);
+        }
+        links.push(link);
+    }
+    emit_links(engine);
+}
+
+/// Opens or queues a link from the peer, per `link_policy_for`.
+pub fn receive_url(engine: &Engine, url: String, title: Option<String>) {
+    let settings = settings::current(engine);
+    if let Err(reason) = check_url(&url, &settings.allowed_url_schemes) {
+        return emit_link_rejected(engine, &url, &reason);
+    }
+    let peer = sync::current_peer(engine);
+    let policy = settings.link_policy_for(peer.as_deref());
+    // A link that fails to open is kept, so it is not lost.
+    if policy == LinkPolicy::Open && engine.desktop().open_url(&url).is_ok() {
+        engine.emit("link_opened", serde_json::json!({ "url": url, "peer": peer }));
+        return;
+    }
+    let link = ReceivedLink {
+        id: crate::transfer::new_id(),
+        url,
+        title,
+        peer,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+    };
+    queue(engine, link);
+}
+
+/// Sends a link for the peer to open.
+pub async fn send_url(url: String, title: Option<String>, engine: Engine) -> Result<(), String> {
+    let url = url.trim().to_string();
+    check_url(&url, &settings::current(&engine).allowed_url_schemes)?;
+    if !sync::peer_supports(&engine, sync::FEATURE_OPEN_URL) {
+        return Err("The other device does not support receiving links".to_string());
+    }
+    sync::send_message(&engine, &WsMessage::OpenUrl { url, title }).await
+}
+
+/// Received links, newest first.
+pub fn list_links(engine: Engine) -> Vec<ReceivedLink> {
+    let links = engine.state().links.lock().unwrap();
+    links.iter().rev().cloned().collect()
+}
+
+/// Opens a queued link and removes it from the list.
+pub fn open_link(id: String, engine: Engine) -> Result<(), String> {
+    let url = {
+        let links = engine.state().links.lock().unwrap();
+        links.iter().find(|l| l.id == id).map(|l| l.url.clone())
+    };
+    engine.desktop().open_url(&url.ok_or("No such link")?)?;
+    dismiss_link(id, engine);
+    Ok(())
+}
+
+pub fn dismiss_link(id: String, engine: Engine) {
+    engine.state().links.lock().unwrap().retain(|l| l.id != id);
+    emit_links(&engine);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn web() -> Vec<String> {
+        vec!["https".to_string(), "http".to_string()]
+    }
+
+    #[test]
+    fn accepts_allowed_schemes_in_any_case() {
+        assert!(check_url("https://example.com/a?b=c#d", &web()).is_ok());
+        assert!(check_url("HTTP://example.com", &web()).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        for (url, scheme) in [
+            ("file:///etc/passwd", "file"),
+            ("javascript:alert(1)", "javascript"),
+            ("smb://host/share", "smb"),
+            ("data:text/html,x", "data"),
+        ] {
+            let error = check_url(url, &web()).unwrap_err();
+            assert_eq!(error, format!("{scheme}: links are not allowed"));
+        }
+        assert!(check_url("https://example.com", &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_schemes() {
+        for url in [
+            "",
+            "example.com",
+            "://example.com",
+            "1http://example.com",
+            "h_t://x",
+        ] {
+            assert_eq!(check_url(url, &web()).unwrap_err(), "Invalid link", "{url:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_whitespace_and_control_characters() {
+        for url in [
+            "https://example.com/a b",
+            "https://example.com/\n",
+            "https://exa\tmple.com",
+            "https://example.com\u{0}",
+            "https://example.com/\u{a0}",
+            "ht tp://x",
+        ] {
+            assert_eq!(check_url(url, &web()).unwrap_err(), "Invalid link", "{url:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_links() {
+        let prefix = "https://example.com/";
+        let longest = format!("{prefix}{}", "a".repeat(MAX_URL_LEN - prefix.len()));
+        assert!(check_url(&longest, &web()).is_ok());
+        let error = check_url(&format!("{longest}a"), &web()).unwrap_err();
+        assert_eq!(error, "Invalid link");
+    }
+}
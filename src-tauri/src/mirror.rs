@@ -0,0 +1,242 @@
+use base64::Engine as _;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::engine::Engine;
+use crate::screenshot::ScreenGrabber;
+use crate::sync::{self, WsMessage};
+
+/// Frames wider than this are scaled down before encoding.
+const MAX_WIDTH: u32 = 1600;
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+const MAX_INTERVAL: Duration = Duration::from_secs(2);
+/// Frames that take longer than this to be acknowledged push quality down.
+const TARGET_DELIVERY: Duration = Duration::from_millis(500);
+/// A frame not acknowledged by then counts as delivered at this speed.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// An unchanged screen is still resent this often, so a viewer that missed a frame
+/// (e.g. while its window was opening) catches up.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_QUALITY: u8 = 30;
+const MAX_QUALITY: u8 = 85;
+
+/// Whether our screen is being streamed, kept in `SyncState`.
+#[derive(Default)]
+pub struct Sharing {
+    active: AtomicBool,
+    /// Highest frame sequence number sent in the current share.
+    sent: AtomicU64,
+    /// Highest frame sequence number the viewer has acknowledged.
+    acked: AtomicU64,
+    /// The capture loop of the last share. A new share waits for it to end, so two loops
+    /// never stream at once.
+    task: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Frame rate and JPEG quality, adapted to how fast frames get acknowledged.
+struct Pace {
+    quality: u8,
+    interval: Duration,
+    /// Smoothed bytes per second from send to acknowledgement.
+    throughput: f64,
+}
+
+impl Pace {
+    fn new() -> Self {
+        Pace {
+            quality: 60,
+            interval: Duration::from_millis(500),
+            throughput: 0.0,
+        }
+    }
+
+    fn on_frame(&mut self, bytes: usize, elapsed: Duration) {
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.throughput = if self.throughput == 0.0 {
+            rate
+        } else {
+            0.7 * self.throughput + 0.3 * rate
+        };
+
+        // Leave the link idle about half the time so clipboard and files still get through.
+        let delivery = Duration::from_secs_f64(bytes as f64 / self.throughput);
+        self.interval = (delivery * 2).clamp(MIN_INTERVAL, MAX_INTERVAL);
+        if delivery > TARGET_DELIVERY {
+            self.quality = self.quality.saturating_sub(10).max(MIN_QUALITY);
+        } else if delivery < TARGET_DELIVERY / 4 {
+            self.quality = (self.quality + 5).min(MAX_QUALITY);
+        }
+    }
+}
+
+struct Frame {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Hash of the pixels, to skip frames where nothing changed.
+    hash: u64,
+}
+
+/// Captures the whole screen and encodes it as a scaled-down JPEG.
+fn grab_frame(grabber: &ScreenGrabber, quality: u8) -> Result<Frame, String> {
+    let mut image = image::open(grabber.grab()?).map_err(|e| e.to_string())?;
+    if image.width() > MAX_WIDTH {
+        image = image.resize(MAX_WIDTH, u32::MAX, FilterType::Triangle);
+    }
+    let image = image.to_rgb8();
+
+    let mut hasher = DefaultHasher::new();
+    image.as_raw().hash(&mut hasher);
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode_image(&image)
+        .map_err(|e| e.to_string())?;
+    Ok(Frame {
+        jpeg,
+        width: image.width(),
+        height: image.height(),
+        hash: hasher.finish(),
+    })
+}
+
+fn emit_sharing(engine: &Engine, sharing: bool, error: Option<&str>) {
+    engine.emit(
+        "screen_sharing",
+        serde_json::json!({ "sharing": sharing, "error": error }),
+    );
+}
+
+/// Waits until the viewer acknowledges `seq`, or the timeout passes.
+async fn wait_for_ack(sharing: &Sharing, seq: u64) {
+    let started = Instant::now();
+    while sharing.acked.load(Ordering::SeqCst) < seq && started.elapsed() < ACK_TIMEOUT {
+        if !sharing.active.load(Ordering::SeqCst) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Starts streaming our screen to the peer until `stop_sharing` or disconnect.
+pub async fn start_sharing(engine: Engine) -> Result<(), String> {
+    if !sync::peer_supports(&engine, sync::FEATURE_MIRROR) {
+        return Err("The other device does not support screen sharing".to_string());
+    }
+    let sharing = &engine.state().sharing;
+    let mut task = sharing.task.lock().await;
+    if sharing.active.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let grabber = Arc::new(engine.spawn_blocking(ScreenGrabber::new).await.map_err(|e| e.to_string())??);
+    // A share that was just stopped may still be finishing a frame; let it send its
+    // `MirrorStop` before the new one starts.
+    if let Some(previous) = task.take() {
+        let _ = previous.await;
+    }
+    sharing.sent.store(0, Ordering::SeqCst);
+    sharing.acked.store(0, Ordering::SeqCst);
+    sharing.active.store(true, Ordering::SeqCst);
+    emit_sharing(&engine, true, None);
+
+    let looping = engine.clone();
+    *task = Some(engine.spawn(async move {
+        let engine = looping;
+        let mut pace = Pace::new();
+        let mut last_hash = None;
+        let mut last_sent = Instant::now();
+        let mut seq = 0;
+        let mut error = None;
+
+        let sharing = &engine.state().sharing;
+        while sharing.active.load(Ordering::SeqCst) && sync::is_connected(&engine) {
+            let started = Instant::now();
+            let quality = pace.quality;
+            let grabber = grabber.clone();
+            let frame = match engine.spawn_blocking(move || grab_frame(&grabber, quality)).await {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(e)) => {
+                    error = Some(e);
+                    break;
+                }
+                Err(e) => {
+                    error = Some(e.to_string());
+                    break;
+                }
+            };
+
+            // Simple delta scheme: an unchanged screen sends nothing.
+            if last_hash != Some(frame.hash) || last_sent.elapsed() > KEYFRAME_INTERVAL {
+                last_hash = Some(frame.hash);
+                last_sent = Instant::now();
+                seq += 1;
+                sharing.sent.store(seq, Ordering::SeqCst);
+                let sent_at = Instant::now();
+                let msg = WsMessage::MirrorFrame {
+                    seq,
+                    width: frame.width,
+                    height: frame.height,
+                    data: base64::engine::general_purpose::STANDARD.encode(&frame.jpeg),
+                };
+                if let Err(e) = sync::send_message(&engine, &msg).await {
+                    error = Some(e);
+                    break;
+                }
+                wait_for_ack(sharing, seq).await;
+                pace.on_frame(frame.jpeg.len(), sent_at.elapsed());
+                engine.emit(
+                    "screen_sharing_stats",
+                    serde_json::json!({
+                        "fps": 1.0 / pace.interval.as_secs_f64(),
+                        "quality": pace.quality,
+                        "bytes": frame.jpeg.len(),
+                    }),
+                );
+            }
+            tokio::time::sleep(pace.interval.saturating_sub(started.elapsed())).await;
+        }
+
+        sharing.active.store(false, Ordering::SeqCst);
+        let _ = sync::send_message(&engine, &WsMessage::MirrorStop).await;
+        emit_sharing(&engine, false, error.as_deref());
+    }));
+    Ok(())
+}
+
+pub fn stop_sharing(engine: Engine) {
+    engine.state().sharing.active.store(false, Ordering::SeqCst);
+}
+
+/// Acknowledgements for frames of an earlier share, which can arrive late, are ignored.
+pub fn receive_ack(engine: &Engine, seq: u64) {
+    let sharing = &engine.state().sharing;
+    if seq <= sharing.sent.load(Ordering::SeqCst) {
+        sharing.acked.fetch_max(seq, Ordering::SeqCst);
+    }
+}
+
+/// Hands the frame to the viewer and acknowledges it, which is what paces the sender.
+/// Frame 1 starts a new share.
+pub async fn receive_frame(engine: &Engine, seq: u64, width: u32, height: u32, data: String) {
+    engine.emit(
+        "mirror_frame",
+        serde_json::json!({
+            "seq": seq,
+            "width": width,
+            "height": height,
+            "data": data,
+            "peer": sync::current_peer(engine),
+        }),
+    );
+    let _ = sync::send_message(engine, &WsMessage::MirrorAck { seq }).await;
+}
+
+pub fn receive_stop(engine: &Engine) {
+    engine.emit("mirror_stopped", ());
+}
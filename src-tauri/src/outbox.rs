@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+use crate::clipboard::{ClipContent, LocalClip};
+use crate::engine::Engine;
+use crate::files;
+use crate::settings;
+use crate::sync;
+
+const OUTBOX_FILE: &str = "outbox.json";
+/// Items kept per outbox; queuing more fails until some are delivered or cancelled.
+const MAX_ITEMS: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxKind {
+    /// Only the newest queued clipboard item per peer is kept.
+    Clipboard {
+        content: ClipContent,
+        #[serde(default)]
+        files: Vec<PathBuf>,
+    },
+    /// Sent from its original location, so it must still be there on delivery.
+    File { path: PathBuf, name: String },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutboxItem {
+    pub id: String,
+    pub peer: String,
+    /// Unix time in milliseconds.
+    pub created: i64,
+    /// Dropped undelivered after this, in Unix milliseconds.
+    pub expires: i64,
+    #[serde(flatten)]
+    pub kind: OutboxKind,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Outbox {
+    /// Peer of the last connection; items queued while disconnected are addressed to it.
+    last_peer: Option<String>,
+    /// Oldest first.
+    items: Vec<OutboxItem>,
+}
+
+impl Outbox {
+    /// Removes and returns the items that expired by `now`.
+    fn prune(&mut self, now: i64) -> Vec<OutboxItem> {
+        let (kept, expired) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition(|item| item.expires > now);
+        self.items = kept;
+        expired
+    }
+}
+
+fn outbox_path(engine: &Engine) -> PathBuf {
+    engine.paths().data_dir.join(OUTBOX_FILE)
+}
+
+/// Loads the outbox from the app data dir, starting empty if missing or unreadable.
+/// Expired items are kept until the next change or listing, which reports them.
+pub fn load(engine: &Engine) -> Outbox {
+    std::fs::read(outbox_path(engine))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save(engine: &Engine, outbox: &Outbox) -> Result<(), String> {
+    let path = outbox_path(engine);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec(outbox).map_err(|e| e.to_string())?;
+    files::write_atomic(&path, &json)
+}
+
+/// Applies `change` to the outbox after dropping expired items, persists it and tells the
+/// UI to refresh.
+fn update<T>(engine: &Engine, change: impl FnOnce(&mut Outbox) -> T) -> Result<T, String> {
+    let state = engine.state();
+    let mut outbox = state.outbox.lock().map_err(|_| "lock")?;
+    prune(engine, &mut outbox);
+    let result = change(&mut outbox);
+    save(engine, &outbox)?;
+    engine.emit("outbox", ());
+    Ok(result)
+}
+
+/// A queued item that could not be delivered and was dropped.
+fn emit_outbox_failed(engine: &Engine, item: &OutboxItem, reason: &str) {
+    engine.emit(
+        "outbox_failed",
+        serde_json::json!({ "id": item.id, "peer": item.peer, "reason": reason }),
+    );
+}
+
+/// Drops expired items, reporting each as failed. Returns whether any were dropped.
+fn prune(engine: &Engine, outbox: &mut Outbox) -> bool {
+    let expired = outbox.prune(chrono::Utc::now().timestamp_millis());
+    for item in &expired {
+        emit_outbox_failed(engine, item, "It expired before the peer connected");
+    }
+    !expired.is_empty()
+}
+
+/// The peer items are addressed to while disconnected.
+pub fn last_peer(engine: &Engine) -> Option<String> {
+    engine.state().outbox.lock().ok().and_then(|o| o.last_peer.clone())
+}
+
+/// Queues an item for `peer`, to be sent when it next connects.
+pub fn queue(engine: &Engine, peer: String, kind: OutboxKind) -> Result<(), String> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let ttl_hours = settings::current(engine).outbox_ttl_hours;
+    let item = OutboxItem {
+        id: crate::transfer::new_id(),
+        peer,
+        created: now,
+        expires: now.saturating_add((ttl_hours as i64).saturating_mul(3_600_000)),
+        kind,
+    };
+    update(engine, |outbox| {
+        if matches!(item.kind, OutboxKind::Clipboard { .. }) {
+            outbox.items.retain(|i| {
+                i.peer != item.peer || !matches!(i.kind, OutboxKind::Clipboard { .. })
+            });
+        }
+        if outbox.items.len() >= MAX_ITEMS {
+            return Err("The outbox is full".to_string());
+        }
+        outbox.items.push(item);
+        Ok(())
+    })?
+}
+
+async fn deliver_item(engine: &Engine, item: &OutboxItem) -> Result<(), String> {
+    match &item.kind {
+        OutboxKind::Clipboard { content, files } => {
+            let clip = LocalClip {
+                content: content.clone(),
+                files: files.iter().filter(|p| p.is_file()).cloned().collect(),
+                concealed: false,
+            };
+            sync::send_clip(engine, clip).await
+        }
+        OutboxKind::File { path, name } => {
+            let meta = files::read_meta(path, settings::current(engine).preserve_xattrs);
+            sync::send_file(engine, path, name.clone(), Some(meta)).await
+        }
+    }
+}
+
+/// Clears the `delivering` flag when dropped, so a delivery that panics does not stop the
+/// outbox from ever delivering again.
+struct Delivering(Engine);
+
+impl Drop for Delivering {
+    fn drop(&mut self) {
+        self.0.state().delivering.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Called when a peer says hello: remembers it and sends what is queued for it, oldest
+/// first. Stops at the first send error, keeping the rest for next time.
+pub fn deliver(engine: &Engine, peer: &str) {
+    let _ = update(engine, |outbox| outbox.last_peer = Some(peer.to_string()));
+    if engine.state().delivering.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let delivering = Delivering(engine.clone());
+    let engine = engine.clone();
+    let peer = peer.to_string();
+    // Files can take a while; keep reading messages meanwhile.
+    engine.clone().spawn(async move {
+        let _delivering = delivering;
+        let pending: Vec<OutboxItem> = match engine.state().outbox.lock() {
+            Ok(outbox) => outbox.items.iter().filter(|i| i.peer == peer).cloned().collect(),
+            Err(_) => Vec::new(),
+        };
+        for item in pending {
+            if sync::current_peer(&engine).as_deref() != Some(peer.as_str()) {
+                break;
+            }
+            if let OutboxKind::File { path, .. } = &item.kind {
+                if !path.is_file() {
+                    let _ = update(&engine, |outbox| outbox.items.retain(|i| i.id != item.id));
+                    emit_outbox_failed(&engine, &item, "The file no longer exists");
+                    continue;
+                }
+            }
+            if deliver_item(&engine, &item).await.is_err() {
+                break;
+            }
+            let _ = update(&engine, |outbox| outbox.items.retain(|i| i.id != item.id));
+        }
+    });
+}
+
+/// Queued items, oldest first.
+pub fn list_outbox(engine: Engine) -> Result<Vec<OutboxItem>, String> {
+    let state = engine.state();
+    let mut outbox = state.outbox.lock().map_err(|_| "lock")?;
+    if prune(&engine, &mut outbox) {
+        save(&engine, &outbox)?;
+    }
+    Ok(outbox.items.clone())
+}
+
+pub fn cancel_outbox(id: String, engine: Engine) -> Result<(), String> {
+    update(&engine, |outbox| outbox.items.retain(|i| i.id != id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestEngine};
+
+    fn queue_file(engine: &Engine, name: &str) {
+        let kind = OutboxKind::File {
+            path: PathBuf::from(name),
+            name: name.to_string(),
+        };
+        queue(engine, "peer".to_string(), kind).unwrap();
+    }
+
+    fn queue_text(engine: &Engine, peer: &str, text: &str) {
+        let kind = OutboxKind::Clipboard {
+            content: ClipContent {
+                text: text.to_string(),
+                formats: Vec::new(),
+            },
+            files: Vec::new(),
+        };
+        queue(engine, peer.to_string(), kind).unwrap();
+    }
+
+    fn texts(engine: &Engine) -> Vec<(String, String)> {
+        list_outbox(engine.clone())
+            .unwrap()
+            .into_iter()
+            .filter_map(|item| match item.kind {
+                OutboxKind::Clipboard { content, .. } => Some((item.peer, content.text)),
+                OutboxKind::File { .. } => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn newer_clipboard_item_replaces_the_older_one_for_the_same_peer_only() {
+        let test = TestEngine::new("outbox-clipboard");
+        queue_text(&test.engine, "other", "for other");
+        queue_text(&test.engine, "peer", "old");
+        queue_file(&test.engine, "kept.txt");
+        queue_text(&test.engine, "peer", "new");
+
+        let expected = [("other", "for other"), ("peer", "new")].map(|(p, t)| (p.to_string(), t.to_string()));
+        assert_eq!(texts(&test.engine), expected);
+        assert_eq!(list_outbox(test.engine.clone()).unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn full_outbox_refuses_more_items() {
+        let test = TestEngine::new("outbox-full");
+        for i in 0..MAX_ITEMS {
+            queue_file(&test.engine, &format!("{i}.txt"));
+        }
+        let kind = OutboxKind::File {
+            path: PathBuf::from("one-more.txt"),
+            name: "one-more.txt".to_string(),
+        };
+        let err = queue(&test.engine, "peer".to_string(), kind).err().unwrap();
+        assert_eq!(err, "The outbox is full");
+        assert_eq!(list_outbox(test.engine.clone()).unwrap().len(), MAX_ITEMS);
+    }
+
+    #[tokio::test]
+    async fn cancelled_item_is_removed() {
+        let test = TestEngine::new("outbox-cancel");
+        queue_file(&test.engine, "a.txt");
+        queue_file(&test.engine, "b.txt");
+        let id = list_outbox(test.engine.clone()).unwrap()[0].id.clone();
+
+        cancel_outbox(id.clone(), test.engine.clone()).unwrap();
+        let items = list_outbox(test.engine.clone()).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_ne!(items[0].id, id);
+        assert_eq!(load(&test.engine).items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delivery_that_panics_does_not_block_later_ones() {
+        let test = TestEngine::new("outbox-panic");
+        let state = test.engine.state();
+        state.delivering.store(true, Ordering::SeqCst);
+        let guard = Delivering(test.engine.clone());
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("send failed");
+        });
+        assert!(task.await.is_err());
+        assert!(!state.delivering.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queued_file_that_is_gone_is_reported_and_removed() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let kind = OutboxKind::File {
+            path: client.dir.join("deleted.txt"),
+            name: "deleted.txt".to_string(),
+        };
+        queue(&client.engine, "host".to_string(), kind).unwrap();
+
+        testing::connect(&mut host, &mut client).await;
+        let failed = client.wait_for("outbox_failed", |_| true).await;
+        assert_eq!(failed["reason"], "The file no longer exists");
+        assert!(list_outbox(client.engine.clone()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_items_are_reported_as_failed() {
+        let mut test = TestEngine::new("outbox-expired");
+        queue_file(&test.engine, "old.txt");
+        queue_file(&test.engine, "new.txt");
+        test.engine.state().outbox.lock().unwrap().items[0].expires = 0;
+
+        let items = list_outbox(test.engine.clone()).unwrap();
+        assert_eq!(items.len(), 1);
+        let failed = test.wait_for("outbox_failed", |_| true).await;
+        assert_ne!(failed["id"], items[0].id.as_str());
+        assert!(failed["reason"].as_str().unwrap().contains("expired"), "{failed}");
+        // The expired item is gone from disk too, so it is reported only once.
+        assert_eq!(load(&test.engine).items.len(), 1);
+    }
+}
@@ -0,0 +1,491 @@
+use chrono::Local;
+use image::codecs::jpeg::JpegEncoder;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::{TempDir, TempPath};
+
+use crate::annotate::{self, Edit};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureMode {
+    /// The user drags out a rectangle.
+    Region,
+    /// A single window: picked by the user where the platform allows, otherwise the
+    /// focused one.
+    Window,
+    /// Every screen.
+    Full,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureOptions {
+    pub mode: CaptureMode,
+    pub format: ImageFormat,
+    /// JPEG quality, 1-100. Ignored for PNG.
+    pub quality: u8,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            mode: CaptureMode::Region,
+            format: ImageFormat::Jpeg,
+            quality: 85,
+        }
+    }
+}
+
+/// A way of grabbing the screen on this machine. Backends always write a PNG; converting
+/// to the requested format happens afterwards.
+trait Backend: Send + Sync {
+    fn available(&self) -> bool;
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String>;
+    /// Whether full-screen captures are quiet enough to take several times a second for
+    /// screen sharing, i.e. without a flash, a sound or a prompt each time.
+    fn streams(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn on_path(program: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+}
+
+/// Runs a capture tool; a non-zero exit or a missing file means the user cancelled.
+fn run(command: &mut Command, png: &Path) -> Result<(), String> {
+    let status = command.status().map_err(|e| e.to_string())?;
+    if !status.success() || !png.is_file() {
+        return Err("Screenshot cancelled or failed".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+struct ScreenCapture;
+
+#[cfg(target_os = "macos")]
+impl Backend for ScreenCapture {
+    fn available(&self) -> bool {
+        true
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let mut command = Command::new("screencapture");
+        command.args(["-x", "-t", "png"]);
+        match mode {
+            CaptureMode::Region => command.arg("-i"),
+            CaptureMode::Window => command.args(["-i", "-w"]),
+            CaptureMode::Full => &mut command,
+        };
+        run(command.arg(png), png)
+    }
+}
+
+/// wlroots compositors (Sway, Hyprland, ...).
+#[cfg(target_os = "linux")]
+struct Grim;
+
+#[cfg(target_os = "linux")]
+impl Backend for Grim {
+    fn available(&self) -> bool {
+        std::env::var_os("WAYLAND_DISPLAY").is_some() && on_path("grim")
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let mut command = Command::new("grim");
+        if mode != CaptureMode::Full {
+            if !on_path("slurp") {
+                return Err("Install slurp to select a region".to_string());
+            }
+            let output = Command::new("slurp").output().map_err(|e| e.to_string())?;
+            if !output.status.success() {
+                return Err("Screenshot cancelled or failed".to_string());
+            }
+            let geometry = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            command.args(["-g", &geometry]);
+        }
+        run(command.arg(png), png)
+    }
+}
+
+/// KDE, on X11 and Wayland (goes through KWin, so no portal prompt).
+#[cfg(target_os = "linux")]
+struct Spectacle;
+
+#[cfg(target_os = "linux")]
+impl Backend for Spectacle {
+    fn available(&self) -> bool {
+        on_path("spectacle")
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let flag = match mode {
+            CaptureMode::Region => "-r",
+            CaptureMode::Window => "-a",
+            CaptureMode::Full => "-f",
+        };
+        let mut command = Command::new("spectacle");
+        run(command.args(["-b", "-n", flag, "-o"]).arg(png), png)
+    }
+}
+
+/// GNOME, on X11 and Wayland (through the screenshot portal).
+#[cfg(target_os = "linux")]
+struct GnomeScreenshot;
+
+#[cfg(target_os = "linux")]
+impl Backend for GnomeScreenshot {
+    fn available(&self) -> bool {
+        on_path("gnome-screenshot")
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let mut command = Command::new("gnome-screenshot");
+        match mode {
+            CaptureMode::Region => command.arg("-a"),
+            CaptureMode::Window => command.arg("-w"),
+            CaptureMode::Full => &mut command,
+        };
+        run(command.arg("-f").arg(png), png)
+    }
+
+    /// Flashes the screen and plays the shutter sound on every capture.
+    fn streams(&self) -> bool {
+        false
+    }
+}
+
+/// Plain X11 with maim, using xdotool for the focused window.
+#[cfg(target_os = "linux")]
+struct Maim;
+
+#[cfg(target_os = "linux")]
+impl Backend for Maim {
+    fn available(&self) -> bool {
+        std::env::var_os("DISPLAY").is_some() && on_path("maim")
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let mut command = Command::new("maim");
+        match mode {
+            CaptureMode::Region => {
+                command.arg("-s");
+            }
+            CaptureMode::Window if on_path("xdotool") => {
+                let output = Command::new("xdotool")
+                    .arg("getactivewindow")
+                    .output()
+                    .map_err(|e| e.to_string())?;
+                let window = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                command.args(["-i", &window]);
+            }
+            // Without xdotool, let the user click the window.
+            CaptureMode::Window => {
+                command.args(["-s", "-u"]);
+            }
+            CaptureMode::Full => {}
+        }
+        run(command.arg(png), png)
+    }
+}
+
+/// Plain X11 with ImageMagick's `import`, which is installed almost everywhere.
+#[cfg(target_os = "linux")]
+struct Import;
+
+#[cfg(target_os = "linux")]
+impl Backend for Import {
+    fn available(&self) -> bool {
+        std::env::var_os("DISPLAY").is_some() && on_path("import")
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        // Without `-window`, import lets the user click a window or drag a region.
+        let mut command = Command::new("import");
+        if mode == CaptureMode::Full {
+            command.args(["-window", "root"]);
+        }
+        run(command.arg(png), png)
+    }
+}
+
+/// GDI through PowerShell for full screen and window; Snip & Sketch for regions.
+#[cfg(target_os = "windows")]
+struct Gdi;
+
+#[cfg(target_os = "windows")]
+const GDI_SCRIPT: &str = r#"
+Add-Type -AssemblyName System.Windows.Forms, System.Drawing
+Add-Type @'
+using System;
+using System.Runtime.InteropServices;
+public static class Native {
+    [DllImport("user32.dll")] public static extern bool SetProcessDPIAware();
+    [DllImport("user32.dll")] public static extern IntPtr GetForegroundWindow();
+    [DllImport("user32.dll")] public static extern bool GetWindowRect(IntPtr hWnd, out RECT rect);
+    public struct RECT { public int Left, Top, Right, Bottom; }
+}
+'@
+[Native]::SetProcessDPIAware() | Out-Null
+if ($env:FILETWIN_CAPTURE_MODE -eq 'window') {
+    $r = New-Object Native+RECT
+    [Native]::GetWindowRect([Native]::GetForegroundWindow(), [ref]$r) | Out-Null
+    $bounds = [System.Drawing.Rectangle]::FromLTRB($r.Left, $r.Top, $r.Right, $r.Bottom)
+} else {
+    $bounds = [System.Windows.Forms.SystemInformation]::VirtualScreen
+}
+$bitmap = New-Object System.Drawing.Bitmap $bounds.Width, $bounds.Height
+$graphics = [System.Drawing.Graphics]::FromImage($bitmap)
+$graphics.CopyFromScreen($bounds.Location, [System.Drawing.Point]::Empty, $bounds.Size)
+$bitmap.Save($env:FILETWIN_CAPTURE_PATH, [System.Drawing.Imaging.ImageFormat]::Png)
+"#;
+
+/// Time the user gets to focus the window to capture, since ours has focus on click.
+#[cfg(target_os = "windows")]
+const WINDOW_DELAY: std::time::Duration = std::time::Duration::from_secs(3);
+/// How long to wait for the user to finish a Snip & Sketch selection.
+#[cfg(target_os = "windows")]
+const SNIP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[cfg(target_os = "windows")]
+impl Gdi {
+    /// Snip & Sketch puts its result on the clipboard; wait for a new image to show up.
+    fn capture_region(&self, png: &Path) -> Result<(), String> {
+        use clipboard_rs::common::RustImage;
+        use clipboard_rs::{Clipboard, ClipboardContext};
+
+        let ctx = ClipboardContext::new().map_err(|e| e.to_string())?;
+        let image_bytes = |ctx: &ClipboardContext| {
+            ctx.get_image()
+                .ok()
+                .and_then(|image| image.to_png().ok())
+                .map(|png| png.get_bytes().to_vec())
+        };
+        let before = image_bytes(&ctx);
+        Command::new("explorer")
+            .arg("ms-screenclip:")
+            .status()
+            .map_err(|e| e.to_string())?;
+
+        let started = std::time::Instant::now();
+        while started.elapsed() < SNIP_TIMEOUT {
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            if let Some(bytes) = image_bytes(&ctx).filter(|b| Some(b) != before.as_ref()) {
+                return std::fs::write(png, bytes).map_err(|e| e.to_string());
+            }
+        }
+        Err("Screenshot cancelled or failed".to_string())
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Backend for Gdi {
+    fn available(&self) -> bool {
+        true
+    }
+
+    fn capture(&self, mode: CaptureMode, png: &Path) -> Result<(), String> {
+        let mode = match mode {
+            CaptureMode::Region => return self.capture_region(png),
+            CaptureMode::Window => {
+                std::thread::sleep(WINDOW_DELAY);
+                "window"
+            }
+            CaptureMode::Full => "full",
+        };
+        let mut command = Command::new("powershell");
+        command
+            .args(["-NoProfile", "-NonInteractive", "-Command", GDI_SCRIPT])
+            .env("FILETWIN_CAPTURE_MODE", mode)
+            .env("FILETWIN_CAPTURE_PATH", png);
+        run(&mut command, png)
+    }
+}
+
+/// Backends for this platform, most preferred first.
+#[cfg(target_os = "macos")]
+fn backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(ScreenCapture)]
+}
+
+#[cfg(target_os = "linux")]
+fn backends() -> Vec<Box<dyn Backend>> {
+    vec![
+        Box::new(Grim),
+        Box::new(Spectacle),
+        Box::new(GnomeScreenshot),
+        Box::new(Maim),
+        Box::new(Import),
+    ]
+}
+
+#[cfg(target_os = "windows")]
+fn backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(Gdi)]
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+fn backends() -> Vec<Box<dyn Backend>> {
+    Vec::new()
+}
+
+/// A screenshot taken as PNG, not yet encoded for sending. It lives in a folder of its
+/// own that only we can enter, removed with the PNG when this is dropped.
+pub struct Capture {
+    pub png: PathBuf,
+    stem: String,
+    _dir: TempDir,
+}
+
+fn new_capture_dir() -> Result<TempDir, String> {
+    // Capture tools overwrite whatever is at the path, symlinks included, so it goes in a
+    // new folder only we can write to.
+    tempfile::Builder::new()
+        .prefix("filetwin-capture-")
+        .tempdir()
+        .map_err(|e| e.to_string())
+}
+
+/// Grabs the screen into a temp PNG. Blocks while the user selects a region or window.
+pub fn capture_png(mode: CaptureMode) -> Result<Capture, String> {
+    let backend = backends()
+        .into_iter()
+        .find(|b| b.available())
+        .ok_or("No screenshot tool found. On Linux, install grim, spectacle, gnome-screenshot, maim or ImageMagick.")?;
+
+    let stem = format!("screenshot_{}", Local::now().format("%Y-%m-%d_%H-%M-%S"));
+    let dir = new_capture_dir()?;
+    let png = dir.path().join(format!("{}.png", stem));
+    backend.capture(mode, &png)?;
+    Ok(Capture { png, stem, _dir: dir })
+}
+
+/// Takes full-screen captures over and over for screen sharing, with the backend and the
+/// folder picked once rather than for every frame.
+pub struct ScreenGrabber {
+    backend: Box<dyn Backend>,
+    png: PathBuf,
+    _dir: TempDir,
+}
+
+impl ScreenGrabber {
+    /// Fails when the only screenshot tool here cannot capture quietly.
+    pub fn new() -> Result<ScreenGrabber, String> {
+        let mut available = backends().into_iter().filter(|b| b.available()).peekable();
+        if available.peek().is_none() {
+            return Err("No screenshot tool found. On Linux, install grim, spectacle, maim or ImageMagick.".to_string());
+        }
+        let backend = available.find(|b| b.streams()).ok_or(
+            "The screenshot tool here flashes the screen on every capture. Install grim, spectacle, maim or ImageMagick to share the screen.",
+        )?;
+        let dir = new_capture_dir()?;
+        let png = dir.path().join("frame.png");
+        Ok(ScreenGrabber { backend, png, _dir: dir })
+    }
+
+    /// Captures the whole screen, returning the PNG it was written to. It is overwritten
+    /// by the next grab.
+    pub fn grab(&self) -> Result<&Path, String> {
+        // So a tool that exits without writing is not mistaken for a fresh frame.
+        let _ = std::fs::remove_file(&self.png);
+        self.backend.capture(CaptureMode::Full, &self.png)?;
+        Ok(&self.png)
+    }
+}
+
+/// Applies `edits` and writes the image in the requested format to a new temp file,
+/// returning it and a name to send it under. The file is removed when the path is dropped.
+pub fn encode(
+    capture: &Capture,
+    options: &CaptureOptions,
+    edits: &[Edit],
+) -> Result<(TempPath, String), String> {
+    let mut image = image::open(&capture.png).map_err(|e| e.to_string())?.to_rgba8();
+    annotate::apply(&mut image, edits);
+
+    let ext = match options.format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpg",
+    };
+    let name = format!("{}.{}", capture.stem, ext);
+    let (file, path) = tempfile::Builder::new()
+        .prefix(&format!("{}-", capture.stem))
+        .suffix(&format!(".{ext}"))
+        .tempfile()
+        .map_err(|e| e.to_string())?
+        .into_parts();
+    let mut writer = std::io::BufWriter::new(file);
+    let written = match options.format {
+        ImageFormat::Png => image.write_to(&mut writer, image::ImageFormat::Png),
+        ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut writer, options.quality.clamp(1, 100))
+            .encode_image(&image::DynamicImage::ImageRgba8(image).to_rgb8()),
+    };
+    drop(writer);
+    written.map_err(|e| e.to_string())?;
+    Ok((path, name))
+}
+
+/// Captures and encodes in one go, for sending without editing.
+pub fn capture(options: &CaptureOptions) -> Result<(TempPath, String), String> {
+    let capture = capture_png(options.mode)?;
+    encode(&capture, options, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A capture of a small image, as a backend would leave it.
+    fn fake_capture() -> Capture {
+        let dir = tempfile::Builder::new().prefix("filetwin-capture-").tempdir().unwrap();
+        let png = dir.path().join("screenshot_2026-01-01_00-00-00.png");
+        image::RgbaImage::new(4, 4).save(&png).unwrap();
+        Capture {
+            png,
+            stem: "screenshot_2026-01-01_00-00-00".to_string(),
+            _dir: dir,
+        }
+    }
+
+    #[test]
+    fn captures_in_the_same_second_do_not_share_files() {
+        let first = fake_capture();
+        let second = fake_capture();
+        assert_ne!(first.png, second.png);
+        let png = second.png.clone();
+        drop(first);
+        assert!(png.is_file());
+        drop(second);
+        assert!(!png.exists());
+    }
+
+    #[test]
+    fn encoded_copies_are_separate_and_removed_when_dropped() {
+        let capture = fake_capture();
+        let options = CaptureOptions::default();
+        let (first, name) = encode(&capture, &options, &[]).unwrap();
+        let (second, _) = encode(&capture, &options, &[]).unwrap();
+        assert_eq!(name, "screenshot_2026-01-01_00-00-00.jpg");
+        assert_ne!(*first, *second);
+
+        let kept = second.to_path_buf();
+        drop(first);
+        assert!(kept.is_file());
+        drop(second);
+        assert!(!kept.exists());
+    }
+}
@@ -0,0 +1,347 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::actions::ActionKind;
+use crate::engine::Engine;
+use crate::files;
+use crate::screenshot::CaptureOptions;
+use crate::sensitive::{self, SensitiveRule};
+use crate::sync;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Folder received files are written to without asking. `None` keeps the save dialog.
+    pub download_dir: Option<PathBuf>,
+    /// Send extended attributes along with files. Mode bits and mtime are always sent.
+    pub preserve_xattrs: bool,
+    /// Real-time clipboard sync: push local changes to the peer as they happen and handle
+    /// incoming ones per `clipboard_apply`. When off, incoming items are only shown.
+    pub sync_clipboard: bool,
+    /// What to do with clipboard content received from a peer.
+    pub clipboard_apply: ApplyPolicy,
+    /// Clipboard items larger than this are streamed like a file, or truncated for peers
+    /// that cannot receive one. Incoming items are truncated to it as well.
+    pub clipboard_max_bytes: u64,
+    /// Hold back clipboard content that looks like a secret instead of sending it.
+    pub filter_sensitive: bool,
+    /// Patterns checked when `filter_sensitive` is on.
+    pub sensitive_rules: Vec<SensitiveRule>,
+    /// What to do with links the peer sends with "open on other device".
+    pub open_links: LinkPolicy,
+    /// URL schemes a received link may use; anything else is rejected.
+    pub allowed_url_schemes: Vec<String>,
+    /// Hours a clipboard item or file queued while disconnected waits for the peer.
+    pub outbox_ttl_hours: u64,
+    /// Mode, format and quality used by "Capture screenshot".
+    pub screenshot: CaptureOptions,
+    /// Overrides keyed by peer name (as announced in `Hello`), each tied to the address
+    /// the peer connected from.
+    pub peers: HashMap<String, PeerSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            download_dir: None,
+            preserve_xattrs: false,
+            sync_clipboard: true,
+            // A peer should not be able to replace what the user is about to paste without
+            // being trusted for it first.
+            clipboard_apply: ApplyPolicy::Ask,
+            clipboard_max_bytes: 1024 * 1024,
+            filter_sensitive: true,
+            sensitive_rules: sensitive::default_rules(),
+            open_links: LinkPolicy::Queue,
+            allowed_url_schemes: vec!["http".to_string(), "https".to_string()],
+            outbox_ttl_hours: 24,
+            screenshot: CaptureOptions::default(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyPolicy {
+    /// Write it to the system clipboard straight away.
+    Always,
+    /// Keep it and ask the user, who applies it with `paste_from_remote`.
+    Ask,
+    /// Only show it in the window.
+    ShowOnly,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkPolicy {
+    /// Open it in the default browser straight away.
+    Open,
+    /// Add it to the received links list for the user to open.
+    Queue,
+}
+
+/// Whether a peer may make this machine do something on its behalf.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Ask the local user each time.
+    #[default]
+    Ask,
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerSettings {
+    pub download_dir: Option<PathBuf>,
+    pub clipboard_apply: Option<ApplyPolicy>,
+    /// Overrides `filter_sensitive`, e.g. to turn it off for a trusted machine of your own.
+    pub filter_sensitive: Option<bool>,
+    pub open_links: Option<LinkPolicy>,
+    /// May this peer capture our screen with a remote screenshot request.
+    pub screenshot: Permission,
+    /// Which remote actions this peer may run here; kinds not listed are asked for.
+    pub actions: HashMap<ActionKind, Permission>,
+    /// Address the peer connected from when these were set. Any device can announce any
+    /// name, so the trust granted here is dropped when the name arrives from elsewhere.
+    pub address: Option<IpAddr>,
+}
+
+impl PeerSettings {
+    /// Overrides that let the peer do more than the global settings do.
+    fn grants_trust(&self) -> bool {
+        self.screenshot == Permission::Allow
+            || self.actions.values().any(|p| *p == Permission::Allow)
+            || self.clipboard_apply == Some(ApplyPolicy::Always)
+            || self.filter_sensitive == Some(false)
+            || self.open_links == Some(LinkPolicy::Open)
+    }
+
+    /// Back to asking for everything the peer could do here. Refusals are kept.
+    fn revoke_trust(&mut self) {
+        if self.screenshot == Permission::Allow {
+            self.screenshot = Permission::Ask;
+        }
+        self.actions.retain(|_, p| *p != Permission::Allow);
+        if self.clipboard_apply == Some(ApplyPolicy::Always) {
+            self.clipboard_apply = None;
+        }
+        if self.filter_sensitive == Some(false) {
+            self.filter_sensitive = None;
+        }
+        if self.open_links == Some(LinkPolicy::Open) {
+            self.open_links = None;
+        }
+    }
+}
+
+impl Settings {
+    pub fn peer(&self, peer: Option<&str>) -> Option<&PeerSettings> {
+        peer.and_then(|p| self.peers.get(p))
+    }
+
+    pub fn download_dir_for(&self, peer: Option<&str>) -> Option<PathBuf> {
+        self.peer(peer)
+            .and_then(|p| p.download_dir.clone())
+            .or_else(|| self.download_dir.clone())
+    }
+
+    pub fn clipboard_apply_for(&self, peer: Option<&str>) -> ApplyPolicy {
+        if !self.sync_clipboard {
+            return ApplyPolicy::ShowOnly;
+        }
+        self.peer(peer)
+            .and_then(|p| p.clipboard_apply)
+            .unwrap_or(self.clipboard_apply)
+    }
+
+    pub fn link_policy_for(&self, peer: Option<&str>) -> LinkPolicy {
+        self.peer(peer)
+            .and_then(|p| p.open_links)
+            .unwrap_or(self.open_links)
+    }
+
+    pub fn action_permission_for(&self, peer: Option<&str>, kind: ActionKind) -> Permission {
+        self.peer(peer)
+            .and_then(|p| p.actions.get(&kind).copied())
+            .unwrap_or_default()
+    }
+
+    pub fn filter_sensitive_for(&self, peer: Option<&str>) -> bool {
+        self.peer(peer)
+            .and_then(|p| p.filter_sensitive)
+            .unwrap_or(self.filter_sensitive)
+    }
+
+    /// Ties `peer`'s overrides to `address`, the first time it is seen. Returns true if
+    /// they had been set for another address and the trust they granted was revoked.
+    pub fn bind_peer(&mut self, peer: &str, address: IpAddr) -> bool {
+        let Some(settings) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let moved = settings.address.is_some_and(|a| a != address);
+        let revoked = moved && settings.grants_trust();
+        if moved {
+            settings.revoke_trust();
+        }
+        settings.address = Some(address);
+        revoked
+    }
+}
+
+fn settings_path(engine: &Engine) -> PathBuf {
+    engine.paths().config_dir.join(SETTINGS_FILE)
+}
+
+/// Loads settings from the app config dir, falling back to defaults if missing or unreadable.
+pub fn load(engine: &Engine) -> Settings {
+    std::fs::read(settings_path(engine))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save(engine: &Engine, settings: &Settings) -> Result<(), String> {
+    let path = settings_path(engine);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec_pretty(settings).map_err(|e| e.to_string())?;
+    files::write_atomic(&path, &json)
+}
+
+/// Snapshot of the current settings.
+pub fn current(engine: &Engine) -> Settings {
+    engine
+        .state()
+        .settings
+        .lock()
+        .map(|s| s.clone())
+        .unwrap_or_default()
+}
+
+pub fn get_settings(engine: Engine) -> Result<Settings, String> {
+    Ok(current(&engine))
+}
+
+pub fn set_settings(mut settings: Settings, engine: Engine) -> Result<(), String> {
+    sensitive::validate(&settings.sensitive_rules)?;
+    // Overrides first set for the connected peer belong to where it connected from.
+    if let (Some(peer), Some(address)) = (sync::current_peer(&engine), sync::peer_address(&engine)) {
+        if let Some(peer) = settings.peers.get_mut(&peer) {
+            peer.address.get_or_insert(address);
+        }
+    }
+    save(&engine, &settings)?;
+    let state = engine.state();
+    *state.settings.lock().map_err(|_| "lock")? = settings;
+    Ok(())
+}
+
+/// Records where the peer announcing itself as `peer` connected from, revoking what it
+/// was trusted with if that name was last seen elsewhere. The revocation applies even if
+/// it cannot be saved.
+pub fn bind_peer(engine: &Engine, peer: &str, address: IpAddr) {
+    let state = engine.state();
+    let (revoked, settings) = {
+        let mut settings = state.settings.lock().unwrap();
+        if settings.peer(Some(peer)).is_none_or(|p| p.address == Some(address)) {
+            return;
+        }
+        (settings.bind_peer(peer, address), settings.clone())
+    };
+    let _ = save(engine, &settings);
+    if revoked {
+        engine.emit(
+            "peer_trust_revoked",
+            serde_json::json!({ "peer": peer, "address": address }),
+        );
+    }
+}
+
+/// Sets the auto-save folder, globally or for a single peer.
+pub fn set_download_dir(peer: Option<String>, dir: PathBuf, engine: Engine) -> Result<(), String> {
+    let mut settings = current(&engine);
+    match peer {
+        Some(peer) => settings.peers.entry(peer).or_default().download_dir = Some(dir),
+        None => settings.download_dir = Some(dir),
+    }
+    set_settings(settings, engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusting(address: Option<IpAddr>) -> Settings {
+        let mut settings = Settings::default();
+        let peer = settings.peers.entry("laptop".to_string()).or_default();
+        peer.screenshot = Permission::Allow;
+        peer.actions.insert(ActionKind::LockScreen, Permission::Allow);
+        peer.actions.insert(ActionKind::OpenUrl, Permission::Deny);
+        peer.clipboard_apply = Some(ApplyPolicy::Always);
+        peer.filter_sensitive = Some(false);
+        peer.download_dir = Some(PathBuf::from("/downloads"));
+        peer.address = address;
+        settings
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn received_clipboard_is_only_applied_for_trusted_peers() {
+        let mut settings = Settings::default();
+        assert!(settings.clipboard_apply_for(Some("laptop")) == ApplyPolicy::Ask);
+        assert!(settings.clipboard_apply_for(None) == ApplyPolicy::Ask);
+
+        let peer = settings.peers.entry("laptop".to_string()).or_default();
+        peer.clipboard_apply = Some(ApplyPolicy::Always);
+        assert!(settings.clipboard_apply_for(Some("laptop")) == ApplyPolicy::Always);
+        assert!(settings.clipboard_apply_for(Some("phone")) == ApplyPolicy::Ask);
+    }
+
+    #[test]
+    fn same_name_from_another_address_is_asked_again() {
+        let mut settings = trusting(Some(ip("192.168.1.20")));
+        assert!(settings.bind_peer("laptop", ip("192.168.1.66")));
+
+        let peer = settings.peer(Some("laptop")).unwrap();
+        assert!(peer.screenshot == Permission::Ask);
+        assert!(settings.action_permission_for(Some("laptop"), ActionKind::LockScreen) == Permission::Ask);
+        assert!(settings.action_permission_for(Some("laptop"), ActionKind::OpenUrl) == Permission::Deny);
+        assert!(settings.clipboard_apply_for(Some("laptop")) == settings.clipboard_apply);
+        assert!(settings.filter_sensitive_for(Some("laptop")));
+        assert_eq!(peer.download_dir.as_deref(), Some(std::path::Path::new("/downloads")));
+        assert_eq!(peer.address, Some(ip("192.168.1.66")));
+    }
+
+    #[test]
+    fn same_address_keeps_what_was_granted() {
+        let mut settings = trusting(Some(ip("192.168.1.20")));
+        assert!(!settings.bind_peer("laptop", ip("192.168.1.20")));
+        assert!(settings.peer(Some("laptop")).unwrap().screenshot == Permission::Allow);
+    }
+
+    #[test]
+    fn first_address_seen_is_remembered() {
+        let mut settings = trusting(None);
+        assert!(!settings.bind_peer("laptop", ip("10.0.0.5")));
+        assert!(settings.peer(Some("laptop")).unwrap().screenshot == Permission::Allow);
+        assert!(settings.bind_peer("laptop", ip("10.0.0.6")));
+    }
+
+    #[test]
+    fn unknown_names_are_not_added() {
+        let mut settings = Settings::default();
+        assert!(!settings.bind_peer("stranger", ip("10.0.0.5")));
+        assert!(settings.peers.is_empty());
+    }
+}
@@ -1,142 +1,864 @@
-use base64::Engine;
-use chrono::Local;
+use base64::Engine as _;
 use futures_util::{SinkExt, StreamExt};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use tauri_plugin_dialog::{DialogExt, FilePath};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Mutex;
-use std::time::Duration;
-use tauri::{AppHandle, Emitter, Manager};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_async, connect_async, tungstenite::Message, MaybeTlsStream};
+
+use crate::actions::{self, RemoteAction};
+use crate::chat::{self, Chats};
+use crate::clipboard::{self, ClipContent, ClipFormat, ClipboardWatch, LocalClip};
+use crate::compression;
+use crate::engine::Engine;
+use crate::filename;
+use crate::history::{self, Direction, History};
+use crate::links::{self, ReceivedLink};
+use crate::mirror;
+use crate::outbox::{self, Outbox, OutboxKind};
+use crate::annotate::Edit;
+use crate::screenshot::{self, Capture, CaptureMode, CaptureOptions};
+use crate::sensitive;
+use crate::files::{self, FileMeta};
+use crate::settings::{self, ApplyPolicy, Permission, Settings};
+use crate::transfer::{self, Incoming, TransferStats};
 
 const SERVICE_TYPE: &str = "_remotesync._tcp.local.";
-const WS_PORT: u16 = 18765;
+pub const DEFAULT_PORT: u16 = 18765;
 const CONNECT_TIMEOUT_SECS: u64 = 15;
 const CONNECT_MAX_ATTEMPTS: u32 = 3;
 
-static HOSTING: AtomicBool = AtomicBool::new(false);
-static BROWSING: AtomicBool = AtomicBool::new(false);
+/// Peer understands `FileStart`/`FileChunk`/`FileEnd`.
+const FEATURE_CHUNKED: &str = "chunked";
+/// Peer understands `FileStart { clipboard: true }`.
+const FEATURE_CLIPBOARD_STREAM: &str = "clipboard_stream";
+/// Peer understands `ScreenshotRequest`.
+const FEATURE_REMOTE_SCREENSHOT: &str = "remote_screenshot";
+/// Peer understands `MirrorFrame`/`MirrorAck`/`MirrorStop`.
+pub const FEATURE_MIRROR: &str = "mirror";
+/// Peer understands `Action`/`ActionResult`.
+pub const FEATURE_ACTIONS: &str = "actions";
+/// Peer understands `OpenUrl`.
+pub const FEATURE_OPEN_URL: &str = "open_url";
+/// Peer understands `Chat`/`ChatAck`.
+pub const FEATURE_CHAT: &str = "chat";
+/// Capabilities advertised in our `Hello`.
+const FEATURES: &[&str] = &[
+    FEATURE_CHUNKED,
+    compression::ZSTD,
+    FEATURE_CLIPBOARD_STREAM,
+    FEATURE_REMOTE_SCREENSHOT,
+    FEATURE_MIRROR,
+    FEATURE_ACTIONS,
+    FEATURE_OPEN_URL,
+    FEATURE_CHAT,
+];
+
+/// Received files remembered so a clipboard file list from the peer can point at them.
+const RECENT_FILES: usize = 64;
+/// Copied files larger than this in total are not sent along with the clipboard.
+const MAX_CLIPBOARD_FILES_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Largest clipboard item streamed as a transfer; anything bigger is truncated instead.
+const MAX_STREAMED_CLIPBOARD_BYTES: u64 = 64 * 1024 * 1024;
+/// Name announced for clipboard items sent as a transfer.
+const CLIPBOARD_TRANSFER_NAME: &str = "clipboard.json";
+
+/// Transfers received at once. Each holds an open temp file and reserved disk space, so
+/// further `FileStart`s are rejected until one ends.
+const MAX_INCOMING: usize = 8;
+/// A transfer with no chunk for this long is dropped and its temp file deleted.
+const STALLED_AFTER: Duration = if cfg!(test) {
+    Duration::from_secs(1)
+} else {
+    Duration::from_secs(60)
+};
 
 #[derive(Default)]
 pub struct SyncState {
+    /// From `start_host` until the peer disconnects or hosting is stopped.
+    pub hosting: AtomicBool,
+    /// Port accepting the peer while hosting.
+    pub host_port: Mutex<Option<u16>>,
+    pub browsing: AtomicBool,
     pub host_tx: Mutex<Option<mpsc::Sender<String>>>,
     pub client_tx: Mutex<Option<mpsc::Sender<String>>>,
+    /// Writes what is sent through `client_tx` to the host; ends once that is dropped.
+    pub client_writer: Mutex<Option<JoinHandle<()>>>,
     pub peer_name: Mutex<Option<String>>,
+    /// Where the current connection comes from, which unlike the name the peer cannot pick.
+    pub peer_address: Mutex<Option<IpAddr>>,
     pub browse_receiver: Mutex<Option<mdns_sd::Receiver<ServiceEvent>>>,
     pub daemon: Mutex<Option<ServiceDaemon>>,
     pub service_info: Mutex<Option<ServiceInfo>>,
+    /// Peers found by the last browse.
+    pub peers: Mutex<Vec<Peer>>,
+    pub settings: Mutex<Settings>,
+    /// Capabilities from the peer's `Hello`; empty for peers that predate feature negotiation.
+    pub peer_features: Mutex<Vec<String>>,
+    /// In-flight chunked transfers by id.
+    pub incoming: Mutex<HashMap<String, InFlight>>,
+    /// Received files waiting in the staging folder for the user to pick a location.
+    pub staged: Mutex<HashMap<String, StagedFile>>,
+    pub clipboard: Mutex<ClipboardWatch>,
+    /// Recently received files as (sanitized announced name, final path), newest last.
+    pub recent_files: Mutex<VecDeque<(String, PathBuf)>>,
+    /// Last clipboard content received from the peer, for "paste from remote".
+    pub last_remote_clip: Mutex<Option<ClipContent>>,
+    pub history: Mutex<History>,
+    /// Screenshots captured for editing, by id, until sent or discarded.
+    pub pending_shots: Mutex<HashMap<String, Capture>>,
+    /// Screenshot requests from the peer waiting for the local user to answer.
+    pub screenshot_requests: Mutex<HashMap<String, Option<CaptureMode>>>,
+    /// Remote actions from the peer waiting for the local user to answer.
+    pub action_requests: Mutex<HashMap<String, actions::PendingRequest>>,
+    /// Actions we asked the peer to run, until it answers.
+    pub sent_actions: Mutex<HashMap<String, RemoteAction>>,
+    /// Links from the peer waiting to be opened, oldest first.
+    pub links: Mutex<Vec<ReceivedLink>>,
+    pub chats: Mutex<Chats>,
+    /// Items waiting for a peer to reconnect.
+    pub outbox: Mutex<Outbox>,
+    /// Queued items are being sent to the peer.
+    pub delivering: AtomicBool,
+    /// The local clipboard is being watched for changes.
+    pub watching: AtomicBool,
+    pub sharing: mirror::Sharing,
+    /// Sensitive-content rules from the settings, compiled.
+    pub sensitive_rules: Mutex<sensitive::CompiledRules>,
+}
+
+/// Chunks are written on a blocking thread while the transfer is locked, so a large chunk
+/// never holds up the message loop or the state locks.
+pub type SharedIncoming = Arc<tokio::sync::Mutex<Incoming>>;
+
+/// A chunked transfer being received.
+pub struct InFlight {
+    transfer: SharedIncoming,
+    dest: Destination,
+    /// When its last chunk arrived, or it started.
+    last_active: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Destination {
+    /// Staging folder, until the user picks a location.
+    Staged,
+    /// The download folder.
+    Saved,
+    /// An oversized clipboard item, read back and applied once complete.
+    Clipboard,
+}
+
+#[derive(Clone)]
+pub struct StagedFile {
+    pub name: String,
+    pub path: PathBuf,
+    pub meta: Option<FileMeta>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
-enum WsMessage {
-    Hello { name: String },
-    Clipboard { text: String },
-    File { name: String, data: String },
+pub enum WsMessage {
+    Hello {
+        name: String,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    Clipboard {
+        text: String,
+        /// Richer representations of the same item; `text` stays the plain-text fallback.
+        #[serde(default)]
+        formats: Vec<ClipFormat>,
+        /// The sender cut the item down to fit its size limit.
+        #[serde(default)]
+        truncated: bool,
+    },
+    /// Whole file in one frame. Only sent to peers without the `chunked` feature.
+    File {
+        name: String,
+        data: String,
+        /// Decoded size in bytes, used for disk-space preflight and verification.
+        #[serde(default)]
+        size: Option<u64>,
+        #[serde(default)]
+        meta: Option<FileMeta>,
+    },
     BringToFront,
+    /// A link for the peer to open or keep, depending on its link policy.
+    OpenUrl {
+        url: String,
+        #[serde(default)]
+        title: Option<String>,
+    },
+    FileStart {
+        id: String,
+        name: String,
+        size: u64,
+        #[serde(default)]
+        meta: Option<FileMeta>,
+        /// Codec used for chunks flagged `compressed`, if any.
+        #[serde(default)]
+        compression: Option<String>,
+        /// The transfer carries a serialized clipboard item too large for one frame.
+        #[serde(default)]
+        clipboard: bool,
+    },
+    FileChunk {
+        id: String,
+        data: String,
+        #[serde(default)]
+        compressed: bool,
+    },
+    FileEnd { id: String },
+    FileAbort { id: String },
+    /// Asks the peer to capture its screen and send it back as a file.
+    ScreenshotRequest {
+        id: String,
+        #[serde(default)]
+        mode: Option<CaptureMode>,
+    },
+    /// The peer refused or failed a `ScreenshotRequest`.
+    ScreenshotDeclined { id: String, reason: String },
+    /// One frame of a screen share: a base64 JPEG.
+    MirrorFrame {
+        seq: u64,
+        width: u32,
+        height: u32,
+        data: String,
+    },
+    /// The viewer has shown frame `seq`; the sharer paces itself on these.
+    MirrorAck { seq: u64 },
+    MirrorStop,
+    /// Asks the peer to run an action, subject to its per-peer permissions.
+    Action { id: String, action: RemoteAction },
+    /// Outcome of an `Action`; `error` is set if it was declined or failed.
+    ActionResult {
+        id: String,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// A chat message. Resent until acknowledged, so receivers drop duplicate ids.
+    Chat { id: String, text: String, timestamp: i64 },
+    /// The peer received (or with `read`, has seen) these chat messages.
+    ChatAck {
+        ids: Vec<String>,
+        #[serde(default)]
+        read: bool,
+    },
+}
+
+/// Parses one text frame. Anything that is not a message we know is ignored, so newer
+/// peers can add message types.
+pub fn decode(text: &str) -> Option<WsMessage> {
+    serde_json::from_str(text).ok()
 }
 
-fn emit_connected(app: &AppHandle, name: &str) {
-    let _ = app.emit("connected", serde_json::json!({ "name": name }));
+fn emit_connected(engine: &Engine, name: &str) {
+    engine.emit("connected", serde_json::json!({ "name": name }));
 }
 
-fn emit_disconnected(app: &AppHandle) {
-    let _ = app.emit("disconnected", ());
+fn emit_disconnected(engine: &Engine) {
+    engine.emit("disconnected", ());
 }
 
-fn emit_peers(app: &AppHandle, peers: Vec<Peer>) {
-    let _ = app.emit("peers", peers);
+fn emit_peers(engine: &Engine, peers: Vec<Peer>) {
+    *engine.state().peers.lock().unwrap() = peers.clone();
+    engine.emit("peers", peers);
 }
 
-fn emit_remote_clipboard(app: &AppHandle, text: &str) {
-    let _ = app.emit("remote_clipboard", serde_json::json!({ "text": text }));
+/// `policy` tells the UI whether the item was applied, needs confirmation or is just shown.
+fn emit_remote_clipboard(engine: &Engine, content: &ClipContent, policy: ApplyPolicy) {
+    engine.emit(
+        "remote_clipboard",
+        serde_json::json!({
+            "text": content.text,
+            "formats": content.format_names(),
+            "policy": policy,
+        }),
+    );
 }
 
-fn emit_remote_file(app: &AppHandle, name: &str, data: &str) {
-    let _ = app.emit(
+/// `path` is set when the file was auto-saved; otherwise it is staged under `id` until
+/// `save_received_file` is called.
+fn emit_remote_file(engine: &Engine, id: &str, name: &str, path: Option<&Path>) {
+    engine.emit(
         "remote_file",
-        serde_json::json!({ "name": name, "data": data }),
+        serde_json::json!({ "id": id, "name": name, "path": path }),
+    );
+}
+
+fn emit_transfer_stats(engine: &Engine, stats: &TransferStats) {
+    engine.emit("transfer_stats", stats);
+}
+
+fn emit_file_rejected(engine: &Engine, name: &str, reason: &str) {
+    engine.emit(
+        "file_rejected",
+        serde_json::json!({ "name": name, "reason": reason }),
+    );
+}
+
+/// Clipboard content that was held back by the sensitive-content filter.
+fn emit_clipboard_redacted(engine: &Engine, reason: &str) {
+    engine.emit(
+        "clipboard_redacted",
+        serde_json::json!({ "reason": reason, "peer": current_peer(engine) }),
+    );
+}
+
+/// A clipboard item cut down to `limit` bytes, on either side of the connection.
+fn emit_clipboard_truncated(engine: &Engine, size: u64, limit: u64, sent: bool) {
+    engine.emit(
+        "clipboard_truncated",
+        serde_json::json!({ "size": size, "limit": limit, "sent": sent }),
     );
 }
 
-fn emit_bring_to_front(app: &AppHandle) {
-    let _ = app.emit("bring_to_front", ());
+/// The peer wants our screen and the user has not decided for this peer yet.
+fn emit_screenshot_requested(engine: &Engine, id: &str, mode: Option<CaptureMode>) {
+    engine.emit(
+        "screenshot_requested",
+        serde_json::json!({ "id": id, "peer": current_peer(engine), "mode": mode }),
+    );
 }
 
-#[derive(Clone, Serialize)]
+fn emit_screenshot_declined(engine: &Engine, id: &str, reason: &str) {
+    engine.emit(
+        "screenshot_declined",
+        serde_json::json!({ "id": id, "reason": reason }),
+    );
+}
+
+fn emit_bring_to_front(engine: &Engine) {
+    engine.emit("bring_to_front", ());
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub name: String,
     pub host: String,
     pub port: u16,
 }
 
-fn send_via_tx(tx: &Mutex<Option<mpsc::Sender<String>>>, msg: &WsMessage) -> Result<(), String> {
-    let json = serde_json::to_string(msg).map_err(|e| e.to_string())?;
-    let guard = tx.lock().map_err(|_| "lock failed")?;
-    if let Some(sender) = guard.as_ref() {
-        tauri::async_runtime::block_on(async move {
-            sender.send(json).await.map_err(|_| "send failed".to_string())
+async fn handle_message(engine: &Engine, msg: WsMessage) {
+    match msg {
+        WsMessage::Hello { name, features } => {
+            let state = engine.state();
+            *state.peer_name.lock().unwrap() = Some(name.clone());
+            *state.peer_features.lock().unwrap() = features;
+            if let Some(address) = peer_address(engine) {
+                settings::bind_peer(engine, &name, address);
+            }
+            emit_connected(engine, &name);
+            chat::flush(engine).await;
+            outbox::deliver(engine, &name);
+        }
+        WsMessage::Clipboard { text, formats, truncated } => {
+            receive_inline_clipboard(engine, ClipContent { text, formats }, truncated).await
+        }
+        WsMessage::File { name, data, size, meta } => {
+            receive_file(engine, name, data, size, meta).await
+        }
+        WsMessage::BringToFront => emit_bring_to_front(engine),
+        WsMessage::OpenUrl { url, title } => links::receive_url(engine, url, title),
+        WsMessage::FileStart { id, name, size, meta, compression, clipboard } => {
+            receive_file_start(engine, id, name, size, meta, compression, clipboard)
+        }
+        WsMessage::FileChunk { id, data, compressed } => {
+            receive_file_chunk(engine, &id, data, compressed).await
+        }
+        WsMessage::FileEnd { id } => receive_file_end(engine, &id).await,
+        WsMessage::FileAbort { id } => {
+            let state = engine.state();
+            state.incoming.lock().unwrap().remove(&id);
+        }
+        WsMessage::ScreenshotRequest { id, mode } => receive_screenshot_request(engine, id, mode).await,
+        WsMessage::ScreenshotDeclined { id, reason } => emit_screenshot_declined(engine, &id, &reason),
+        WsMessage::MirrorFrame { seq, width, height, data } => {
+            mirror::receive_frame(engine, seq, width, height, data).await
+        }
+        WsMessage::MirrorAck { seq } => mirror::receive_ack(engine, seq),
+        WsMessage::MirrorStop => mirror::receive_stop(engine),
+        WsMessage::Action { id, action } => actions::receive_action(engine, id, action).await,
+        WsMessage::ActionResult { id, error } => actions::receive_result(engine, id, error),
+        WsMessage::Chat { id, text, timestamp } => chat::receive_chat(engine, id, text, timestamp).await,
+        WsMessage::ChatAck { ids, read } => chat::receive_ack(engine, ids, read),
+    }
+}
+
+fn our_hello(engine: &Engine) -> WsMessage {
+    WsMessage::Hello {
+        name: engine.name().to_string(),
+        features: FEATURES.iter().map(|f| f.to_string()).collect(),
+    }
+}
+
+pub fn peer_supports(engine: &Engine, feature: &str) -> bool {
+    engine
+        .state()
+        .peer_features
+        .lock()
+        .map(|features| features.iter().any(|f| f == feature))
+        .unwrap_or(false)
+}
+
+/// Forgets the peer tied to the current connection, dropping (and so deleting) any
+/// half-received files.
+fn clear_peer(state: &SyncState) {
+    *state.peer_name.lock().unwrap() = None;
+    *state.peer_address.lock().unwrap() = None;
+    state.peer_features.lock().unwrap().clear();
+    state.incoming.lock().unwrap().clear();
+    state.screenshot_requests.lock().unwrap().clear();
+    state.action_requests.lock().unwrap().clear();
+    state.sent_actions.lock().unwrap().clear();
+}
+
+pub fn is_connected(engine: &Engine) -> bool {
+    let state = engine.state();
+    state.host_tx.lock().map(|g| g.is_some()).unwrap_or(false)
+        || state.client_tx.lock().map(|g| g.is_some()).unwrap_or(false)
+}
+
+/// Peers found while browsing, as last reported in the `peers` event.
+pub fn list_peers(engine: &Engine) -> Vec<Peer> {
+    engine.state().peers.lock().map(|p| p.clone()).unwrap_or_default()
+}
+
+pub fn current_peer(engine: &Engine) -> Option<String> {
+    engine.state().peer_name.lock().ok().and_then(|g| g.clone())
+}
+
+/// Address of the connected peer.
+pub fn peer_address(engine: &Engine) -> Option<IpAddr> {
+    *engine.state().peer_address.lock().ok()?
+}
+
+/// Where received files go: the peer's or global download folder, or the staging folder
+/// if the user wants to be asked.
+fn receive_dir(engine: &Engine) -> (PathBuf, bool) {
+    let peer = current_peer(engine);
+    match settings::current(engine).download_dir_for(peer.as_deref()) {
+        Some(dir) => (dir, true),
+        None => (staging_dir(engine), false),
+    }
+}
+
+pub fn staging_dir(engine: &Engine) -> PathBuf {
+    engine.paths().cache_dir.join("incoming")
+}
+
+/// Reports a file that has landed in `dir`, staging it for the save dialog if it was not
+/// auto-saved.
+fn file_received(
+    engine: &Engine,
+    id: &str,
+    announced: &str,
+    path: PathBuf,
+    auto_saved: bool,
+    meta: Option<FileMeta>,
+) {
+    let state = engine.state();
+    {
+        // Released before emitting, as event sinks may look files up again.
+        let mut recent = state.recent_files.lock().unwrap();
+        if recent.len() == RECENT_FILES {
+            recent.pop_front();
+        }
+        recent.push_back((announced.to_string(), path.clone()));
+    }
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if auto_saved {
+        return emit_remote_file(engine, id, &name, Some(&path));
+    }
+    state.staged.lock().unwrap().insert(
+        id.to_string(),
+        StagedFile { name: name.clone(), path, meta },
+    );
+    emit_remote_file(engine, id, &name, None);
+}
+
+/// Local path of the most recent file the peer sent under `name`.
+pub fn received_file(engine: &Engine, name: &str) -> Option<PathBuf> {
+    let name = filename::sanitize_file_name(name).ok()?;
+    let recent = engine.state().recent_files.lock().unwrap();
+    recent
+        .iter()
+        .rev()
+        .find(|(announced, _)| *announced == name)
+        .map(|(_, path)| path.clone())
+}
+
+/// Local paths of the files a peer's clipboard refers to, matched against what it sent
+/// us just before. Names we did not receive are skipped.
+fn local_clip_files(engine: &Engine, content: &ClipContent) -> Vec<PathBuf> {
+    content
+        .formats
+        .iter()
+        .filter_map(|f| match f {
+            ClipFormat::Files { names } => Some(names),
+            _ => None,
         })
+        .flatten()
+        .filter_map(|name| received_file(engine, name))
+        .collect()
+}
+
+/// Puts content on the local clipboard without the watcher sending it back to the peer.
+/// Falls back to plain text through the clipboard plugin if the rich write fails.
+async fn apply_clip(engine: &Engine, content: ClipContent, files: Vec<PathBuf>) -> Result<(), String> {
+    clipboard::note_remote(engine, &content);
+    let text = content.text.clone();
+    let written =
+        engine.spawn_blocking(move || clipboard::write_local(&content, &files))
+            .await
+            .map_err(|e| e.to_string())?;
+    match written {
+        Err(_) if !text.is_empty() => engine.desktop().write_text(&text),
+        result => result,
+    }
+}
+
+/// `truncated` is set when the sender already cut the item down. Inline items over our
+/// own limit are cut down here too; larger ones should have come as a transfer.
+async fn receive_inline_clipboard(engine: &Engine, mut content: ClipContent, truncated: bool) {
+    let limit = settings::current(engine).clipboard_max_bytes;
+    let size = content.size() as u64;
+    if content.truncate(limit as usize) || truncated {
+        emit_clipboard_truncated(engine, size, limit, false);
+    }
+    receive_clipboard(engine, content).await
+}
+
+async fn receive_clipboard(engine: &Engine, content: ClipContent) {
+    let state = engine.state();
+    *state.last_remote_clip.lock().unwrap() = Some(content.clone());
+    let peer = current_peer(engine);
+    let files = local_clip_files(engine, &content);
+    history::record(engine, Direction::Received, peer.clone(), &content, &files);
+
+    let mut policy = settings::current(engine).clipboard_apply_for(peer.as_deref());
+    if policy == ApplyPolicy::Always && apply_clip(engine, content.clone(), files).await.is_err() {
+        // Could not write it; leave it for the user to retry.
+        policy = ApplyPolicy::Ask;
+    }
+    emit_remote_clipboard(engine, &content, policy);
+}
+
+/// Handles the single-frame `File` message sent by peers without chunked transfers.
+async fn receive_file(
+    engine: &Engine,
+    name: String,
+    data: String,
+    size: Option<u64>,
+    meta: Option<FileMeta>,
+) {
+    let name = match filename::sanitize_file_name(&name) {
+        Ok(safe) => safe,
+        Err(reason) => return emit_file_rejected(engine, &name, &reason),
+    };
+    let (dir, auto_saved) = receive_dir(engine);
+    if let Some(size) = size {
+        if let Err(reason) = files::check_free_space(&dir, size) {
+            return emit_file_rejected(engine, &name, &reason);
+        }
+    }
+
+    let bytes = match base64::engine::general_purpose::STANDARD.decode(&data) {
+        Ok(bytes) => bytes,
+        Err(_) => return emit_file_rejected(engine, &name, "Corrupt file data"),
+    };
+    if let Some(size) = size {
+        if bytes.len() as u64 != size {
+            let reason = format!("Received {} bytes, expected {}", bytes.len(), size);
+            return emit_file_rejected(engine, &name, &reason);
+        }
+    }
+
+    match files::save_to_dir(&dir, &name, &bytes, meta.as_ref()).await {
+        Ok(path) => file_received(engine, &transfer::new_id(), &name, path, auto_saved, meta),
+        Err(reason) => emit_file_rejected(engine, &name, &reason),
+    }
+}
+
+fn receive_file_start(
+    engine: &Engine,
+    id: String,
+    name: String,
+    size: u64,
+    meta: Option<FileMeta>,
+    compression: Option<String>,
+    clipboard: bool,
+) {
+    let name = match filename::sanitize_file_name(&name) {
+        Ok(safe) => safe,
+        Err(reason) => return emit_file_rejected(engine, &name, &reason),
+    };
+    if clipboard && size > MAX_STREAMED_CLIPBOARD_BYTES {
+        return emit_file_rejected(engine, &name, "Clipboard item too large");
+    }
+    let (dir, dest) = if clipboard {
+        (staging_dir(engine), Destination::Clipboard)
     } else {
-        Err("Not connected".to_string())
+        let (dir, auto_saved) = receive_dir(engine);
+        (dir, if auto_saved { Destination::Saved } else { Destination::Staged })
+    };
+    if engine.state().incoming.lock().unwrap().len() >= MAX_INCOMING {
+        return emit_file_rejected(engine, &name, "Too many transfers in progress");
+    }
+    match Incoming::start(&dir, name.clone(), size, meta, compression) {
+        Ok(transfer) => {
+            let in_flight = InFlight {
+                transfer: Arc::new(tokio::sync::Mutex::new(transfer)),
+                dest,
+                last_active: Instant::now(),
+            };
+            let state = engine.state();
+            state.incoming.lock().unwrap().insert(id.clone(), in_flight);
+            engine.spawn(drop_when_stalled(engine.clone(), id));
+        }
+        Err(reason) => emit_file_rejected(engine, &name, &reason),
     }
 }
 
-pub async fn start_host(app: AppHandle) -> Result<(), String> {
-    if HOSTING.swap(true, Ordering::SeqCst) {
-        return Err("Already hosting".to_string());
+/// Drops transfer `id`, deleting its temp file, once no chunk has arrived for
+/// `STALLED_AFTER`. Returns when the transfer ends either way.
+async fn drop_when_stalled(engine: Engine, id: String) {
+    loop {
+        let idle = match engine.state().incoming.lock().unwrap().get(&id) {
+            Some(in_flight) => in_flight.last_active.elapsed(),
+            None => return,
+        };
+        if idle < STALLED_AFTER {
+            tokio::time::sleep(STALLED_AFTER - idle).await;
+            continue;
+        }
+        let stalled = engine.state().incoming.lock().unwrap().remove(&id);
+        if let Some(in_flight) = stalled {
+            let name = in_flight.transfer.lock().await.name.clone();
+            // Deletes the temp file, unless a chunk being written still holds it.
+            drop(in_flight);
+            emit_file_rejected(&engine, &name, "Transfer stalled");
+        }
+        return;
+    }
+}
+
+async fn receive_file_chunk(engine: &Engine, id: &str, data: String, compressed: bool) {
+    let transfer = engine.state().incoming.lock().unwrap().get_mut(id).map(|in_flight| {
+        in_flight.last_active = Instant::now();
+        in_flight.transfer.clone()
+    });
+    let Some(transfer) = transfer else {
+        return;
+    };
+    let mut transfer = transfer.lock_owned().await;
+    let name = transfer.name.clone();
+    let written = engine.spawn_blocking(move || transfer.write_encoded(&data, compressed))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    if let Err(reason) = written {
+        if engine.state().incoming.lock().unwrap().remove(id).is_some() {
+            emit_file_rejected(engine, &name, &reason);
+        }
     }
+}
 
-    let host_name = hostname::get()
-        .map(|h| h.to_string_lossy().into_owned())
-        .unwrap_or_else(|_| "Mac".to_string());
+async fn receive_file_end(engine: &Engine, id: &str) {
+    let Some(InFlight { transfer, dest, .. }) = engine.state().incoming.lock().unwrap().remove(id)
+    else {
+        return;
+    };
+    // Chunks are handled one at a time, so no write still holds the transfer.
+    let Ok(transfer) = Arc::try_unwrap(transfer).map(tokio::sync::Mutex::into_inner) else {
+        return;
+    };
+    let name = transfer.name.clone();
+    let meta = transfer.meta.clone();
+    let stats = TransferStats::new(id, &name, transfer.size, transfer.wire_bytes());
+    let finished = engine.spawn_blocking(move || transfer.finish())
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match finished {
+        Ok(path) if dest == Destination::Clipboard => receive_clipboard_transfer(engine, &path).await,
+        Ok(path) => {
+            emit_transfer_stats(engine, &stats);
+            file_received(engine, id, &name, path, dest == Destination::Saved, meta);
+        }
+        Err(reason) => emit_file_rejected(engine, &name, &reason),
+    }
+}
 
-    let listener = TcpListener::bind(("0.0.0.0", WS_PORT))
+/// Reads back a clipboard item that arrived as a transfer and handles it like an inline one.
+async fn receive_clipboard_transfer(engine: &Engine, path: &Path) {
+    let content = tokio::fs::read(path)
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<ClipContent>(&bytes).ok());
+    let _ = tokio::fs::remove_file(path).await;
+    if let Some(content) = content {
+        receive_clipboard(engine, content).await;
+    }
+}
+
+pub async fn send_message(engine: &Engine, msg: &WsMessage) -> Result<(), String> {
+    let state = engine.state();
+    let host = state.host_tx.lock().ok().and_then(|g| g.clone());
+    let tx = host.or_else(|| state.client_tx.lock().ok().and_then(|g| g.clone()));
+    let tx = tx.ok_or("Not connected")?;
+    let json = serde_json::to_string(msg).map_err(|e| e.to_string())?;
+    tx.send(json).await.map_err(|_| "Send failed".to_string())
+}
+
+/// Streams the file at `path` to the peer in chunks, compressing them when negotiated and
+/// worthwhile. Falls back to a single `File` frame for peers without chunked transfers.
+pub async fn send_file(engine: &Engine, path: &Path, name: String, meta: Option<FileMeta>) -> Result<(), String> {
+    if !peer_supports(engine, FEATURE_CHUNKED) {
+        let bytes = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+        let data = base64::engine::general_purpose::STANDARD.encode(&bytes);
+        let msg = WsMessage::File {
+            name,
+            data,
+            size: Some(bytes.len() as u64),
+            meta,
+        };
+        return send_message(engine, &msg).await;
+    }
+
+    stream_file(engine, path, name, meta, false).await
+}
+
+/// Sends `path` as a chunked transfer. `clipboard` marks a serialized clipboard item.
+async fn stream_file(
+    engine: &Engine,
+    path: &Path,
+    name: String,
+    meta: Option<FileMeta>,
+    clipboard: bool,
+) -> Result<(), String> {
+    let mut file = tokio::fs::File::open(path)
         .await
         .map_err(|e| e.to_string())?;
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let mut buf = vec![0u8; transfer::CHUNK_SIZE];
+    let mut len = transfer::read_chunk(&mut file, &mut buf).await?;
+
+    let compress = peer_supports(engine, compression::ZSTD)
+        && compression::worth_compressing(&name, &buf[..len]);
+    let id = transfer::new_id();
+    let start = WsMessage::FileStart {
+        id: id.clone(),
+        name: name.clone(),
+        size,
+        meta,
+        compression: compress.then(|| compression::ZSTD.to_string()),
+        clipboard,
+    };
+    send_message(engine, &start).await?;
+
+    let mut wire_bytes = 0u64;
+    while len > 0 {
+        let (data, compressed, payload_len) = transfer::encode_chunk(&buf[..len], compress);
+        wire_bytes += payload_len as u64;
+        let chunk = WsMessage::FileChunk { id: id.clone(), data, compressed };
+        send_message(engine, &chunk).await?;
+
+        len = match transfer::read_chunk(&mut file, &mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = send_message(engine, &WsMessage::FileAbort { id }).await;
+                return Err(e);
+            }
+        };
+    }
+    send_message(engine, &WsMessage::FileEnd { id: id.clone() }).await?;
+
+    emit_transfer_stats(engine, &TransferStats::new(&id, &name, size, wire_bytes));
+    Ok(())
+}
+
+pub async fn start_host(engine: Engine) -> Result<(), String> {
+    start_host_on(DEFAULT_PORT, true, engine).await.map(drop)
+}
 
+/// Announces the listening port over mDNS.
+fn advertise(engine: &Engine, port: u16) -> Result<(), String> {
+    let host_name = crate::engine::host_name();
     let local_ip = local_ip_address::local_ip()
         .map(|ip| ip.to_string())
         .unwrap_or_else(|_| "127.0.0.1".to_string());
     let host_domain = format!("{}.local.", host_name);
 
     let daemon = ServiceDaemon::new().map_err(|e| e.to_string())?;
-    let service_name = format!("RemoteSync-{}", host_name);
+    let service_name = format!("RemoteSync-{}", engine.name());
     let service_info = ServiceInfo::new(
         SERVICE_TYPE,
         &service_name,
         &host_domain,
         local_ip.as_str(),
-        WS_PORT,
+        port,
         &[] as &[(&str, &str)],
     )
     .map_err(|e| e.to_string())?
     .enable_addr_auto();
     daemon.register(service_info.clone()).map_err(|e| e.to_string())?;
 
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.daemon.lock().map_err(|_| "lock")? = Some(daemon);
-        *state.service_info.lock().map_err(|_| "lock")? = Some(service_info);
+    let state = engine.state();
+    *state.daemon.lock().map_err(|_| "lock")? = Some(daemon);
+    *state.service_info.lock().map_err(|_| "lock")? = Some(service_info);
+    Ok(())
+}
+
+async fn listen(port: u16, advertised: bool, engine: &Engine) -> Result<(TcpListener, u16), String> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|e| e.to_string())?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    if advertised {
+        advertise(engine, port)?;
     }
+    *engine.state().host_port.lock().map_err(|_| "lock")? = Some(port);
+    Ok((listener, port))
+}
 
-    let app_accept = app.clone();
-    tauri::async_runtime::spawn(async move {
-        if let Ok((stream, _)) = listener.accept().await {
+/// Waits for one peer on `port`, or on any free port if it is 0, and returns the port.
+/// Unless `advertised`, peers only find it by being told the port.
+pub async fn start_host_on(port: u16, advertised: bool, engine: Engine) -> Result<u16, String> {
+    let state = engine.state();
+    if state.hosting.swap(true, Ordering::SeqCst) {
+        return Err("Already hosting".to_string());
+    }
+    let (listener, port) = match listen(port, advertised, &engine).await {
+        Ok(listening) => listening,
+        Err(e) => {
+            state.hosting.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+    };
+
+    let engine_accept = engine.clone();
+    engine.spawn(async move {
+        if let Ok((stream, address)) = listener.accept().await {
             if let Ok(ws) = accept_async(stream).await {
                 let (mut write, mut read) = ws.split();
                 let (tx, mut rx) = mpsc::channel::<String>(32);
 
-                if let Some(state) = app_accept.try_state::<SyncState>() {
-                    *state.host_tx.lock().unwrap() = Some(tx.clone());
-                }
+                let state = engine_accept.state();
+                *state.host_tx.lock().unwrap() = Some(tx.clone());
+                *state.peer_address.lock().unwrap() = Some(address.ip());
 
-                tauri::async_runtime::spawn(async move {
+                engine_accept.spawn(async move {
                     while let Some(msg) = rx.recv().await {
                         if write.send(Message::Text(msg)).await.is_err() {
                             break;
@@ -146,66 +868,68 @@ pub async fn start_host(app: AppHandle) -> Result<(), String> {
 
                 while let Some(Ok(msg)) = read.next().await {
                     if let Message::Text(text) = msg {
-                        if let Ok(parsed) = serde_json::from_str::<WsMessage>(&text) {
-                            match parsed {
-                                WsMessage::Hello { name } => {
-                                    if let Some(state) = app_accept.try_state::<SyncState>() {
-                                        *state.peer_name.lock().unwrap() = Some(name.clone());
-                                    }
-                                    emit_connected(&app_accept, &name);
+                        if let Some(parsed) = decode(&text) {
+                            if let WsMessage::Hello { .. } = parsed {
+                                if let Ok(json) = serde_json::to_string(&our_hello(&engine_accept)) {
+                                    let _ = tx.send(json).await;
                                 }
-                                WsMessage::Clipboard { text: t } => emit_remote_clipboard(&app_accept, &t),
-                                WsMessage::File { name, data } => emit_remote_file(&app_accept, &name, &data),
-                                WsMessage::BringToFront => emit_bring_to_front(&app_accept),
-                                _ => {}
                             }
+                            handle_message(&engine_accept, parsed).await;
                         }
                     }
                 }
 
-                if let Some(state) = app_accept.try_state::<SyncState>() {
-                    *state.host_tx.lock().unwrap() = None;
-                    *state.peer_name.lock().unwrap() = None;
-                }
-                emit_disconnected(&app_accept);
+                let state = engine_accept.state();
+                *state.host_tx.lock().unwrap() = None;
+                clear_peer(state);
+                emit_disconnected(&engine_accept);
             }
         }
-        HOSTING.store(false, Ordering::SeqCst);
+        let state = engine_accept.state();
+        if let Ok(mut host_port) = state.host_port.lock() {
+            *host_port = None;
+        }
+        state.hosting.store(false, Ordering::SeqCst);
     });
 
-    Ok(())
+    Ok(port)
 }
 
-pub async fn stop_host(app: AppHandle) -> Result<(), String> {
-    HOSTING.store(false, Ordering::SeqCst);
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.host_tx.lock().map_err(|_| "lock")? = None;
-        *state.daemon.lock().map_err(|_| "lock")? = None;
-        *state.service_info.lock().map_err(|_| "lock")? = None;
-    }
+/// True from `start_host` until a peer that connected disconnects or hosting is stopped.
+pub fn is_hosting(engine: &Engine) -> bool {
+    engine.state().hosting.load(Ordering::SeqCst)
+}
+
+/// The port peers connect to while hosting.
+pub fn host_port(engine: &Engine) -> Option<u16> {
+    *engine.state().host_port.lock().ok()?
+}
+
+pub async fn stop_host(engine: Engine) -> Result<(), String> {
+    let state = engine.state();
+    state.hosting.store(false, Ordering::SeqCst);
+    *state.host_tx.lock().map_err(|_| "lock")? = None;
+    *state.daemon.lock().map_err(|_| "lock")? = None;
+    *state.service_info.lock().map_err(|_| "lock")? = None;
     Ok(())
 }
 
-pub async fn start_browse(app: AppHandle) -> Result<(), String> {
-    if BROWSING.swap(true, Ordering::SeqCst) {
+pub async fn start_browse(engine: Engine) -> Result<(), String> {
+    if engine.state().browsing.swap(true, Ordering::SeqCst) {
         return Ok(());
     }
 
     let daemon = ServiceDaemon::new().map_err(|e| e.to_string())?;
     let receiver = daemon.browse(SERVICE_TYPE).map_err(|e| e.to_string())?;
 
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.daemon.lock().map_err(|_| "lock")? = Some(daemon);
-        *state.browse_receiver.lock().map_err(|_| "lock")? = Some(receiver);
-    }
+    let state = engine.state();
+    *state.daemon.lock().map_err(|_| "lock")? = Some(daemon);
+    *state.browse_receiver.lock().map_err(|_| "lock")? = Some(receiver);
 
-    let app_browse = app.clone();
+    let engine_browse = engine.clone();
     std::thread::spawn(move || {
-        let state = match app_browse.try_state::<SyncState>() {
-            Some(s) => s,
-            None => return,
-        };
-        let mut guard = match state.browse_receiver.lock() {
+        let state = engine_browse.state();
+        let guard = match state.browse_receiver.lock() {
             Ok(g) => g,
             Err(_) => return,
         };
@@ -216,7 +940,7 @@ pub async fn start_browse(app: AppHandle) -> Result<(), String> {
         drop(guard);
 
         let mut peers: HashMap<String, Peer> = HashMap::new();
-        while BROWSING.load(Ordering::SeqCst) {
+        while state.browsing.load(Ordering::SeqCst) {
             if let Ok(event) = receiver.recv_timeout(Duration::from_millis(500)) {
                 match event {
                     ServiceEvent::ServiceResolved(resolved) => {
@@ -234,12 +958,12 @@ pub async fn start_browse(app: AppHandle) -> Result<(), String> {
                             port,
                         });
                         let list: Vec<Peer> = peers.values().cloned().collect();
-                        emit_peers(&app_browse, list);
+                        emit_peers(&engine_browse, list);
                     }
                     ServiceEvent::ServiceRemoved(_, fullname) => {
                         peers.remove(&fullname);
                         let list: Vec<Peer> = peers.values().cloned().collect();
-                        emit_peers(&app_browse, list);
+                        emit_peers(&engine_browse, list);
                     }
                     _ => {}
                 }
@@ -251,17 +975,16 @@ pub async fn start_browse(app: AppHandle) -> Result<(), String> {
     Ok(())
 }
 
-pub async fn stop_browse(app: AppHandle) -> Result<(), String> {
-    BROWSING.store(false, Ordering::SeqCst);
+pub async fn stop_browse(engine: Engine) -> Result<(), String> {
+    engine.state().browsing.store(false, Ordering::SeqCst);
     tokio::time::sleep(Duration::from_millis(600)).await;
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.browse_receiver.lock().map_err(|_| "lock")? = None;
-        *state.daemon.lock().map_err(|_| "lock")? = None;
-    }
+    let state = engine.state();
+    *state.browse_receiver.lock().map_err(|_| "lock")? = None;
+    *state.daemon.lock().map_err(|_| "lock")? = None;
     Ok(())
 }
 
-pub async fn connect_to(host: String, port: u16, app: AppHandle) -> Result<(), String> {
+pub async fn connect_to(host: String, port: u16, engine: Engine) -> Result<(), String> {
     let url = format!("ws://{}:{}", host, port);
     let timeout_msg = "Connection timed out. Check that both Macs are on the same network and the other device is sharing.";
 
@@ -295,215 +1018,799 @@ pub async fn connect_to(host: String, port: u16, app: AppHandle) -> Result<(), S
                 CONNECT_MAX_ATTEMPTS, last_error
             )
         })?;
+        if let MaybeTlsStream::Plain(tcp) = stream.get_ref() {
+            *engine.state().peer_address.lock().unwrap() = tcp.peer_addr().ok().map(|a| a.ip());
+        }
         stream.split()
     };
 
-    let my_name = hostname::get()
-        .map(|h| h.to_string_lossy().into_owned())
-        .unwrap_or_else(|_| "Mac".to_string());
-
-    let hello = serde_json::to_string(&WsMessage::Hello { name: my_name.clone() }).unwrap();
+    let hello = serde_json::to_string(&our_hello(&engine)).unwrap();
     write
         .send(Message::Text(hello))
         .await
         .map_err(|e| e.to_string())?;
 
     let (tx, mut rx) = mpsc::channel::<String>(32);
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.client_tx.lock().unwrap() = Some(tx);
-        *state.peer_name.lock().unwrap() = Some(host.clone());
-    }
-    emit_connected(&app, &host);
+    let state = engine.state();
+    *state.client_tx.lock().unwrap() = Some(tx);
+    *state.peer_name.lock().unwrap() = Some(host.clone());
+    emit_connected(&engine, &host);
 
-    let app_recv = app.clone();
-    tauri::async_runtime::spawn(async move {
+    let writer = engine.spawn(async move {
         while let Some(msg) = rx.recv().await {
             if write.send(Message::Text(msg)).await.is_err() {
-                break;
+                return;
             }
         }
+        let _ = write.close().await;
     });
+    *state.client_writer.lock().unwrap() = Some(writer);
 
-    let app_read = app.clone();
-    tauri::async_runtime::spawn(async move {
+    let engine_read = engine.clone();
+    engine.spawn(async move {
         while let Some(Ok(msg)) = read.next().await {
             if let Message::Text(text) = msg {
-                if let Ok(parsed) = serde_json::from_str::<WsMessage>(&text) {
-                    match parsed {
-                        WsMessage::Clipboard { text: t } => emit_remote_clipboard(&app_read, &t),
-                        WsMessage::File { name, data } => emit_remote_file(&app_read, &name, &data),
-                        WsMessage::BringToFront => emit_bring_to_front(&app_read),
-                        _ => {}
-                    }
+                if let Some(parsed) = decode(&text) {
+                    handle_message(&engine_read, parsed).await;
                 }
             }
         }
-        if let Some(state) = app_read.try_state::<SyncState>() {
-            *state.client_tx.lock().unwrap() = None;
-            *state.peer_name.lock().unwrap() = None;
-        }
-        emit_disconnected(&app_read);
+        let state = engine_read.state();
+        *state.client_tx.lock().unwrap() = None;
+        clear_peer(state);
+        emit_disconnected(&engine_read);
     });
 
     Ok(())
 }
 
-pub async fn disconnect(app: AppHandle) -> Result<(), String> {
-    if let Some(state) = app.try_state::<SyncState>() {
-        *state.host_tx.lock().map_err(|_| "lock")? = None;
-        *state.client_tx.lock().map_err(|_| "lock")? = None;
-        *state.peer_name.lock().map_err(|_| "lock")? = None;
-    }
-    emit_disconnected(&app);
+pub async fn disconnect(engine: Engine) -> Result<(), String> {
+    let state = engine.state();
+    *state.host_tx.lock().map_err(|_| "lock")? = None;
+    *state.client_tx.lock().map_err(|_| "lock")? = None;
+    clear_peer(state);
+    emit_disconnected(&engine);
     Ok(())
 }
 
-pub async fn send_clipboard(text: String, app: AppHandle) -> Result<(), String> {
-    let msg = WsMessage::Clipboard { text };
-    if let Some(state) = app.try_state::<SyncState>() {
-        if let Some(tx) = state.host_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
-        }
-        if let Some(tx) = state.client_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
-        }
+/// Disconnects from the host once everything already sent has been written out, so a
+/// short-lived client can exit right after sending.
+pub async fn disconnect_when_sent(engine: Engine) -> Result<(), String> {
+    let state = engine.state();
+    *state.client_tx.lock().map_err(|_| "lock")? = None;
+    let writer = state.client_writer.lock().map_err(|_| "lock")?.take();
+    if let Some(writer) = writer {
+        let _ = writer.await;
     }
-    Err("Not connected".to_string())
+    disconnect(engine).await
 }
 
-pub async fn send_bring_to_front(app: AppHandle) -> Result<(), String> {
-    let msg = WsMessage::BringToFront;
-    if let Some(state) = app.try_state::<SyncState>() {
-        if let Some(tx) = state.host_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
-        }
-        if let Some(tx) = state.client_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
+/// Why `clip` must not be sent to the current peer, if the sensitive-content filter is on.
+fn redaction_reason(engine: &Engine, peer: Option<&str>, clip: &LocalClip) -> Option<String> {
+    let settings = settings::current(engine);
+    if !settings.filter_sensitive_for(peer) {
+        return None;
+    }
+    if clip.concealed {
+        return Some("Marked as a password by the source app".to_string());
+    }
+    let mut compiled = engine.state().sensitive_rules.lock().unwrap();
+    compiled
+        .matching_rule(&settings.sensitive_rules, &clip.content)
+        .map(|rule| format!("Matched rule \"{}\"", rule))
+}
+
+/// Sends a clipboard item. Copied files go first through the regular file transfer so
+/// the peer has them on disk by the time it sees the file list. Content caught by the
+/// sensitive-content filter is dropped with a `clipboard_redacted` event instead.
+pub async fn send_clip(engine: &Engine, clip: LocalClip) -> Result<(), String> {
+    if let Some(reason) = redaction_reason(engine, current_peer(engine).as_deref(), &clip) {
+        emit_clipboard_redacted(engine, &reason);
+        return Ok(());
+    }
+    let LocalClip { mut content, mut files, .. } = clip;
+    let total: u64 = files
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+    if total > MAX_CLIPBOARD_FILES_BYTES {
+        files.clear();
+        content.formats.retain(|f| !matches!(f, ClipFormat::Files { .. }));
+    } else {
+        let include_xattrs = settings::current(engine).preserve_xattrs;
+        for path in &files {
+            let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            let meta = files::read_meta(path, include_xattrs);
+            send_file(engine, path, name, Some(meta)).await?;
         }
     }
-    Err("Not connected".to_string())
+    send_clip_content(engine, &mut content).await?;
+    history::record(engine, Direction::Sent, current_peer(engine), &content, &files);
+    Ok(())
 }
 
-pub async fn pick_and_send_file(app: AppHandle) -> Result<(), String> {
-    let path = app.dialog().file().blocking_pick_file();
+/// Sends the clipboard item itself: inline if it fits `clipboard_max_bytes`, otherwise as
+/// a streamed transfer, or cut down to the limit for peers that cannot take one and for
+/// items whose JSON would be over the peer's streaming limit.
+async fn send_clip_content(engine: &Engine, content: &mut ClipContent) -> Result<(), String> {
+    let limit = settings::current(engine).clipboard_max_bytes;
+    let size = content.size() as u64;
+    if size > limit && size <= MAX_STREAMED_CLIPBOARD_BYTES
+        && peer_supports(engine, FEATURE_CLIPBOARD_STREAM)
+    {
+        // Escaping and base64 make the JSON larger than `size`; the peer checks this.
+        let json = serde_json::to_vec(&*content).map_err(|e| e.to_string())?;
+        if json.len() as u64 <= MAX_STREAMED_CLIPBOARD_BYTES {
+            let dir = staging_dir(engine);
+            let path = files::save_to_dir(&dir, CLIPBOARD_TRANSFER_NAME, &json, None).await?;
+            let name = CLIPBOARD_TRANSFER_NAME.to_string();
+            let sent = stream_file(engine, &path, name, None, true).await;
+            let _ = tokio::fs::remove_file(&path).await;
+            return sent;
+        }
+    }
 
-    let path = match path {
-        Some(p) => p,
-        None => return Ok(()),
+    let truncated = content.truncate(limit as usize);
+    if truncated {
+        emit_clipboard_truncated(engine, size, limit, true);
+    }
+    let msg = WsMessage::Clipboard {
+        text: content.text.clone(),
+        formats: content.formats.clone(),
+        truncated,
+    };
+    send_message(engine, &msg).await
+}
+
+/// Sends `text`, or everything on the local clipboard when `text` is `None`. While
+/// disconnected the item goes to the outbox for the last peer instead.
+pub async fn send_clipboard(text: Option<String>, engine: Engine) -> Result<(), String> {
+    let clip: LocalClip = match text {
+        Some(text) => ClipContent { text, formats: Vec::new() }.into(),
+        None => engine.spawn_blocking(clipboard::read_current)
+            .await
+            .map_err(|e| e.to_string())??,
     };
+    if is_connected(&engine) {
+        return send_clip(&engine, clip).await;
+    }
+    let peer = outbox::last_peer(&engine).ok_or("Not connected")?;
+    // Filter now rather than on delivery, so secrets are never written to the outbox.
+    if let Some(reason) = redaction_reason(&engine, Some(&peer), &clip) {
+        emit_clipboard_redacted(&engine, &reason);
+        return Ok(());
+    }
+    let kind = OutboxKind::Clipboard {
+        content: clip.content,
+        files: clip.files,
+    };
+    outbox::queue(&engine, peer, kind)
+}
 
-    let path_buf = match path {
-        FilePath::Path(p) => p,
-        _ => return Err("Invalid path".to_string()),
+/// The last clipboard item received from the peer.
+pub fn last_remote_clip(engine: &Engine) -> Option<ClipContent> {
+    engine.state().last_remote_clip.lock().ok().and_then(|c| c.clone())
+}
+
+/// Applies the last item received from the peer to the local clipboard.
+pub async fn paste_from_remote(engine: Engine) -> Result<(), String> {
+    let content = last_remote_clip(&engine).ok_or("Nothing received yet")?;
+    let files = local_clip_files(&engine, &content);
+    apply_clip(&engine, content, files).await
+}
+
+/// Sends an old history entry to the current peer again.
+pub async fn resend_history(id: String, engine: Engine) -> Result<(), String> {
+    let entry = history::entry(&engine, &id)?;
+    let clip = LocalClip {
+        content: entry.content,
+        files: entry.files.into_iter().filter(|p| p.is_file()).collect(),
+        concealed: false,
     };
-    let bytes = tokio::fs::read(&path_buf).await.map_err(|e| e.to_string())?;
-    let name = path_buf
+    send_clip(&engine, clip).await
+}
+
+/// Puts an old history entry back on the local clipboard.
+pub async fn apply_history(id: String, engine: Engine) -> Result<(), String> {
+    let entry = history::entry(&engine, &id)?;
+    let files = entry.files.into_iter().filter(|p| p.is_file()).collect();
+    apply_clip(&engine, entry.content, files).await
+}
+
+pub async fn send_bring_to_front(engine: Engine) -> Result<(), String> {
+    let msg = WsMessage::BringToFront;
+    let state = engine.state();
+    if let Some(tx) = state.host_tx.lock().ok().and_then(|g| g.clone()) {
+        let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
+        tx.send(json).await.map_err(|_| "Send failed".to_string())?;
+        return Ok(());
+    }
+    if let Some(tx) = state.client_tx.lock().ok().and_then(|g| g.clone()) {
+        let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
+        tx.send(json).await.map_err(|_| "Send failed".to_string())?;
+        return Ok(());
+    }
+    Err("Not connected".to_string())
+}
+
+/// Sends the file at `path`, or queues it for the last peer while disconnected.
+pub async fn send_path(path: PathBuf, engine: Engine) -> Result<(), String> {
+    let name = path
         .file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("file")
         .to_string();
-    let data = base64::engine::general_purpose::STANDARD.encode(&bytes);
-
-    let msg = WsMessage::File { name, data };
-    if let Some(state) = app.try_state::<SyncState>() {
-        if let Some(tx) = state.host_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
-        }
-        if let Some(tx) = state.client_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tx.send(json).await.map_err(|_| "Send failed".to_string())?;
-            return Ok(());
-        }
+    if !is_connected(&engine) {
+        let peer = outbox::last_peer(&engine).ok_or("Not connected")?;
+        return outbox::queue(&engine, peer, OutboxKind::File { path, name });
     }
-    Err("Not connected".to_string())
-}
+    let meta = files::read_meta(&path, settings::current(&engine).preserve_xattrs);
 
-#[cfg(target_os = "macos")]
-fn capture_screenshot_to_jpg() -> Result<(PathBuf, String), String> {
-    let name = format!("screenshot_{}.jpg", Local::now().format("%Y-%m-%d_%H-%M-%S"));
-    let temp_dir = std::env::temp_dir();
-    let path = temp_dir.join(&name);
+    send_file(&engine, &path, name, Some(meta)).await
+}
 
-    let status = Command::new("screencapture")
-        .args(["-i", "-x", "-t", "jpg", path.to_str().unwrap()])
-        .status()
+/// Captures the screen with `options`, or the saved screenshot settings, and sends it.
+pub async fn capture_screenshot_and_send(
+    options: Option<CaptureOptions>,
+    engine: Engine,
+) -> Result<(), String> {
+    let options = options.unwrap_or_else(|| settings::current(&engine).screenshot);
+    let result = engine.spawn_blocking(move || screenshot::capture(&options))
+        .await
         .map_err(|e| e.to_string())?;
+    let (path, name) = result?;
+
+    send_file(&engine, &path, name, None).await
+}
+
+/// A captured screenshot handed to the UI for redaction and annotation.
+#[derive(Serialize)]
+pub struct ShotPreview {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    /// Base64-encoded PNG.
+    pub png: String,
+}
+
+/// Captures the screen and keeps it for `send_screenshot`, returning a preview to edit.
+pub async fn capture_screenshot(mode: Option<CaptureMode>, engine: Engine) -> Result<ShotPreview, String> {
+    let mode = mode.unwrap_or_else(|| settings::current(&engine).screenshot.mode);
+    let capture = engine.spawn_blocking(move || screenshot::capture_png(mode))
+        .await
+        .map_err(|e| e.to_string())??;
+    let bytes = tokio::fs::read(&capture.png).await.map_err(|e| e.to_string())?;
+    let (width, height) = image::image_dimensions(&capture.png).map_err(|e| e.to_string())?;
+
+    let id = transfer::new_id();
+    let state = engine.state();
+    state.pending_shots.lock().unwrap().insert(id.clone(), capture);
+    Ok(ShotPreview {
+        id,
+        width,
+        height,
+        png: base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+/// Applies the UI's redactions and annotations to a captured screenshot, then sends it.
+pub async fn send_screenshot(
+    id: String,
+    edits: Vec<Edit>,
+    options: Option<CaptureOptions>,
+    engine: Engine,
+) -> Result<(), String> {
+    let capture = engine
+        .state()
+        .pending_shots
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or("Screenshot no longer available")?;
+    let options = options.unwrap_or_else(|| settings::current(&engine).screenshot);
+    let (path, name) =
+        engine.spawn_blocking(move || screenshot::encode(&capture, &options, &edits))
+            .await
+            .map_err(|e| e.to_string())??;
+
+    send_file(&engine, &path, name, None).await
+}
+
+pub fn discard_screenshot(id: String, engine: Engine) -> Result<(), String> {
+    let state = engine.state();
+    state.pending_shots.lock().map_err(|_| "lock")?.remove(&id);
+    Ok(())
+}
 
-    if !status.success() {
-        return Err("Screenshot cancelled or failed".to_string());
+/// Asks the peer for a screenshot of its screen. It arrives like any other received file.
+pub async fn request_remote_screenshot(mode: Option<CaptureMode>, engine: Engine) -> Result<String, String> {
+    if !peer_supports(&engine, FEATURE_REMOTE_SCREENSHOT) {
+        return Err("The other device does not support screenshot requests".to_string());
     }
+    let id = transfer::new_id();
+    let msg = WsMessage::ScreenshotRequest { id: id.clone(), mode };
+    send_message(&engine, &msg).await?;
+    Ok(id)
+}
 
-    Ok((path, name))
+async fn decline_screenshot(engine: &Engine, id: String, reason: &str) {
+    let msg = WsMessage::ScreenshotDeclined {
+        id,
+        reason: reason.to_string(),
+    };
+    let _ = send_message(engine, &msg).await;
 }
 
-#[cfg(not(target_os = "macos"))]
-fn capture_screenshot_to_jpg() -> Result<(PathBuf, String), String> {
-    Err("Screenshot capture is only supported on macOS".to_string())
+/// Captures our screen for the peer and sends it back. Defaults to the full screen, since
+/// nobody may be at this machine to pick a region.
+async fn fulfil_screenshot_request(engine: &Engine, id: String, mode: Option<CaptureMode>) {
+    let mut options = settings::current(engine).screenshot;
+    options.mode = mode.unwrap_or(CaptureMode::Full);
+    let captured = match engine.spawn_blocking(move || screenshot::capture(&options)).await {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    };
+    let (path, name) = match captured {
+        Ok(captured) => captured,
+        Err(reason) => return decline_screenshot(engine, id, &reason).await,
+    };
+    let sent = send_file(engine, &path, name, None).await;
+    drop(path);
+    if let Err(reason) = sent {
+        decline_screenshot(engine, id, &reason).await;
+    }
 }
 
-fn send_file_bytes(app: &AppHandle, name: String, bytes: &[u8]) -> Result<(), String> {
-    let data = base64::engine::general_purpose::STANDARD.encode(bytes);
-    let msg = WsMessage::File { name, data };
-    if let Some(state) = app.try_state::<SyncState>() {
-        if let Some(tx) = state.host_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tauri::async_runtime::block_on(async move {
-                tx.send(json).await.map_err(|_| "Send failed".to_string())
-            })?;
-            return Ok(());
+async fn receive_screenshot_request(engine: &Engine, id: String, mode: Option<CaptureMode>) {
+    let peer = current_peer(engine);
+    let permission = settings::current(engine)
+        .peer(peer.as_deref())
+        .map(|p| p.screenshot)
+        .unwrap_or_default();
+    match permission {
+        // Capturing can take a while; keep reading messages meanwhile.
+        Permission::Allow => {
+            let engine = engine.clone();
+            engine.clone().spawn(async move {
+                fulfil_screenshot_request(&engine, id, mode).await;
+            });
         }
-        if let Some(tx) = state.client_tx.lock().ok().and_then(|g| g.clone()) {
-            let json = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
-            tauri::async_runtime::block_on(async move {
-                tx.send(json).await.map_err(|_| "Send failed".to_string())
-            })?;
-            return Ok(());
+        Permission::Deny => decline_screenshot(engine, id, "Not allowed").await,
+        Permission::Ask => {
+            let state = engine.state();
+            state.screenshot_requests.lock().unwrap().insert(id.clone(), mode);
+            emit_screenshot_requested(engine, &id, mode);
+            emit_bring_to_front(engine);
         }
     }
-    Err("Not connected".to_string())
 }
 
-pub async fn capture_screenshot_and_send(app: AppHandle) -> Result<(), String> {
-    let result = tokio::task::spawn_blocking(capture_screenshot_to_jpg)
-        .await
-        .map_err(|e| e.to_string())?;
-    let (path, name) = result?;
+/// The local user's answer to a screenshot request. `remember` stores it for this peer.
+pub async fn answer_screenshot_request(
+    id: String,
+    allow: bool,
+    remember: bool,
+    engine: Engine,
+) -> Result<(), String> {
+    let mode = engine
+        .state()
+        .screenshot_requests
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or("Request no longer pending")?;
+    if remember {
+        if let Some(peer) = current_peer(&engine) {
+            let mut settings = settings::current(&engine);
+            settings.peers.entry(peer).or_default().screenshot =
+                if allow { Permission::Allow } else { Permission::Deny };
+            settings::set_settings(settings, engine.clone())?;
+        }
+    }
+    if allow {
+        fulfil_screenshot_request(&engine, id, mode).await;
+    } else {
+        decline_screenshot(&engine, id, "Declined by the other user").await;
+    }
+    Ok(())
+}
+
+/// The name a staged file was announced under, to suggest in the save dialog.
+pub fn staged_name(engine: &Engine, id: &str) -> Result<String, String> {
+    engine
+        .state()
+        .staged
+        .lock()
+        .ok()
+        .and_then(|s| s.get(id).map(|f| f.name.clone()))
+        .ok_or("File is no longer available".to_string())
+}
+
+/// Copies a staged file to `path`. The staged copy is kept so the same file can be saved
+/// again.
+pub async fn save_staged(id: String, path: PathBuf, engine: Engine) -> Result<String, String> {
+    let staged = engine
+        .state()
+        .staged
+        .lock()
+        .ok()
+        .and_then(|s| s.get(&id).cloned())
+        .ok_or("File is no longer available")?;
 
-    let bytes = tokio::fs::read(&path).await.map_err(|e| e.to_string())?;
-    let _ = std::fs::remove_file(&path);
+    files::copy_atomic(&staged.path, &path, staged.meta.as_ref()).await?;
 
-    send_file_bytes(&app, name, &bytes)
+    Ok(path.to_string_lossy().into_owned())
 }
 
-pub async fn save_received_file(name: String, data: String, app: AppHandle) -> Result<String, String> {
-    let path = app
-        .dialog()
-        .file()
-        .set_file_name(&name)
-        .blocking_save_file();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Faults, Proxy, TestEngine};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+    use std::sync::Arc;
 
-    let path = match path {
-        Some(FilePath::Path(p)) => p,
-        Some(_) => return Err("Invalid path".to_string()),
-        None => return Err("Cancelled".to_string()),
-    };
+    /// Frames of `kind`, e.g. `FileChunk`.
+    fn is(text: &str, kind: &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(text).is_ok_and(|v| v["type"] == kind)
+    }
 
-    let bytes = base64::engine::general_purpose::STANDARD
-        .decode(&data)
-        .map_err(|e| e.to_string())?;
-    tokio::fs::write(&path, &bytes)
-        .await
-        .map_err(|e| e.to_string())?;
+    /// Files left behind in `dir` and below, such as partial downloads.
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(Result::ok)
+            .flat_map(|entry| match entry.path() {
+                path if path.is_dir() => files_in(&path),
+                path => vec![path],
+            })
+            .collect()
+    }
 
-    Ok(path.to_string_lossy().into_owned())
+    #[tokio::test(flavor = "multi_thread")]
+    async fn engines_in_one_process_keep_their_own_state() {
+        let a = TestEngine::new("a");
+        let b = TestEngine::new("b");
+        let port = start_host_on(0, false, a.engine.clone()).await.unwrap();
+        assert!(is_hosting(&a.engine));
+        assert_eq!(host_port(&a.engine), Some(port));
+        assert!(!is_hosting(&b.engine));
+
+        let other = start_host_on(0, false, b.engine.clone()).await.unwrap();
+        assert_ne!(port, other);
+        assert!(start_host_on(0, false, a.engine.clone()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handshake_exchanges_names_and_features() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        assert_eq!(current_peer(&host.engine).as_deref(), Some("client"));
+        assert_eq!(current_peer(&client.engine).as_deref(), Some("host"));
+        for feature in FEATURES {
+            assert!(peer_supports(&host.engine, feature), "host lacks {feature}");
+            assert!(peer_supports(&client.engine, feature), "client lacks {feature}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clipboard_reaches_the_host() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        send_clipboard(Some("over loopback".to_string()), client.engine.clone())
+            .await
+            .unwrap();
+        let received = host.wait_for("remote_clipboard", |_| true).await;
+        assert_eq!(received["text"], "over loopback");
+        assert_eq!(last_remote_clip(&host.engine).unwrap().text, "over loopback");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_reaches_the_client() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        let bytes: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let path = host.file("report.bin", &bytes);
+        send_path(path, host.engine.clone()).await.unwrap();
+
+        let (received, saved) = client.receive_file().await;
+        assert_eq!(received["name"], "report.bin");
+        assert_eq!(saved, bytes);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_binary_file_round_trips() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        // Several chunks, the last one partial, none of them compressible.
+        let bytes = testing::noise(5 * transfer::CHUNK_SIZE + 1234);
+        send_path(client.file("noise.bin", &bytes), client.engine.clone())
+            .await
+            .unwrap();
+        let (_, saved) = host.receive_file().await;
+        assert_eq!(saved, bytes);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compressible_file_round_trips_smaller() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        let bytes = vec![0u8; 3 * transfer::CHUNK_SIZE];
+        send_path(client.file("zeros.bin", &bytes), client.engine.clone())
+            .await
+            .unwrap();
+        let stats = host.wait_for("transfer_stats", |_| true).await;
+        assert!(stats["wire_bytes"].as_u64().unwrap() < bytes.len() as u64 / 10);
+        let (_, saved) = host.receive_file().await;
+        assert_eq!(saved, bytes);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_file_round_trips() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        testing::connect(&mut host, &mut client).await;
+
+        send_path(client.file("empty.txt", b""), client.engine.clone())
+            .await
+            .unwrap();
+        let (received, saved) = host.receive_file().await;
+        assert_eq!(received["name"], "empty.txt");
+        assert!(saved.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connect_to_gives_up_when_nobody_listens() {
+        let client = TestEngine::new("client");
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let error = connect_to("127.0.0.1".to_string(), port, client.engine.clone())
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("Failed after 3 attempts"), "{error}");
+        assert!(!is_connected(&client.engine));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connect_to_retries_until_the_host_is_up() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+
+        let connecting = tokio::spawn(connect_to("127.0.0.1".to_string(), port, client.engine.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        start_host_on(port, false, host.engine.clone()).await.unwrap();
+        connecting.await.unwrap().unwrap();
+        host.wait_for("connected", |p| p["name"] == "client").await;
+        client.wait_for("connected", |p| p["name"] == "host").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnect_ends_hosting_and_the_client_can_reconnect() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = testing::connect(&mut host, &mut client).await;
+
+        disconnect(client.engine.clone()).await.unwrap();
+        host.wait_for("disconnected", |_| true).await;
+        assert!(!is_connected(&host.engine));
+        // Hosting accepts a single peer; a new connection needs hosting again.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!is_hosting(&host.engine));
+
+        start_host_on(port, false, host.engine.clone()).await.unwrap();
+        testing::join(port, &mut host, &mut client).await;
+        send_clipboard(Some("again".to_string()), client.engine.clone())
+            .await
+            .unwrap();
+        host.wait_for("remote_clipboard", |p| p["text"] == "again").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clipboard_copied_while_cut_off_arrives_after_reconnecting() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let proxy = Proxy::start(port, Faults::default()).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        proxy.cut();
+        client.wait_for("disconnected", |_| true).await;
+        host.wait_for("disconnected", |_| true).await;
+        send_clipboard(Some("queued".to_string()), client.engine.clone())
+            .await
+            .unwrap();
+        assert_eq!(outbox::list_outbox(client.engine.clone()).unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        start_host_on(port, false, host.engine.clone()).await.unwrap();
+        testing::join(port, &mut host, &mut client).await;
+        host.wait_for("remote_clipboard", |p| p["text"] == "queued").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn malformed_frames_are_ignored() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let proxy = Proxy::start(port, Faults::default()).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        for junk in [
+            "not json",
+            "{}",
+            "[1, 2, 3]",
+            r#"{"type":"NoSuchMessage"}"#,
+            r#"{"type":"Clipboard"}"#,
+            r#"{"type":"FileChunk","id":"unknown","data":"!!not base64!!"}"#,
+            r#"{"type":"FileEnd","id":"unknown"}"#,
+            r#"{"type":"FileStart","id":"x","name":"../../escape","size":3}"#,
+            r#"{"type":"Hello","name":7}"#,
+        ] {
+            proxy.inject_to_host(Message::Text(junk.to_string()));
+            proxy.inject_to_client(Message::Text(junk.to_string()));
+        }
+        proxy.inject_to_host(Message::Binary(vec![0xff, 0x00, 0xfe]));
+        host.wait_for("file_rejected", |p| p["name"] == "../../escape").await;
+
+        send_clipboard(Some("still here".to_string()), client.engine.clone())
+            .await
+            .unwrap();
+        host.wait_for("remote_clipboard", |p| p["text"] == "still here").await;
+        send_clipboard(Some("and back".to_string()), host.engine.clone())
+            .await
+            .unwrap();
+        client.wait_for("remote_clipboard", |p| p["text"] == "and back").await;
+        assert_eq!(current_peer(&host.engine).as_deref(), Some("client"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropped_chunk_rejects_the_file() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let chunks = Arc::new(AtomicUsize::new(0));
+        let seen = chunks.clone();
+        let faults = Faults {
+            drop: Some(Arc::new(move |text| {
+                is(text, "FileChunk") && seen.fetch_add(1, Ordering::SeqCst) == 1
+            })),
+            ..Faults::default()
+        };
+        let proxy = Proxy::start(port, faults).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        let bytes = testing::noise(3 * transfer::CHUNK_SIZE);
+        send_path(client.file("gap.bin", &bytes), client.engine.clone())
+            .await
+            .unwrap();
+        let rejected = host.wait_for("file_rejected", |_| true).await;
+        assert_eq!(rejected["name"], "gap.bin");
+        assert!(files_in(&staging_dir(&host.engine)).is_empty());
+
+        // The next transfer is unaffected.
+        send_path(client.file("whole.bin", b"whole"), client.engine.clone())
+            .await
+            .unwrap();
+        let (_, saved) = host.receive_file().await;
+        assert_eq!(saved, b"whole");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn oversized_streamed_clipboard_is_rejected_not_reported_as_truncated() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let proxy = Proxy::start(port, Faults::default()).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        let size = MAX_STREAMED_CLIPBOARD_BYTES + 1;
+        let start = format!(
+            r#"{{"type":"FileStart","id":"big","name":"{CLIPBOARD_TRANSFER_NAME}","size":{size},"clipboard":true}}"#
+        );
+        proxy.inject_to_host(Message::Text(start));
+        let rejected = host.wait_for("file_rejected", |_| true).await;
+        assert_eq!(rejected["reason"], "Clipboard item too large");
+        assert!(host.engine.state().incoming.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unfinished_transfers_are_capped_and_dropped_when_stalled() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let proxy = Proxy::start(port, Faults::default()).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        for i in 0..=MAX_INCOMING {
+            let start = format!(r#"{{"type":"FileStart","id":"s{i}","name":"never-{i}.bin","size":1000}}"#);
+            proxy.inject_to_host(Message::Text(start));
+        }
+        let rejected = host.wait_for("file_rejected", |_| true).await;
+        assert_eq!(rejected["name"], format!("never-{MAX_INCOMING}.bin"));
+        assert_eq!(rejected["reason"], "Too many transfers in progress");
+        assert_eq!(files_in(&staging_dir(&host.engine)).len(), MAX_INCOMING);
+
+        for _ in 0..MAX_INCOMING {
+            host.wait_for("file_rejected", |p| p["reason"] == "Transfer stalled").await;
+        }
+        assert!(host.engine.state().incoming.lock().unwrap().is_empty());
+        assert!(files_in(&staging_dir(&host.engine)).is_empty());
+
+        send_path(client.file("after.bin", b"after"), client.engine.clone())
+            .await
+            .unwrap();
+        let (_, saved) = host.receive_file().await;
+        assert_eq!(saved, b"after");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cut_mid_transfer_leaves_no_partial_file() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let faults = Faults {
+            drop: Some(Arc::new(|text| is(text, "FileEnd"))),
+            ..Faults::default()
+        };
+        let proxy = Proxy::start(port, faults).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        let bytes = testing::noise(2 * transfer::CHUNK_SIZE);
+        send_path(client.file("partial.bin", &bytes), client.engine.clone())
+            .await
+            .unwrap();
+        // Wait until the host is holding the unfinished transfer.
+        let deadline = Instant::now() + testing::EVENT_TIMEOUT;
+        while files_in(&staging_dir(&host.engine)).is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!files_in(&staging_dir(&host.engine)).is_empty());
+
+        proxy.cut();
+        host.wait_for("disconnected", |_| true).await;
+        assert!(files_in(&staging_dir(&host.engine)).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_link_delivers_intact() {
+        let mut host = TestEngine::new("host");
+        let mut client = TestEngine::new("client");
+        let port = start_host_on(0, false, host.engine.clone()).await.unwrap();
+        let faults = Faults {
+            latency: Duration::from_millis(20),
+            bytes_per_second: Some(4 * 1024 * 1024),
+            ..Faults::default()
+        };
+        let proxy = Proxy::start(port, faults).await;
+        testing::join(proxy.port, &mut host, &mut client).await;
+
+        let bytes = testing::noise(transfer::CHUNK_SIZE * 3 / 2);
+        let started = Instant::now();
+        send_path(host.file("slow.bin", &bytes), host.engine.clone())
+            .await
+            .unwrap();
+        send_clipboard(Some("after the file".to_string()), host.engine.clone())
+            .await
+            .unwrap();
+        let (_, saved) = client.receive_file().await;
+        assert_eq!(saved, bytes);
+        client.wait_for("remote_clipboard", |p| p["text"] == "after the file").await;
+        // Base64 makes the file about 500 KB on the wire.
+        assert!(started.elapsed() > Duration::from_millis(100));
+    }
 }
@@ -0,0 +1,276 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, connect_async};
+
+use crate::daemon::Headless;
+use crate::engine::{Engine, EventSink, Paths};
+use crate::sync;
+
+/// How long a test waits for an event before failing.
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// Events are read through `Engine::subscribe`.
+struct NoEvents;
+
+impl EventSink for NoEvents {
+    fn emit(&self, _event: &str, _payload: Value) {}
+}
+
+/// A path under the system temp folder that no other test uses.
+fn unique_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "filetwin-test-{}-{}-{name}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// An empty folder of its own, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = unique_dir(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Names of the entries in the folder, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(&self.0)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An engine with its events, removing its folder when dropped.
+pub struct TestEngine {
+    pub engine: Engine,
+    pub events: broadcast::Receiver<(String, Value)>,
+    pub dir: PathBuf,
+}
+
+impl TestEngine {
+    /// Must be called from within a Tokio runtime.
+    pub fn new(name: &str) -> TestEngine {
+        let dir = unique_dir(name);
+        let paths = Paths {
+            config_dir: dir.join("config"),
+            data_dir: dir.join("data"),
+            cache_dir: dir.join("cache"),
+        };
+        let runtime = tokio::runtime::Handle::current();
+        let engine = Engine::new(name.to_string(), paths, NoEvents, Headless, runtime);
+        let events = engine.subscribe();
+        TestEngine { engine, events, dir }
+    }
+
+    /// Waits for the next `event` that `matches` accepts, skipping others, and returns
+    /// its payload.
+    pub async fn wait_for(&mut self, event: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        let wait = async {
+            loop {
+                match self.events.recv().await {
+                    Ok((name, payload)) if name == event && matches(&payload) => return payload,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => panic!("engine dropped"),
+                }
+            }
+        };
+        tokio::time::timeout(EVENT_TIMEOUT, wait)
+            .await
+            .unwrap_or_else(|_| panic!("{} got no {event} event", self.engine.name()))
+    }
+
+    /// Waits for the next received file and saves it, returning its `remote_file` event
+    /// and contents.
+    pub async fn receive_file(&mut self) -> (Value, Vec<u8>) {
+        let received = self.wait_for("remote_file", |_| true).await;
+        let id = received["id"].as_str().unwrap().to_string();
+        let path = self.dir.join("received").join(&id);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        sync::save_staged(id, path.clone(), self.engine.clone())
+            .await
+            .unwrap();
+        (received, read(&path))
+    }
+
+    /// Writes `bytes` to a file named `name` in this engine's folder.
+    pub fn file(&self, name: &str, bytes: &[u8]) -> PathBuf {
+        let path = self.dir.join(name);
+        std::fs::create_dir_all(&self.dir).unwrap();
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+}
+
+impl Drop for TestEngine {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts `host` on a free loopback port and connects `client` to it, returning the port
+/// once both have the other's `Hello`.
+pub async fn connect(host: &mut TestEngine, client: &mut TestEngine) -> u16 {
+    let port = sync::start_host_on(0, false, host.engine.clone()).await.unwrap();
+    join(port, host, client).await;
+    port
+}
+
+/// Connects `client` to `port`, where `host` or a `Proxy` in front of it listens, and
+/// waits for both `Hello`s.
+pub async fn join(port: u16, host: &mut TestEngine, client: &mut TestEngine) {
+    sync::connect_to("127.0.0.1".to_string(), port, client.engine.clone())
+        .await
+        .unwrap();
+    let host_name = host.engine.name().to_string();
+    let client_name = client.engine.name().to_string();
+    client.wait_for("connected", |p| p["name"] == host_name.as_str()).await;
+    host.wait_for("connected", |p| p["name"] == client_name.as_str()).await;
+}
+
+/// Decides whether a `Proxy` drops a text frame.
+pub type DropFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// What a `Proxy` does to the frames it forwards, in both directions.
+#[derive(Clone, Default)]
+pub struct Faults {
+    /// Frames for which this returns true are not forwarded.
+    pub drop: Option<DropFilter>,
+    /// Added before forwarding each frame.
+    pub latency: Duration,
+    /// Forwarding is paced to this many bytes per second.
+    pub bytes_per_second: Option<u64>,
+}
+
+impl Faults {
+    async fn delay(&self, len: usize) {
+        let mut delay = self.latency;
+        if let Some(rate) = self.bytes_per_second {
+            delay += Duration::from_secs_f64(len as f64 / rate as f64);
+        }
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Forwards one WebSocket connection from a client to a host on loopback, applying
+/// `Faults`, and can inject frames or cut the connection.
+pub struct Proxy {
+    pub port: u16,
+    to_host: mpsc::UnboundedSender<Message>,
+    to_client: mpsc::UnboundedSender<Message>,
+    cut: watch::Sender<bool>,
+}
+
+/// Forwards frames from `from` to `to` until either side closes, with `injected` frames
+/// sent as they come.
+async fn relay<R, W>(mut from: R, mut to: W, mut injected: mpsc::UnboundedReceiver<Message>, faults: Faults)
+where
+    R: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    W: futures_util::Sink<Message> + Unpin,
+{
+    loop {
+        let message = tokio::select! {
+            message = from.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(message)) => message,
+            },
+            Some(message) = injected.recv() => message,
+        };
+        if let Message::Text(text) = &message {
+            if faults.drop.as_ref().is_some_and(|drop| drop(text)) {
+                continue;
+            }
+        }
+        faults.delay(message.len()).await;
+        if to.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Proxy {
+    /// Listens on a free loopback port and forwards the first connection to `host_port`.
+    pub async fn start(host_port: u16, faults: Faults) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (to_host, to_host_rx) = mpsc::unbounded_channel();
+        let (to_client, to_client_rx) = mpsc::unbounded_channel();
+        let (cut, mut cut_rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let Ok((stream, _)) = listener.accept().await else { return };
+            let Ok(client) = accept_async(stream).await else { return };
+            let Ok((host, _)) = connect_async(format!("ws://127.0.0.1:{host_port}")).await else {
+                return;
+            };
+            let (client_write, client_read) = client.split();
+            let (host_write, host_read) = host.split();
+            // Returning drops both connections, which the engines see as the peer leaving.
+            tokio::select! {
+                _ = relay(client_read, host_write, to_host_rx, faults.clone()) => {}
+                _ = relay(host_read, client_write, to_client_rx, faults) => {}
+                _ = cut_rx.wait_for(|cut| *cut) => {}
+            }
+        });
+        Proxy {
+            port,
+            to_host,
+            to_client,
+            cut,
+        }
+    }
+
+    /// Sends `message` to the host as if the client had sent it.
+    pub fn inject_to_host(&self, message: Message) {
+        let _ = self.to_host.send(message);
+    }
+
+    /// Sends `message` to the client as if the host had sent it.
+    pub fn inject_to_client(&self, message: Message) {
+        let _ = self.to_client.send(message);
+    }
+
+    /// Drops the connection without a WebSocket close, like a network failure.
+    pub fn cut(&self) {
+        let _ = self.cut.send(true);
+    }
+}
+
+/// `len` bytes that do not compress, covering every byte value.
+pub fn noise(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Contents of the file at `path`.
+pub fn read(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+}
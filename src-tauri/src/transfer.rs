@@ -0,0 +1,191 @@
+use base64::Engine;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+
+use crate::compression;
+use crate::files::{self, FileMeta};
+
+/// Raw (uncompressed) bytes carried by one `FileChunk`.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Transfer ids only need to be unique within a connection.
+pub fn new_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{:x}-{:x}", nanos, NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Fills `buf` from `file`, returning fewer bytes only at end of file.
+pub async fn read_chunk(file: &mut tokio::fs::File, buf: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file
+            .read(&mut buf[filled..])
+            .await
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Encodes one chunk for the wire, compressing it only if that actually saves space.
+/// Returns the base64 payload, whether it is compressed, and the payload size before base64.
+pub fn encode_chunk(raw: &[u8], compress: bool) -> (String, bool, usize) {
+    let packed = compress
+        .then(|| compression::compress(raw).ok())
+        .flatten()
+        .filter(|packed| packed.len() < raw.len());
+    let (payload, compressed) = match &packed {
+        Some(packed) => (packed.as_slice(), true),
+        None => (raw, false),
+    };
+    let data = base64::engine::general_purpose::STANDARD.encode(payload);
+    (data, compressed, payload.len())
+}
+
+/// Byte counts for one finished transfer, reported to the UI.
+#[derive(Clone, serde::Serialize)]
+pub struct TransferStats {
+    pub id: String,
+    pub name: String,
+    pub bytes: u64,
+    /// Payload bytes actually sent, after compression and before base64.
+    pub wire_bytes: u64,
+    /// `wire_bytes / bytes`; below 1.0 means compression helped.
+    pub ratio: f64,
+}
+
+impl TransferStats {
+    pub fn new(id: &str, name: &str, bytes: u64, wire_bytes: u64) -> Self {
+        let ratio = if bytes == 0 { 1.0 } else { wire_bytes as f64 / bytes as f64 };
+        TransferStats {
+            id: id.to_string(),
+            name: name.to_string(),
+            bytes,
+            wire_bytes,
+            ratio,
+        }
+    }
+}
+
+/// A file being received chunk by chunk into a temp file inside its destination folder.
+/// Dropping it before [`Incoming::finish`] removes the temp file.
+pub struct Incoming {
+    pub name: String,
+    pub size: u64,
+    pub meta: Option<FileMeta>,
+    compression: Option<String>,
+    dir: PathBuf,
+    temp: PathBuf,
+    file: Option<File>,
+    received: u64,
+    wire_bytes: u64,
+}
+
+impl Incoming {
+    /// `name` must already be sanitized. Checks for disk space up front using the
+    /// announced `size`.
+    pub fn start(
+        dir: &Path,
+        name: String,
+        size: u64,
+        meta: Option<FileMeta>,
+        compression: Option<String>,
+    ) -> Result<Self, String> {
+        if compression.as_deref().is_some_and(|c| c != compression::ZSTD) {
+            return Err("Unsupported compression".to_string());
+        }
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        files::check_free_space(dir, size)?;
+
+        let temp = files::temp_path_for(&dir.join(&name));
+        let file = File::create(&temp).map_err(|e| e.to_string())?;
+        Ok(Incoming {
+            name,
+            size,
+            meta,
+            compression,
+            dir: dir.to_path_buf(),
+            temp,
+            file: Some(file),
+            received: 0,
+            wire_bytes: 0,
+        })
+    }
+
+    /// Appends one decoded chunk payload. Fails if the peer sends more than it announced.
+    pub fn write_chunk(&mut self, payload: &[u8], compressed: bool) -> Result<(), String> {
+        let remaining = self.size - self.received;
+        let unpacked;
+        let bytes = if compressed {
+            if self.compression.is_none() {
+                return Err("Compressed chunk without negotiated compression".to_string());
+            }
+            let max_len = (remaining as usize).min(CHUNK_SIZE);
+            unpacked = compression::decompress(payload, max_len)?;
+            unpacked.as_slice()
+        } else {
+            payload
+        };
+        if bytes.len() as u64 > remaining {
+            return Err("Received more data than announced".to_string());
+        }
+
+        let file = self.file.as_mut().ok_or("Transfer already finished")?;
+        file.write_all(bytes).map_err(|e| e.to_string())?;
+        self.received += bytes.len() as u64;
+        self.wire_bytes += payload.len() as u64;
+        Ok(())
+    }
+
+    /// Appends one chunk as it comes off the wire, base64-encoded.
+    pub fn write_encoded(&mut self, data: &str, compressed: bool) -> Result<(), String> {
+        let payload = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|_| "Corrupt file data".to_string())?;
+        self.write_chunk(&payload, compressed)
+    }
+
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes
+    }
+
+    /// Verifies the size, syncs, restores metadata and renames the temp file to a free
+    /// name in the destination folder.
+    pub fn finish(mut self) -> Result<PathBuf, String> {
+        if self.received != self.size {
+            return Err(format!(
+                "Received {} bytes, expected {}",
+                self.received, self.size
+            ));
+        }
+        let file = self.file.as_ref().ok_or("Transfer already finished")?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        // From here on `commit_temp_unique` owns cleanup of the temp file.
+        drop(self.file.take());
+        if let Some(meta) = &self.meta {
+            files::apply_meta(&self.temp, meta);
+        }
+        let path = files::commit_temp_unique(&self.temp, &self.dir, &self.name)?;
+        Ok(path)
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
@@ -1,9 +1,13 @@
 import { useEffect, useRef, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
-import { writeText, readText } from "@tauri-apps/plugin-clipboard-manager";
 import { openPath } from "@tauri-apps/plugin-opener";
 import { getCurrentWindow } from "@tauri-apps/api/window";
+import Chat from "./Chat";
+import Outbox from "./Outbox";
+import Links, { LinkPolicy } from "./Links";
+import RemoteActions, { ActionKind, Permission } from "./RemoteActions";
+import ScreenshotEditor, { Edit, ShotPreview } from "./ScreenshotEditor";
 import "./App.css";
 
 interface Peer {
@@ -12,17 +16,89 @@ interface Peer {
   port: number;
 }
 
+interface ReceivedFile {
+  id: string;
+  name: string;
+  path: string | null;
+}
+
+interface TransferStats {
+  id: string;
+  name: string;
+  bytes: number;
+  wire_bytes: number;
+  ratio: number;
+}
+
+interface CaptureOptions {
+  mode: "region" | "window" | "full";
+  format: "png" | "jpeg";
+  quality: number;
+}
+
+type ApplyPolicy = "always" | "ask" | "show_only";
+
+interface Settings {
+  download_dir: string | null;
+  preserve_xattrs: boolean;
+  sync_clipboard: boolean;
+  clipboard_apply: ApplyPolicy;
+  clipboard_max_bytes: number;
+  screenshot: CaptureOptions;
+  open_links: LinkPolicy;
+  outbox_ttl_hours: number;
+  filter_sensitive: boolean;
+  sensitive_rules: { name: string; pattern: string; luhn?: boolean | null }[];
+  peers: Record<
+    string,
+    {
+      download_dir: string | null;
+      filter_sensitive: boolean | null;
+      open_links?: LinkPolicy | null;
+      actions?: Partial<Record<ActionKind, Permission>>;
+      address?: string | null;
+    }
+  >;
+}
+
+interface HistoryEntry {
+  id: string;
+  direction: "sent" | "received";
+  peer: string | null;
+  timestamp: number;
+  pinned: boolean;
+  content: { text: string; formats: { format: string }[] };
+}
+
 type ConnectionStatus = "idle" | "hosting" | "browsing" | "connected";
 
+function formatBytes(n: number): string {
+  if (n < 1024) return `${n} B`;
+  if (n < 1024 * 1024) return `${(n / 1024).toFixed(1)} KB`;
+  return `${(n / (1024 * 1024)).toFixed(1)} MB`;
+}
+
 function App() {
   const [connectionStatus, setConnectionStatus] = useState<ConnectionStatus>("idle");
   const [peers, setPeers] = useState<Peer[]>([]);
   const [connectedPeer, setConnectedPeer] = useState<string | null>(null);
   const [clipboardContent, setClipboardContent] = useState("");
+  const [clipboardFormats, setClipboardFormats] = useState<string[]>([]);
+  const [pendingClipboard, setPendingClipboard] = useState(false);
   const [syncClipboard, setSyncClipboard] = useState(true);
+  const [history, setHistory] = useState<HistoryEntry[]>([]);
+  const [historyQuery, setHistoryQuery] = useState("");
   const [transferring, setTransferring] = useState(false);
   const [screenshotting, setScreenshotting] = useState(false);
-  const [receivedFiles, setReceivedFiles] = useState<{ name: string; data: string }[]>([]);
+  const [editingShot, setEditingShot] = useState<ShotPreview | null>(null);
+  const [sharingScreen, setSharingScreen] = useState(false);
+  const [shareStats, setShareStats] = useState<{ fps: number; quality: number } | null>(null);
+  const [screenshotRequest, setScreenshotRequest] = useState<{ id: string; peer: string | null } | null>(null);
+  const [receivedFiles, setReceivedFiles] = useState<ReceivedFile[]>([]);
+  const [settings, setSettings] = useState<Settings | null>(null);
+  const [lastTransfer, setLastTransfer] = useState<TransferStats | null>(null);
+  const [fileError, setFileError] = useState<string | null>(null);
+  const [clipboardNotice, setClipboardNotice] = useState<string | null>(null);
   const [hostName, setHostName] = useState("");
   const [connecting, setConnecting] = useState(false);
   const [connectingToPeer, setConnectingToPeer] = useState<string | null>(null);
@@ -32,8 +108,34 @@ function App() {
 
   useEffect(() => {
     invoke<string>("get_host_name").then(setHostName).catch(() => setHostName("This Mac"));
+    invoke<Settings>("get_settings")
+      .then((s) => {
+        setSettings(s);
+        setSyncClipboard(s.sync_clipboard);
+      })
+      .catch(console.error);
   }, []);
 
+  useEffect(() => {
+    const refresh = () =>
+      invoke<HistoryEntry[]>("list_history", { query: historyQuery || null })
+        .then(setHistory)
+        .catch(console.error);
+    refresh();
+    const unlistenHistory = listen("clipboard_history", refresh);
+    return () => {
+      unlistenHistory.then((u) => u());
+    };
+  }, [historyQuery]);
+
+  const historyAction = async (command: string, args: Record<string, unknown>) => {
+    try {
+      await invoke(command, args);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
   useEffect(() => {
     const unlistenPeers = listen<Peer[]>("peers", (e) => setPeers(e.payload));
     const unlistenConnected = listen<{ name: string }>("connected", (e) => {
@@ -54,14 +156,50 @@ function App() {
       setConnecting(false);
       setConnectingToPeer(null);
     });
-    const unlistenClipboard = listen<{ text: string }>("remote_clipboard", (e) => {
-      if (syncClipboard && e.payload.text) {
+    // The backend applies remote clipboard content itself; this only updates the preview.
+    const unlistenClipboard = listen<{ text: string; formats: string[]; policy: ApplyPolicy }>(
+      "remote_clipboard",
+      (e) => {
         setClipboardContent(e.payload.text);
-        writeText(e.payload.text).catch(() => {});
-      }
+        setClipboardFormats(e.payload.formats);
+        setPendingClipboard(e.payload.policy === "ask");
+      },
+    );
+    const unlistenFile = listen<ReceivedFile>("remote_file", (e) => {
+      setReceivedFiles((prev) => [...prev, e.payload]);
+    });
+    const unlistenStats = listen<TransferStats>("transfer_stats", (e) => setLastTransfer(e.payload));
+    const unlistenRedacted = listen<{ reason: string }>("clipboard_redacted", (e) => {
+      setClipboardNotice(`Clipboard not sent: ${e.payload.reason}`);
     });
-    const unlistenFile = listen<{ name: string; data: string }>("remote_file", (e) => {
-      setReceivedFiles((prev) => [...prev, { name: e.payload.name, data: e.payload.data }]);
+    const unlistenTruncated = listen<{ size: number; limit: number; sent: boolean }>("clipboard_truncated", (e) => {
+      const { size, limit, sent } = e.payload;
+      setClipboardNotice(
+        `${sent ? "Sent" : "Received"} clipboard item of ${formatBytes(size)} was cut down to ${formatBytes(limit)}`,
+      );
+    });
+    const unlistenShotRequested = listen<{ id: string; peer: string | null }>("screenshot_requested", (e) =>
+      setScreenshotRequest(e.payload),
+    );
+    const unlistenShotDeclined = listen<{ id: string; reason: string }>("screenshot_declined", (e) =>
+      setFileError(`Screenshot request: ${e.payload.reason}`),
+    );
+    const unlistenSharing = listen<{ sharing: boolean; error: string | null }>("screen_sharing", (e) => {
+      setSharingScreen(e.payload.sharing);
+      if (!e.payload.sharing) setShareStats(null);
+      if (e.payload.error) setFileError(`Screen sharing: ${e.payload.error}`);
+    });
+    const unlistenShareStats = listen<{ fps: number; quality: number }>("screen_sharing_stats", (e) =>
+      setShareStats(e.payload),
+    );
+    const unlistenRejected = listen<{ name: string; reason: string }>("file_rejected", (e) => {
+      setFileError(`${e.payload.name}: ${e.payload.reason}`);
+    });
+    const unlistenTrustRevoked = listen<{ peer: string; address: string }>("peer_trust_revoked", (e) => {
+      setConnectionError(
+        `"${e.payload.peer}" connected from a new address (${e.payload.address}), so what it was allowed to do here is asked for again.`,
+      );
+      invoke<Settings>("get_settings").then(setSettings).catch(console.error);
     });
     const unlistenBringToFront = listen("bring_to_front", () => {
       getCurrentWindow().setFocus().catch(() => {});
@@ -72,6 +210,15 @@ function App() {
       unlistenDisconnected.then((u) => u());
       unlistenClipboard.then((u) => u());
       unlistenFile.then((u) => u());
+      unlistenStats.then((u) => u());
+      unlistenRejected.then((u) => u());
+      unlistenRedacted.then((u) => u());
+      unlistenShotRequested.then((u) => u());
+      unlistenShotDeclined.then((u) => u());
+      unlistenSharing.then((u) => u());
+      unlistenShareStats.then((u) => u());
+      unlistenTruncated.then((u) => u());
+      unlistenTrustRevoked.then((u) => u());
       unlistenBringToFront.then((u) => u());
     };
   }, [syncClipboard]);
@@ -140,15 +287,122 @@ function App() {
 
   const sendClipboard = async () => {
     try {
-      const text = await readText();
-      if (text) await invoke("send_clipboard", { text });
+      await invoke("send_clipboard", { text: null });
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const filterSensitive = (peer: string | null) =>
+    settings ? (peer ? settings.peers[peer]?.filter_sensitive : null) ?? settings.filter_sensitive : true;
+
+  const toggleFilterSensitive = async (peer: string, enabled: boolean) => {
+    if (!settings) return;
+    try {
+      const current = settings.peers[peer] ?? { download_dir: null, filter_sensitive: null };
+      const next = { ...settings, peers: { ...settings.peers, [peer]: { ...current, filter_sensitive: enabled } } };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const setActionPermission = async (peer: string, kind: ActionKind, permission: Permission) => {
+    if (!settings) return;
+    try {
+      const current = settings.peers[peer] ?? { download_dir: null, filter_sensitive: null };
+      const actions = { ...current.actions, [kind]: permission };
+      const next = { ...settings, peers: { ...settings.peers, [peer]: { ...current, actions } } };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const setLinkPolicy = async (peer: string, policy: LinkPolicy) => {
+    if (!settings) return;
+    try {
+      const current = settings.peers[peer] ?? { download_dir: null, filter_sensitive: null };
+      const next = { ...settings, peers: { ...settings.peers, [peer]: { ...current, open_links: policy } } };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const setApplyPolicy = async (policy: ApplyPolicy) => {
+    if (!settings) return;
+    try {
+      const next = { ...settings, clipboard_apply: policy };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const setClipboardMaxKb = async (kb: number) => {
+    if (!settings || !(kb > 0)) return;
+    try {
+      const next = { ...settings, clipboard_max_bytes: Math.round(kb * 1024) };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const toggleSyncClipboard = async (enabled: boolean) => {
+    setSyncClipboard(enabled);
+    if (!settings) return;
+    try {
+      const next = { ...settings, sync_clipboard: enabled };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const pasteFromRemote = async () => {
+    try {
+      await invoke("paste_from_remote");
+      setPendingClipboard(false);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const requestRemoteScreenshot = async () => {
+    try {
+      setFileError(null);
+      await invoke("request_remote_screenshot", { mode: null });
+    } catch (e) {
+      setFileError(String(e));
+    }
+  };
+
+  const answerScreenshotRequest = async (allow: boolean, remember: boolean) => {
+    if (!screenshotRequest) return;
+    const id = screenshotRequest.id;
+    setScreenshotRequest(null);
+    try {
+      await invoke("answer_screenshot_request", { id, allow, remember });
     } catch (e) {
       console.error(e);
     }
   };
 
-  const pasteFromRemote = () => {
-    if (clipboardContent) writeText(clipboardContent).catch(() => {});
+  const toggleScreenShare = async () => {
+    try {
+      setFileError(null);
+      await invoke(sharingScreen ? "stop_screen_share" : "start_screen_share");
+    } catch (e) {
+      setFileError(String(e));
+    }
   };
 
   const requestOtherFocus = async () => {
@@ -173,7 +427,7 @@ function App() {
   const captureScreenshotAndSend = async () => {
     try {
       setScreenshotting(true);
-      await invoke("capture_screenshot_and_send");
+      await invoke("capture_screenshot_and_send", { options: null });
     } catch (e) {
       console.error(e);
     } finally {
@@ -181,9 +435,70 @@ function App() {
     }
   };
 
-  const saveReceivedFile = async (name: string, data: string) => {
+  const captureScreenshotToEdit = async () => {
+    try {
+      setScreenshotting(true);
+      setEditingShot(await invoke<ShotPreview>("capture_screenshot", { mode: null }));
+    } catch (e) {
+      console.error(e);
+    } finally {
+      setScreenshotting(false);
+    }
+  };
+
+  const sendEditedShot = async (edits: Edit[]) => {
+    if (!editingShot) return;
+    const id = editingShot.id;
+    setEditingShot(null);
+    try {
+      setTransferring(true);
+      await invoke("send_screenshot", { id, edits, options: null });
+    } catch (e) {
+      console.error(e);
+    } finally {
+      setTransferring(false);
+    }
+  };
+
+  const discardShot = () => {
+    if (editingShot) invoke("discard_screenshot", { id: editingShot.id }).catch(console.error);
+    setEditingShot(null);
+  };
+
+  const setCaptureOptions = async (changes: Partial<CaptureOptions>) => {
+    if (!settings) return;
+    try {
+      const next = { ...settings, screenshot: { ...settings.screenshot, ...changes } };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const saveReceivedFile = async (f: ReceivedFile) => {
+    try {
+      await invoke("save_received_file", { id: f.id });
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const chooseDownloadDir = async () => {
+    try {
+      const dir = await invoke<string | null>("pick_download_dir", { peer: null });
+      if (dir) setSettings(await invoke<Settings>("get_settings"));
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  const askWhereToSave = async () => {
+    if (!settings) return;
     try {
-      await invoke("save_received_file", { name, data });
+      const next = { ...settings, download_dir: null };
+      await invoke("set_settings", { settings: next });
+      setSettings(next);
     } catch (e) {
       console.error(e);
     }
@@ -272,7 +587,37 @@ function App() {
               <button type="button" className="btn small" onClick={requestOtherFocus} title="Bring app to front on other device">
                 Open on other device
               </button>
+              <button type="button" className="btn small" onClick={requestRemoteScreenshot} title="Ask the other device for a screenshot">
+                Get their screen
+              </button>
+              <button type="button" className="btn small" onClick={toggleScreenShare} title="Stream this screen to the other device">
+                {sharingScreen ? "Stop sharing" : "Share my screen"}
+              </button>
+              {shareStats && (
+                <small>
+                  {shareStats.fps.toFixed(1)} fps, quality {shareStats.quality}
+                </small>
+              )}
             </div>
+            {screenshotRequest && (
+              <div className="clipboard-preview">
+                <small>{screenshotRequest.peer ?? "The other device"} wants a screenshot of this screen.</small>
+                <div className="row">
+                  <button type="button" className="btn primary" onClick={() => answerScreenshotRequest(true, false)}>
+                    Allow once
+                  </button>
+                  <button type="button" className="btn small" onClick={() => answerScreenshotRequest(true, true)}>
+                    Always allow
+                  </button>
+                  <button type="button" className="btn secondary" onClick={() => answerScreenshotRequest(false, false)}>
+                    Decline
+                  </button>
+                  <button type="button" className="btn small" onClick={() => answerScreenshotRequest(false, true)}>
+                    Never allow
+                  </button>
+                </div>
+              </div>
+            )}
             {showConnectionSuccess && (
               <p className="connection-success-msg">Connection successful!</p>
             )}
@@ -288,24 +633,94 @@ function App() {
               <input
                 type="checkbox"
                 checked={syncClipboard}
-                onChange={(e) => setSyncClipboard(e.target.checked)}
+                onChange={(e) => toggleSyncClipboard(e.target.checked)}
               />
               <span>Sync clipboard in real time</span>
             </label>
+            <label className="row">
+              <span>Received clipboard:</span>
+              <select
+                value={settings?.clipboard_apply ?? "ask"}
+                onChange={(e) => setApplyPolicy(e.target.value as ApplyPolicy)}
+                disabled={!syncClipboard}
+              >
+                <option value="always">Copy automatically</option>
+                <option value="ask">Ask first</option>
+                <option value="show_only">Only show it</option>
+              </select>
+            </label>
+            <label className="row">
+              <span>Stream items larger than (KB):</span>
+              <input
+                type="number"
+                min={1}
+                defaultValue={settings ? settings.clipboard_max_bytes / 1024 : 1024}
+                key={settings?.clipboard_max_bytes}
+                onBlur={(e) => setClipboardMaxKb(Number(e.target.value))}
+              />
+            </label>
+            {connectedPeer && (
+              <label className="toggle">
+                <input
+                  type="checkbox"
+                  checked={filterSensitive(connectedPeer)}
+                  onChange={(e) => toggleFilterSensitive(connectedPeer, e.target.checked)}
+                />
+                <span>Hold back passwords and secrets sent to {connectedPeer}</span>
+              </label>
+            )}
+            {clipboardNotice && <p className="connection-error">{clipboardNotice}</p>}
             <div className="row">
               <button type="button" className="btn primary" onClick={sendClipboard}>
                 Send my clipboard
               </button>
-              <button type="button" className="btn secondary" onClick={pasteFromRemote} disabled={!clipboardContent}>
+              <button type="button" className="btn secondary" onClick={pasteFromRemote} disabled={clipboardFormats.length === 0}>
                 Paste from remote
               </button>
             </div>
-            {clipboardContent && (
+            {clipboardFormats.length > 0 && (
               <div className="clipboard-preview">
-                <small>Remote clipboard:</small>
+                <small>
+                  Remote clipboard ({clipboardFormats.join(", ")}){pendingClipboard ? " — copy it here?" : ""}:
+                </small>
                 <pre>{clipboardContent.slice(0, 200)}{clipboardContent.length > 200 ? "…" : ""}</pre>
               </div>
             )}
+            <div className="clipboard-history">
+              <h3>History</h3>
+              <input
+                type="search"
+                placeholder="Search history"
+                value={historyQuery}
+                onChange={(e) => setHistoryQuery(e.target.value)}
+              />
+              <ul>
+                {history.map((h) => (
+                  <li key={h.id}>
+                    <span title={new Date(h.timestamp).toLocaleString()}>
+                      {h.direction === "sent" ? "→" : "←"} {h.peer ?? "?"}:{" "}
+                      {h.content.text.slice(0, 80) || h.content.formats.map((f) => f.format).join(", ")}
+                    </span>
+                    <button
+                      type="button"
+                      className="btn small"
+                      onClick={() => historyAction("pin_history", { id: h.id, pinned: !h.pinned })}
+                    >
+                      {h.pinned ? "Unpin" : "Pin"}
+                    </button>
+                    <button type="button" className="btn small" onClick={() => historyAction("resend_history", { id: h.id })}>
+                      Re-send
+                    </button>
+                    <button type="button" className="btn small" onClick={() => historyAction("apply_history", { id: h.id })}>
+                      Copy
+                    </button>
+                    <button type="button" className="btn small" onClick={() => historyAction("delete_history", { id: h.id })}>
+                      Delete
+                    </button>
+                  </li>
+                ))}
+              </ul>
+            </div>
           </section>
 
           <section className="card files">
@@ -324,44 +739,135 @@ function App() {
                 className="btn primary"
                 onClick={captureScreenshotAndSend}
                 disabled={screenshotting || transferring}
-                title="Capture screen (select region), save as JPG, and send to remote"
+                title="Capture the screen and send it to remote"
               >
                 {screenshotting ? "Capturing…" : "Capture screenshot"}
               </button>
+              <button
+                type="button"
+                className="btn secondary"
+                onClick={captureScreenshotToEdit}
+                disabled={screenshotting || transferring || editingShot !== null}
+                title="Capture the screen, hide or mark up parts of it, then send"
+              >
+                Capture and edit…
+              </button>
+            </div>
+            {editingShot && <ScreenshotEditor shot={editingShot} onSend={sendEditedShot} onCancel={discardShot} />}
+            {settings && (
+              <div className="row">
+                <select
+                  value={settings.screenshot.mode}
+                  onChange={(e) => setCaptureOptions({ mode: e.target.value as CaptureOptions["mode"] })}
+                >
+                  <option value="region">Region</option>
+                  <option value="window">Window</option>
+                  <option value="full">Full screen</option>
+                </select>
+                <select
+                  value={settings.screenshot.format}
+                  onChange={(e) => setCaptureOptions({ format: e.target.value as CaptureOptions["format"] })}
+                >
+                  <option value="jpeg">JPEG</option>
+                  <option value="png">PNG</option>
+                </select>
+                {settings.screenshot.format === "jpeg" && (
+                  <label>
+                    <small>Quality {settings.screenshot.quality}</small>
+                    <input
+                      type="range"
+                      min={10}
+                      max={100}
+                      value={settings.screenshot.quality}
+                      onChange={(e) => setCaptureOptions({ quality: Number(e.target.value) })}
+                    />
+                  </label>
+                )}
+              </div>
+            )}
+            <div className="row">
+              <small>
+                {settings?.download_dir ? `Auto-saving to ${settings.download_dir}` : "Asking where to save each file"}
+              </small>
+              <button type="button" className="btn small" onClick={chooseDownloadDir}>
+                Choose folder…
+              </button>
+              {settings?.download_dir && (
+                <button type="button" className="btn small" onClick={askWhereToSave}>
+                  Ask each time
+                </button>
+              )}
             </div>
+            {lastTransfer && (
+              <p className="transfer-stats">
+                <small>
+                  {lastTransfer.name}: {formatBytes(lastTransfer.bytes)} sent as {formatBytes(lastTransfer.wire_bytes)} (
+                  {Math.round(lastTransfer.ratio * 100)}%)
+                </small>
+              </p>
+            )}
+            {fileError && <p className="connection-error">{fileError}</p>}
             {receivedFiles.length > 0 && (
               <div className="received-files">
                 <h3>Received</h3>
                 <ul>
-                  {receivedFiles.map((f, i) => (
-                    <li key={i}>
-                      <span>{f.name}</span>
-                      <button type="button" className="btn small" onClick={() => saveReceivedFile(f.name, f.data)}>
-                        Save
-                      </button>
-                      <button
-                        type="button"
-                        className="btn small"
-                        onClick={async () => {
-                          try {
-                            const path = await invoke<string>("save_received_file", { name: f.name, data: f.data });
-                            if (path) openReceivedFile(path);
-                          } catch {
-                            // User cancelled save dialog
-                          }
-                        }}
-                      >
-                        Open
-                      </button>
+                  {receivedFiles.map((f) => (
+                    <li key={f.id}>
+                      <span title={f.path ?? undefined}>{f.name}</span>
+                      {f.path ? (
+                        <button type="button" className="btn small" onClick={() => openReceivedFile(f.path!)}>
+                          Open
+                        </button>
+                      ) : (
+                        <>
+                          <button type="button" className="btn small" onClick={() => saveReceivedFile(f)}>
+                            Save
+                          </button>
+                          <button
+                            type="button"
+                            className="btn small"
+                            onClick={async () => {
+                              try {
+                                const path = await invoke<string>("save_received_file", { id: f.id });
+                                if (path) openReceivedFile(path);
+                              } catch {
+                                // User cancelled save dialog
+                              }
+                            }}
+                          >
+                            Open
+                          </button>
+                        </>
+                      )}
                     </li>
                   ))}
                 </ul>
               </div>
             )}
           </section>
+
+          {connectedPeer && (
+            <Links
+              peer={connectedPeer}
+              policy={settings?.peers[connectedPeer]?.open_links ?? settings?.open_links ?? "queue"}
+              onPolicy={(policy) => setLinkPolicy(connectedPeer, policy)}
+            />
+          )}
+
+          {connectedPeer && (
+            <RemoteActions
+              peer={connectedPeer}
+              permissions={settings?.peers[connectedPeer]?.actions ?? {}}
+              onPermission={(kind, permission) => setActionPermission(connectedPeer, kind, permission)}
+            />
+          )}
         </>
       )}
 
+      <Chat connectedPeer={connectedPeer} />
+
+      <Outbox connected={connectionStatus === "connected"} />
+
       <footer className="footer">
         Connect over the same WiFi or Mac sharing. No IP address needed — devices find each other by name.
       </footer>
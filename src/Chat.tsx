@@ -0,0 +1,116 @@
+import { useEffect, useRef, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+interface ChatMessage {
+  id: string;
+  direction: "sent" | "received";
+  text: string;
+  timestamp: number;
+  status: "queued" | "sent" | "delivered" | "read";
+}
+
+interface ChatThread {
+  peer: string;
+  unread: number;
+  last: ChatMessage | null;
+}
+
+const STATUS_MARKS: Record<ChatMessage["status"], string> = {
+  queued: "🕓",
+  sent: "✓",
+  delivered: "✓✓",
+  read: "✓✓ read",
+};
+
+// Message threads per peer. Works while disconnected too; messages wait for the peer.
+export default function Chat({ connectedPeer }: { connectedPeer: string | null }) {
+  const [threads, setThreads] = useState<ChatThread[]>([]);
+  const [peer, setPeer] = useState<string | null>(connectedPeer);
+  const [messages, setMessages] = useState<ChatMessage[]>([]);
+  const [draft, setDraft] = useState("");
+  const [error, setError] = useState<string | null>(null);
+  const endRef = useRef<HTMLDivElement>(null);
+
+  useEffect(() => {
+    if (connectedPeer) setPeer(connectedPeer);
+  }, [connectedPeer]);
+
+  useEffect(() => {
+    const refresh = () => {
+      invoke<ChatThread[]>("list_chat_threads").then(setThreads).catch(console.error);
+      if (!peer) return;
+      invoke<ChatMessage[]>("list_chat", { peer }).then(setMessages).catch(console.error);
+      if (document.visibilityState === "visible") {
+        invoke("mark_chat_read", { peer }).catch(console.error);
+      }
+    };
+    refresh();
+    const unlistenChat = listen("chat", refresh);
+    return () => {
+      unlistenChat.then((u) => u());
+    };
+  }, [peer]);
+
+  useEffect(() => {
+    endRef.current?.scrollIntoView({ block: "nearest" });
+  }, [messages.length]);
+
+  const send = async () => {
+    if (!peer || !draft.trim()) return;
+    try {
+      setError(null);
+      await invoke("send_chat", { peer, text: draft });
+      setDraft("");
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  const peers = Array.from(new Set([...(connectedPeer ? [connectedPeer] : []), ...threads.map((t) => t.peer)]));
+  if (peers.length === 0) return null;
+
+  return (
+    <section className="card chat">
+      <h2>Messages</h2>
+      {peers.length > 1 && (
+        <select value={peer ?? ""} onChange={(e) => setPeer(e.target.value)}>
+          {peers.map((p) => {
+            const unread = threads.find((t) => t.peer === p)?.unread ?? 0;
+            return (
+              <option key={p} value={p}>
+                {p}
+                {unread > 0 ? ` (${unread})` : ""}
+              </option>
+            );
+          })}
+        </select>
+      )}
+      <div className="chat-thread">
+        {messages.map((m) => (
+          <div key={m.id} className={`chat-message ${m.direction}`} title={new Date(m.timestamp).toLocaleString()}>
+            <span>{m.text}</span>
+            {m.direction === "sent" && <small>{STATUS_MARKS[m.status]}</small>}
+          </div>
+        ))}
+        <div ref={endRef} />
+      </div>
+      {peer && peer !== connectedPeer && (
+        <small>{peer} is not connected; messages are sent when it reconnects.</small>
+      )}
+      <div className="row">
+        <input
+          type="text"
+          placeholder={peer ? `Message ${peer}` : "Message"}
+          value={draft}
+          onChange={(e) => setDraft(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && send()}
+        />
+        <button type="button" className="btn primary" onClick={send} disabled={!peer || !draft.trim()}>
+          Send
+        </button>
+      </div>
+      {error && <p className="connection-error">{error}</p>}
+    </section>
+  );
+}
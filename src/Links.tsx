@@ -0,0 +1,101 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+export type LinkPolicy = "open" | "queue";
+
+interface ReceivedLink {
+  id: string;
+  url: string;
+  title: string | null;
+  peer: string | null;
+  timestamp: number;
+}
+
+export default function Links({
+  peer,
+  policy,
+  onPolicy,
+}: {
+  peer: string;
+  policy: LinkPolicy;
+  onPolicy: (policy: LinkPolicy) => void;
+}) {
+  const [url, setUrl] = useState("");
+  const [links, setLinks] = useState<ReceivedLink[]>([]);
+  const [notice, setNotice] = useState<string | null>(null);
+
+  useEffect(() => {
+    invoke<ReceivedLink[]>("list_links").then(setLinks).catch(console.error);
+    const unlistenLinks = listen<ReceivedLink[]>("links", (e) => setLinks(e.payload));
+    const unlistenRejected = listen<{ url: string; reason: string }>("link_rejected", (e) =>
+      setNotice(`Link not opened: ${e.payload.reason}`),
+    );
+    return () => {
+      unlistenLinks.then((u) => u());
+      unlistenRejected.then((u) => u());
+    };
+  }, []);
+
+  const sendUrl = async () => {
+    try {
+      setNotice(null);
+      await invoke("send_url", { url, title: null });
+      setUrl("");
+    } catch (e) {
+      setNotice(String(e));
+    }
+  };
+
+  const linkAction = async (command: string, id: string) => {
+    try {
+      await invoke(command, { id });
+    } catch (e) {
+      setNotice(String(e));
+    }
+  };
+
+  return (
+    <section className="card links">
+      <h2>Links</h2>
+      <div className="row">
+        <input
+          type="url"
+          placeholder="https://…"
+          value={url}
+          onChange={(e) => setUrl(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && url && sendUrl()}
+        />
+        <button type="button" className="btn primary" disabled={!url} onClick={sendUrl}>
+          Open on other device
+        </button>
+      </div>
+      <label className="row">
+        <span>Links from {peer}:</span>
+        <select value={policy} onChange={(e) => onPolicy(e.target.value as LinkPolicy)}>
+          <option value="queue">Add to the list</option>
+          <option value="open">Open in the browser</option>
+        </select>
+      </label>
+      {notice && <p className="connection-error">{notice}</p>}
+      {links.length > 0 && (
+        <div className="received-files">
+          <h3>Links received</h3>
+          <ul>
+            {links.map((l) => (
+              <li key={l.id}>
+                <span title={`${l.peer ?? "?"}, ${new Date(l.timestamp).toLocaleString()}`}>{l.title ?? l.url}</span>
+                <button type="button" className="btn small" onClick={() => linkAction("open_link", l.id)}>
+                  Open
+                </button>
+                <button type="button" className="btn small" onClick={() => linkAction("dismiss_link", l.id)}>
+                  Dismiss
+                </button>
+              </li>
+            ))}
+          </ul>
+        </div>
+      )}
+    </section>
+  );
+}
@@ -0,0 +1,40 @@
+import { useEffect, useState } from "react";
+import { listen } from "@tauri-apps/api/event";
+
+interface Frame {
+  seq: number;
+  width: number;
+  height: number;
+  data: string;
+}
+
+// Shown in the "mirror" window while the peer shares its screen.
+export default function MirrorView() {
+  const [frame, setFrame] = useState<Frame | null>(null);
+  const [stopped, setStopped] = useState(false);
+
+  useEffect(() => {
+    const unlistenFrame = listen<Frame>("mirror_frame", (e) => {
+      setFrame(e.payload);
+      setStopped(false);
+    });
+    const unlistenStopped = listen("mirror_stopped", () => setStopped(true));
+    const unlistenDisconnected = listen("disconnected", () => setStopped(true));
+    return () => {
+      unlistenFrame.then((u) => u());
+      unlistenStopped.then((u) => u());
+      unlistenDisconnected.then((u) => u());
+    };
+  }, []);
+
+  return (
+    <div className="mirror-view">
+      {frame ? (
+        <img src={`data:image/jpeg;base64,${frame.data}`} width={frame.width} height={frame.height} alt="Remote screen" />
+      ) : (
+        <p>Waiting for the first frame…</p>
+      )}
+      {stopped && <p className="mirror-stopped">Screen sharing stopped</p>}
+    </div>
+  );
+}
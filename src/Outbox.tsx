@@ -0,0 +1,82 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+type OutboxItem = {
+  id: string;
+  peer: string;
+  created: number;
+  expires: number;
+} & (
+  | { kind: "clipboard"; content: { text: string; formats: { format: string }[] } }
+  | { kind: "file"; path: string; name: string }
+);
+
+function describe(item: OutboxItem): string {
+  if (item.kind === "file") return item.name;
+  const { text, formats } = item.content;
+  return `Clipboard: ${text.slice(0, 60) || formats.map((f) => f.format).join(", ")}`;
+}
+
+// Items waiting for a peer to reconnect. While disconnected, new items can be queued here.
+export default function Outbox({ connected }: { connected: boolean }) {
+  const [items, setItems] = useState<OutboxItem[]>([]);
+  const [notice, setNotice] = useState<string | null>(null);
+
+  useEffect(() => {
+    const refresh = () => invoke<OutboxItem[]>("list_outbox").then(setItems).catch(console.error);
+    refresh();
+    const unlistenOutbox = listen("outbox", refresh);
+    const unlistenFailed = listen<{ id: string; reason: string }>("outbox_failed", (e) =>
+      setNotice(`Queued item dropped: ${e.payload.reason}`),
+    );
+    return () => {
+      unlistenOutbox.then((u) => u());
+      unlistenFailed.then((u) => u());
+    };
+  }, []);
+
+  const run = async (command: string, args?: Record<string, unknown>) => {
+    try {
+      setNotice(null);
+      await invoke(command, args);
+    } catch (e) {
+      setNotice(String(e));
+    }
+  };
+
+  if (connected && items.length === 0) return null;
+
+  return (
+    <section className="card outbox">
+      <h2>Outbox</h2>
+      {!connected && (
+        <div className="row">
+          <button type="button" className="btn secondary" onClick={() => run("send_clipboard", { text: null })}>
+            Send clipboard later
+          </button>
+          <button type="button" className="btn secondary" onClick={() => run("pick_and_send_file")}>
+            Send a file later
+          </button>
+        </div>
+      )}
+      {notice && <p className="connection-error">{notice}</p>}
+      {items.length > 0 ? (
+        <ul className="action-log">
+          {items.map((item) => (
+            <li key={item.id}>
+              <span title={`Expires ${new Date(item.expires).toLocaleString()}`}>
+                → {item.peer}: {describe(item)}
+              </span>
+              <button type="button" className="btn small" onClick={() => run("cancel_outbox", { id: item.id })}>
+                Cancel
+              </button>
+            </li>
+          ))}
+        </ul>
+      ) : (
+        <small>Nothing waiting. Items sent while disconnected go to the last connected device when it is back.</small>
+      )}
+    </section>
+  );
+}
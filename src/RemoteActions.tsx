@@ -0,0 +1,189 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+
+export type ActionKind = "notify" | "open_url" | "open_file" | "lock_screen";
+export type Permission = "ask" | "allow" | "deny";
+
+type RemoteAction =
+  | { action: "notify"; title: string; body: string }
+  | { action: "open_url"; url: string }
+  | { action: "open_file"; name: string }
+  | { action: "lock_screen" };
+
+interface AuditEntry {
+  timestamp: number;
+  direction: "sent" | "received";
+  peer: string | null;
+  id: string;
+  action: RemoteAction;
+  outcome: "requested" | "pending" | "done" | "declined" | "failed";
+  detail: string | null;
+}
+
+const KIND_LABELS: Record<ActionKind, string> = {
+  notify: "Show notifications",
+  open_url: "Open links",
+  open_file: "Open files it sent",
+  lock_screen: "Lock the screen",
+};
+
+function describe(action: RemoteAction): string {
+  switch (action.action) {
+    case "notify":
+      return `notification "${action.body.slice(0, 60)}"`;
+    case "open_url":
+      return `open ${action.url}`;
+    case "open_file":
+      return `open file ${action.name}`;
+    case "lock_screen":
+      return "lock the screen";
+  }
+}
+
+export default function RemoteActions({
+  peer,
+  permissions,
+  onPermission,
+}: {
+  peer: string;
+  permissions: Partial<Record<ActionKind, Permission>>;
+  onPermission: (kind: ActionKind, permission: Permission) => void;
+}) {
+  const [message, setMessage] = useState("");
+  const [fileName, setFileName] = useState("");
+  // Requests waiting for an answer, oldest first; the app caps how many a peer can queue.
+  const [requests, setRequests] = useState<{ id: string; peer: string | null; action: RemoteAction }[]>([]);
+  const request = requests[0] ?? null;
+  const [notice, setNotice] = useState<string | null>(null);
+  const [log, setLog] = useState<AuditEntry[]>([]);
+
+  useEffect(() => {
+    const refresh = () => invoke<AuditEntry[]>("list_action_log").then(setLog).catch(console.error);
+    refresh();
+    const unlistenLog = listen("action_log", refresh);
+    const unlistenRequested = listen<{ id: string; peer: string | null; action: RemoteAction }>(
+      "action_requested",
+      (e) => setRequests((current) => [...current, e.payload]),
+    );
+    const unlistenResult = listen<{ id: string; action: RemoteAction | null; error: string | null }>(
+      "action_result",
+      (e) => {
+        const what = e.payload.action ? describe(e.payload.action) : "Action";
+        setNotice(e.payload.error ? `${what}: ${e.payload.error}` : `${what}: done`);
+      },
+    );
+    return () => {
+      unlistenLog.then((u) => u());
+      unlistenRequested.then((u) => u());
+      unlistenResult.then((u) => u());
+    };
+  }, []);
+
+  const send = async (action: RemoteAction) => {
+    try {
+      setNotice(null);
+      await invoke("send_remote_action", { action });
+    } catch (e) {
+      setNotice(String(e));
+    }
+  };
+
+  const answer = async (allow: boolean, remember: boolean) => {
+    if (!request) return;
+    const id = request.id;
+    setRequests((current) => current.filter((r) => r.id !== id));
+    try {
+      await invoke("answer_remote_action", { id, allow, remember });
+    } catch (e) {
+      console.error(e);
+    }
+  };
+
+  return (
+    <section className="card remote-actions">
+      <h2>Remote actions</h2>
+      {request && (
+        <div className="clipboard-preview">
+          <small>
+            {request.peer ?? "The other device"} wants to {describe(request.action)}.
+            {requests.length > 1 && ` (${requests.length - 1} more waiting)`}
+          </small>
+          <div className="row">
+            <button type="button" className="btn primary" onClick={() => answer(true, false)}>
+              Allow once
+            </button>
+            {request.action.action !== "open_file" && (
+              <button type="button" className="btn small" onClick={() => answer(true, true)}>
+                Always allow
+              </button>
+            )}
+            <button type="button" className="btn secondary" onClick={() => answer(false, false)}>
+              Decline
+            </button>
+            <button type="button" className="btn small" onClick={() => answer(false, true)}>
+              Never allow
+            </button>
+          </div>
+        </div>
+      )}
+      <div className="row">
+        <input type="text" placeholder="Message" value={message} onChange={(e) => setMessage(e.target.value)} />
+        <button
+          type="button"
+          className="btn small"
+          disabled={!message}
+          onClick={() => send({ action: "notify", title: "", body: message })}
+        >
+          Notify
+        </button>
+      </div>
+      <div className="row">
+        <input type="text" placeholder="Name of a file you sent" value={fileName} onChange={(e) => setFileName(e.target.value)} />
+        <button
+          type="button"
+          className="btn small"
+          disabled={!fileName}
+          onClick={() => send({ action: "open_file", name: fileName })}
+        >
+          Open file there
+        </button>
+        <button type="button" className="btn small" onClick={() => send({ action: "lock_screen" })}>
+          Lock their screen
+        </button>
+      </div>
+      {notice && <p className="connection-error">{notice}</p>}
+      <details>
+        <summary>What {peer} may do here</summary>
+        {(Object.keys(KIND_LABELS) as ActionKind[]).map((kind) => (
+          <label className="row" key={kind}>
+            <span>{KIND_LABELS[kind]}</span>
+            <select
+              value={permissions[kind] ?? "ask"}
+              onChange={(e) => onPermission(kind, e.target.value as Permission)}
+            >
+              <option value="ask">Ask</option>
+              {/* Opening a file is always asked for. */}
+              {kind !== "open_file" && <option value="allow">Allow</option>}
+              <option value="deny">Deny</option>
+            </select>
+          </label>
+        ))}
+      </details>
+      <details>
+        <summary>Audit log</summary>
+        <ul className="action-log">
+          {log.map((entry, i) => (
+            <li key={`${entry.id}-${i}`}>
+              <small>
+                {new Date(entry.timestamp).toLocaleString()} {entry.direction === "sent" ? "→" : "←"} {entry.peer ?? "?"}:{" "}
+                {describe(entry.action)} — {entry.outcome}
+                {entry.detail ? ` (${entry.detail})` : ""}
+              </small>
+            </li>
+          ))}
+        </ul>
+      </details>
+    </section>
+  );
+}
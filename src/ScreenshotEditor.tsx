@@ -0,0 +1,138 @@
+import { useRef, useState, type MouseEvent } from "react";
+
+export interface ShotPreview {
+  id: string;
+  width: number;
+  height: number;
+  png: string;
+}
+
+interface Rect {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+export type Edit =
+  | { kind: "blur"; rect: Rect }
+  | { kind: "black_box"; rect: Rect }
+  | { kind: "rect"; rect: Rect; color: [number, number, number]; width: number }
+  | { kind: "line"; from: [number, number]; to: [number, number]; color: [number, number, number]; width: number };
+
+type Tool = Edit["kind"];
+
+const RED: [number, number, number] = [230, 40, 40];
+const STROKE = 4;
+
+function normalize(r: Rect): Rect {
+  return {
+    x: Math.min(r.x, r.x + r.width),
+    y: Math.min(r.y, r.y + r.height),
+    width: Math.abs(r.width),
+    height: Math.abs(r.height),
+  };
+}
+
+function makeEdit(tool: Tool, from: [number, number], to: [number, number]): Edit {
+  const rect = { x: from[0], y: from[1], width: to[0] - from[0], height: to[1] - from[1] };
+  switch (tool) {
+    case "blur":
+      return { kind: "blur", rect };
+    case "black_box":
+      return { kind: "black_box", rect };
+    case "rect":
+      return { kind: "rect", rect, color: RED, width: STROKE };
+    case "line":
+      return { kind: "line", from, to, color: RED, width: STROKE };
+  }
+}
+
+// Outline of an edit on the preview; the real rendering happens in the backend.
+function EditShape({ edit }: { edit: Edit }) {
+  if (edit.kind === "line") {
+    return <line x1={edit.from[0]} y1={edit.from[1]} x2={edit.to[0]} y2={edit.to[1]} stroke="red" strokeWidth={STROKE} />;
+  }
+  const r = normalize(edit.rect);
+  const style =
+    edit.kind === "black_box"
+      ? { fill: "black" }
+      : edit.kind === "blur"
+        ? { fill: "rgba(128,128,128,0.7)" }
+        : { fill: "none", stroke: "red", strokeWidth: STROKE };
+  return <rect x={r.x} y={r.y} width={r.width} height={r.height} {...style} />;
+}
+
+export default function ScreenshotEditor({
+  shot,
+  onSend,
+  onCancel,
+}: {
+  shot: ShotPreview;
+  onSend: (edits: Edit[]) => void;
+  onCancel: () => void;
+}) {
+  const [tool, setTool] = useState<Tool>("blur");
+  const [edits, setEdits] = useState<Edit[]>([]);
+  const [dragFrom, setDragFrom] = useState<[number, number] | null>(null);
+  const [dragTo, setDragTo] = useState<[number, number] | null>(null);
+  const svgRef = useRef<SVGSVGElement>(null);
+
+  // Screen coordinates to image pixels.
+  const toImage = (e: MouseEvent): [number, number] => {
+    const box = svgRef.current!.getBoundingClientRect();
+    return [
+      Math.round(((e.clientX - box.left) / box.width) * shot.width),
+      Math.round(((e.clientY - box.top) / box.height) * shot.height),
+    ];
+  };
+
+  const finishDrag = (e: MouseEvent) => {
+    if (!dragFrom) return;
+    const to = toImage(e);
+    if (to[0] !== dragFrom[0] || to[1] !== dragFrom[1]) {
+      setEdits((prev) => [...prev, makeEdit(tool, dragFrom, to)]);
+    }
+    setDragFrom(null);
+    setDragTo(null);
+  };
+
+  return (
+    <div className="screenshot-editor">
+      <div className="row">
+        <select value={tool} onChange={(e) => setTool(e.target.value as Tool)}>
+          <option value="blur">Blur</option>
+          <option value="black_box">Black box</option>
+          <option value="rect">Rectangle</option>
+          <option value="line">Line</option>
+        </select>
+        <button type="button" className="btn small" onClick={() => setEdits((prev) => prev.slice(0, -1))} disabled={edits.length === 0}>
+          Undo
+        </button>
+        <button type="button" className="btn primary" onClick={() => onSend(edits)}>
+          Send
+        </button>
+        <button type="button" className="btn secondary" onClick={onCancel}>
+          Discard
+        </button>
+      </div>
+      <div className="screenshot-canvas">
+        <img src={`data:image/png;base64,${shot.png}`} alt="Screenshot" draggable={false} />
+        <svg
+          ref={svgRef}
+          viewBox={`0 0 ${shot.width} ${shot.height}`}
+          preserveAspectRatio="none"
+          onMouseDown={(e) => setDragFrom(toImage(e))}
+          onMouseMove={(e) => dragFrom && setDragTo(toImage(e))}
+          onMouseUp={finishDrag}
+          onMouseLeave={finishDrag}
+        >
+          {edits.map((edit, i) => (
+            <EditShape key={i} edit={edit} />
+          ))}
+          {dragFrom && dragTo && <EditShape edit={makeEdit(tool, dragFrom, dragTo)} />}
+        </svg>
+      </div>
+    </div>
+  );
+}